@@ -15,6 +15,9 @@ fn main() {
     println!("cargo:rustc-link-arg=-lfontconfig");
     println!("cargo:rustc-link-arg=-lX11");
     println!("cargo:rustc-link-arg=-lXft");
+    println!("cargo:rustc-link-arg=-lXrender");
+    // libst calls back into the Rust ttywrite
+    println!("cargo:rustc-link-arg=-Wl,--export-dynamic");
 
     let st = Path::new("st").canonicalize().unwrap();
     println!("cargo:rustc-link-arg=-Wl,-rpath,{}", st.display());
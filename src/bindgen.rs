@@ -25,4 +25,18 @@ impl Term {
     pub(crate) fn line(ptr: *mut Term, i: c_int, j: c_int) -> *mut Glyph_ {
         unsafe { (*(*ptr).line.offset(i as isize)).offset(j as isize) }
     }
+
+    /// Return the line displayed on row `y`, which comes from the history ring
+    /// when the view is scrolled back by `scr` lines.
+    pub(crate) fn tline(ptr: *mut Term, y: c_int) -> Line {
+        unsafe {
+            let t = &*ptr;
+            if y < t.scr {
+                let i = (t.histi + y - t.scr + t.histmax) % t.histmax;
+                *t.hist.offset(i as isize)
+            } else {
+                *t.line.offset((y - t.scr) as isize)
+            }
+        }
+    }
 }
@@ -0,0 +1,174 @@
+//! Settings for the parts of rt written in Rust, in the style of
+//! st/config.h.
+
+use std::ffi::{c_int, c_uint};
+
+use x11::{
+    keysym::{XK_Return, XK_space, XK_A, XK_E, XK_F, XK_G, XK_S, XK_X, XK_Z},
+    xlib::{Button1, ControlMask, ShiftMask},
+};
+
+use crate::{
+    bindgen::{Arg, KeySym},
+    copymode::copymode,
+    export::{exportclip, exportfile, Format},
+    kscrollprompt, newterm,
+    search::search,
+    selcmdoutput,
+};
+
+/// A keyboard shortcut calling `func` with `arg` when `keysym` is pressed with
+/// the modifiers in `mod_`.
+pub(crate) struct Shortcut {
+    pub(crate) mod_: c_uint,
+    pub(crate) keysym: KeySym,
+    pub(crate) func: fn(&Arg),
+    pub(crate) arg: Arg,
+}
+
+/// Directory for the files written by `exportfile`, or `None` for `rt` in
+/// `$XDG_STATE_HOME`, which defaults to `~/.local/state`.
+pub(crate) const EXPORTDIR: Option<&str> = None;
+
+/// What a program is allowed to do.
+#[allow(dead_code)] // not every policy is used by the settings below
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Policy {
+    Deny,
+    Allow,
+    /// ask the user in the bottom row of the window each time
+    Prompt,
+}
+
+/// Whether programs may set and read the clipboard and the primary selection
+/// with OSC 52, which works over ssh. This is independent of `allowwindowops`.
+pub(crate) const OSC52WRITE: Policy = Policy::Prompt;
+pub(crate) const OSC52READ: Policy = Policy::Prompt;
+
+/// Whether to underline the hyperlink under the mouse pointer.
+pub(crate) const LINKHOVER: bool = true;
+
+/// The modifiers and mouse button for opening the hyperlink under the pointer.
+pub(crate) const LINKMOD: c_uint = ControlMask;
+pub(crate) const LINKBUTTON: c_uint = Button1;
+
+/// The command opening a hyperlink, which is passed the URI as its last
+/// argument.
+pub(crate) const LINKOPENER: &[&str] = &["xdg-open"];
+
+/// Whether to detect file paths followed by a line number, like
+/// `src/main.rs:12`, as well as URLs in the text on the terminal. They are
+/// underlined under the pointer while `LINKMOD` is held and opened with a click
+/// like hyperlinks. Relative paths are taken to be in the working directory
+/// reported with OSC 7, and paths to files that don't exist are left alone.
+pub(crate) const PATHS: bool = false;
+
+/// The command opening a detected path, which is passed `+<line>`, `--` and the
+/// path as its last arguments, the way editors like vi take them.
+pub(crate) const PATHOPENER: &[&str] = &["rt", "-e", "vi"];
+
+/// The longest time in milliseconds to wait for a program to finish updating
+/// the screen in synchronized output mode before drawing anyway.
+pub(crate) const SYNCTIMEOUT: i64 = 150;
+
+/// The time in milliseconds the cursor is shown and then hidden for when it
+/// blinks, or 0 to never blink it.
+pub(crate) const CURSORBLINK: i64 = 600;
+
+/// The time in milliseconds the colors of the window are inverted for when the
+/// bell rings, or 0 for no visual bell. `bellvolume` in config.h sets the
+/// volume of the keyboard bell.
+pub(crate) const VISUALBELL: i64 = 0;
+
+/// The time in milliseconds messages, like where the screen was exported to,
+/// are shown in the bottom row of the window.
+pub(crate) const MESSAGETIMEOUT: i64 = 3000;
+
+/// Whether to set the urgency hint of the window when the bell rings while it
+/// is not focused.
+pub(crate) const BELLURGENT: bool = true;
+
+/// The command run when the bell rings, which is passed the window id as its
+/// last argument, or empty to run nothing. It isn't run again while it is
+/// still running.
+pub(crate) const BELLCMD: &[&str] = &[];
+
+/// The command showing the desktop notifications programs send with OSC 9 and
+/// OSC 777, which is passed the title and the body as its last two arguments,
+/// or empty to ignore them.
+pub(crate) const NOTIFIER: &[&str] = &["notify-send", "--"];
+
+/// Whether to show notifications only while the window is not focused.
+pub(crate) const NOTIFYUNFOCUSED: bool = true;
+
+/// The shortest time in milliseconds between two notifications. The ones
+/// coming sooner are dropped.
+pub(crate) const NOTIFYINTERVAL: i64 = 1000;
+
+/// The foreground and background colors of the search matches, and of the
+/// current one.
+pub(crate) const SEARCHCOLORS: [(u32, u32); 2] = [(0, 3), (0, 208)];
+
+/// The shortest time in milliseconds between two searches for the matches
+/// again while the lines change, as when a program writes a lot.
+pub(crate) const SEARCHINTERVAL: i64 = 200;
+
+const TERMMOD: c_uint = ControlMask | ShiftMask;
+
+/// Internal keyboard shortcuts, checked before the ones in config.h.
+pub(crate) const SHORTCUTS: &[Shortcut] = &[
+    Shortcut {
+        mod_: TERMMOD,
+        keysym: XK_E as KeySym,
+        func: exportclip,
+        arg: Arg { i: Format::Text as c_int },
+    },
+    Shortcut {
+        mod_: TERMMOD,
+        keysym: XK_A as KeySym,
+        func: exportclip,
+        arg: Arg { i: Format::Ansi as c_int },
+    },
+    Shortcut {
+        mod_: TERMMOD,
+        keysym: XK_S as KeySym,
+        func: exportfile,
+        arg: Arg { i: Format::Html as c_int },
+    },
+    Shortcut {
+        mod_: TERMMOD,
+        keysym: XK_Return as KeySym,
+        func: newterm,
+        arg: Arg { i: 0 },
+    },
+    Shortcut {
+        mod_: TERMMOD,
+        keysym: XK_Z as KeySym,
+        func: kscrollprompt,
+        arg: Arg { i: -1 },
+    },
+    Shortcut {
+        mod_: TERMMOD,
+        keysym: XK_X as KeySym,
+        func: kscrollprompt,
+        arg: Arg { i: 1 },
+    },
+    Shortcut {
+        mod_: TERMMOD,
+        keysym: XK_G as KeySym,
+        func: selcmdoutput,
+        arg: Arg { i: 0 },
+    },
+    Shortcut {
+        mod_: TERMMOD,
+        keysym: XK_F as KeySym,
+        func: search,
+        arg: Arg { i: 0 },
+    },
+    Shortcut {
+        mod_: TERMMOD,
+        keysym: XK_space as KeySym,
+        func: copymode,
+        arg: Arg { i: 0 },
+    },
+];
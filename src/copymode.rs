@@ -0,0 +1,328 @@
+//! Copy mode, which moves a cursor of its own over the screen and the history
+//! with vi's motions to select text from the keyboard.
+//!
+//! The motions are h, j, k, l and the arrow keys, w, b and e for words, 0, ^
+//! and $ in a line, H, M and L in the view, gg and G for the first and the
+//! last line, and Ctrl+U, Ctrl+D, Ctrl+B and Ctrl+F by half and whole
+//! screens. They can be given a count first. v, V and Ctrl+V start a regular,
+//! line or rectangular selection, or stop it when typed again. y or Return
+//! copies it into the primary selection and the clipboard and leaves copy
+//! mode, as do q and Escape without copying. Escape only stops a selection if
+//! there is one.
+
+use std::{ffi::c_int, ptr::null_mut};
+
+use x11::{keysym::*, xlib::ControlMask};
+
+use crate::{
+    bindgen::{self, sel, term, Arg, Glyph_, KeySym, Term, XKeyEvent},
+    getsel, is_set, isdelim, kscrolldown, kscrollup, selclear, selnormalize,
+    state, tfulldirt, tlinelen, x, Pos, ATTR_WDUMMY, ATTR_WRAP, MODE_ALTSCREEN,
+    SEL_READY, SEL_RECTANGULAR, SEL_REGULAR, SNAP_LINE,
+};
+
+/// The largest count that can be typed before a motion.
+const COUNT_MAX: c_int = 9999;
+
+/// The state of copy mode.
+pub(crate) struct Copy {
+    /// the position of the cursor
+    cursor: Pos,
+    /// where the selection started, with its `SEL_*` type and `SNAP_*` snap,
+    /// while selecting
+    anchor: Option<(Pos, c_int, c_int)>,
+    /// the count typed before a motion, or 0
+    count: c_int,
+    /// whether a first `g` was typed
+    g: bool,
+}
+
+/// Return the first and the last row the cursor can go to.
+fn rows() -> (c_int, c_int) {
+    unsafe {
+        let first = if is_set(MODE_ALTSCREEN) { 0 } else { -term.histn };
+        (first, term.row - 1)
+    }
+}
+
+fn glyph((y, x): Pos) -> Glyph_ {
+    unsafe { *Term::tline(&raw mut term, y + term.scr).offset(x as isize) }
+}
+
+/// Return the class of the character at `p` for the word motions: 0 for
+/// blanks, 1 for the `worddelimiters` and 2 for the rest.
+fn class((y, x): Pos) -> u8 {
+    let g = glyph((y, x));
+    if g.mode & ATTR_WDUMMY as u16 != 0 && x > 0 {
+        return class((y, x - 1));
+    }
+    let len =
+        unsafe { tlinelen(Term::tline(&raw mut term, y + term.scr), term.col) };
+    if x >= len || g.u == ' ' as u32 {
+        0
+    } else if isdelim(g.u) {
+        1
+    } else {
+        2
+    }
+}
+
+/// Return the cell after `p` if `dir` is positive or else the one before it,
+/// and whether a line break not made by wrapping is crossed on the way.
+fn step((y, x): Pos, dir: c_int) -> Option<(Pos, bool)> {
+    let col = unsafe { term.col };
+    let x = x + dir;
+    if (0..col).contains(&x) {
+        return Some(((y, x), false));
+    }
+    let (first, last) = rows();
+    let y2 = y + dir;
+    if y2 < first || y2 > last {
+        return None;
+    }
+    let upper = y.min(y2);
+    let wrapped = glyph((upper, col - 1)).mode & ATTR_WRAP as u16 != 0;
+    Some(((y2, if dir > 0 { 0 } else { col - 1 }), !wrapped))
+}
+
+/// Return the start of the next word after `p`, like vi's `w`.
+fn nextword(mut p: Pos) -> Pos {
+    let c = class(p);
+    let mut blank = false;
+    while let Some((q, brk)) = step(p, 1) {
+        p = q;
+        blank |= brk;
+        match class(p) {
+            0 => blank = true,
+            k if blank || k != c => break,
+            _ => {}
+        }
+    }
+    p
+}
+
+/// Return the end of the word after `p` if `dir` is positive, like vi's `e`,
+/// or else the start of the word before it, like `b`.
+fn wordedge(mut p: Pos, dir: c_int) -> Pos {
+    // skip the blanks, moving at least once
+    loop {
+        let Some((q, _)) = step(p, dir) else {
+            return p;
+        };
+        p = q;
+        if class(p) != 0 {
+            break;
+        }
+    }
+    let c = class(p);
+    while let Some((q, brk)) = step(p, dir) {
+        if brk || class(q) != c {
+            break;
+        }
+        p = q;
+    }
+    p
+}
+
+impl Copy {
+    /// Move the cursor as the key `ksym` says, with Control held if `ctrl` is
+    /// set and after a `g` if `g` is, returning whether it is a motion.
+    #[allow(non_upper_case_globals)]
+    fn motion(&mut self, ksym: u32, ctrl: bool, g: bool) -> bool {
+        let (first, last) = rows();
+        let (row, col, scr) = unsafe { (term.row, term.col, term.scr) };
+        let n = self.count.max(1);
+        let (y, x) = self.cursor;
+        let len = tlinelen(Term::tline(&raw mut term, y + scr), col);
+        let repeat = |f: fn(Pos) -> Pos| (0..n).fold((y, x), |p, _| f(p));
+        // the line counted from the first one for gg and G
+        let line =
+            (self.count > 0).then(|| first.saturating_add(self.count - 1));
+        self.cursor = match ksym {
+            XK_u | XK_U if ctrl => {
+                (y.saturating_sub(n.saturating_mul(row / 2)), x)
+            }
+            XK_d | XK_D if ctrl => {
+                (y.saturating_add(n.saturating_mul(row / 2)), x)
+            }
+            XK_b | XK_B if ctrl => (y.saturating_sub(n.saturating_mul(row)), x),
+            XK_f | XK_F if ctrl => (y.saturating_add(n.saturating_mul(row)), x),
+            _ if ctrl => return false,
+            XK_h | XK_Left => (y, x.saturating_sub(n)),
+            XK_l | XK_Right => (y, x.saturating_add(n)),
+            XK_k | XK_Up => (y.saturating_sub(n), x),
+            XK_j | XK_Down => (y.saturating_add(n), x),
+            XK_w => repeat(nextword),
+            XK_b => repeat(|p| wordedge(p, -1)),
+            XK_e => repeat(|p| wordedge(p, 1)),
+            XK_0 | XK_Home => (y, 0),
+            XK_dollar | XK_End => (y, len - 1),
+            XK_asciicircum => {
+                let x = (0..len).find(|&x| class((y, x)) != 0).unwrap_or(0);
+                (y, x)
+            }
+            XK_H => (-scr, x),
+            XK_M => (-scr + (row - 1) / 2, x),
+            XK_L => (-scr + row - 1, x),
+            XK_g if g => (line.unwrap_or(first), 0),
+            XK_G => (line.unwrap_or(last), 0),
+            _ => return false,
+        };
+        let (y, x) = self.cursor;
+        let y = y.clamp(first, last);
+        let mut x = x.clamp(0, col - 1);
+        if x > 0 && glyph((y, x)).mode & ATTR_WDUMMY as u16 != 0 {
+            x -= 1;
+        }
+        self.cursor = (y, x);
+        true
+    }
+
+    /// Scroll the view to show the cursor and update the selection.
+    fn update(&self) {
+        unsafe {
+            let y = self.cursor.0 + term.scr;
+            if y < 0 {
+                kscrollup(&Arg { i: -y });
+            } else if y >= term.row {
+                kscrolldown(&Arg { i: y - term.row + 1 });
+            }
+            // scrolling may have cleared the selection, which is set again
+            // from the anchor in any case
+            if let Some(((ay, ax), type_, snap)) = self.anchor {
+                selclear();
+                sel.mode = SEL_READY;
+                sel.type_ = type_;
+                sel.snap = snap;
+                sel.alt = is_set(MODE_ALTSCREEN) as c_int;
+                (sel.ob.x, sel.ob.y) = (ax, ay + term.scr);
+                (sel.oe.x, sel.oe.y) =
+                    (self.cursor.1, self.cursor.0 + term.scr);
+                selnormalize();
+            }
+            tfulldirt();
+        }
+    }
+
+    /// Start a selection of `type_` and `snap` at the cursor, switch the
+    /// current one to them, or stop it if it already has them.
+    fn select(&mut self, type_: c_int, snap: c_int) {
+        self.anchor = match self.anchor {
+            Some((_, t, s)) if (t, s) == (type_, snap) => {
+                selclear();
+                None
+            }
+            Some((p, _, _)) => Some((p, type_, snap)),
+            None => Some((self.cursor, type_, snap)),
+        };
+    }
+
+    /// Handle the key press `e`, returning whether to leave copy mode and
+    /// copy the selection, if it is to be left.
+    #[allow(non_upper_case_globals)]
+    fn key(&mut self, e: &mut XKeyEvent) -> Option<bool> {
+        let mut ksym: KeySym = 0;
+        let mut buf = [0; 64];
+        unsafe {
+            bindgen::XLookupString(
+                e,
+                buf.as_mut_ptr(),
+                buf.len() as c_int,
+                &mut ksym,
+                null_mut(),
+            );
+        }
+        // wait for a key that is not a modifier
+        if (XK_Shift_L as KeySym..=XK_Hyper_R as KeySym).contains(&ksym) {
+            return None;
+        }
+        let ksym = ksym as u32;
+        let ctrl = e.state & ControlMask != 0;
+        let g = std::mem::take(&mut self.g);
+
+        if !ctrl
+            && ((XK_1..=XK_9).contains(&ksym) || ksym == XK_0 && self.count > 0)
+        {
+            let count = self.count * 10 + (ksym - XK_0) as c_int;
+            self.count = count.min(COUNT_MAX);
+            return None;
+        }
+        if self.motion(ksym, ctrl, g) {
+            self.count = 0;
+            self.update();
+            return None;
+        }
+        match ksym {
+            XK_g if !ctrl => {
+                self.g = !g;
+                return None;
+            }
+            XK_v | XK_V if ctrl => self.select(SEL_RECTANGULAR, 0),
+            XK_v => self.select(SEL_REGULAR, 0),
+            XK_V => self.select(SEL_REGULAR, SNAP_LINE),
+            XK_y | XK_Return | XK_KP_Enter => return Some(true),
+            XK_Escape if self.anchor.is_some() => {
+                self.anchor = None;
+                selclear();
+            }
+            XK_q | XK_Escape => return Some(false),
+            XK_c | XK_C if ctrl => return Some(false),
+            _ => {}
+        }
+        self.count = 0;
+        self.update();
+        None
+    }
+}
+
+/// Enter copy mode, with the cursor on the terminal's cursor, or on the last
+/// row of the view if it is scrolled back.
+pub(crate) fn copymode(_: &Arg) {
+    unsafe {
+        let cursor = if term.scr == 0 {
+            (term.c.y, term.c.x)
+        } else {
+            (term.row - 1 - term.scr, 0)
+        };
+        state().copy = Some(Copy { cursor, anchor: None, count: 0, g: false });
+    }
+    tfulldirt();
+}
+
+/// Leave copy mode, copying the selection if `yank` is set, or else clearing
+/// it.
+fn leave(copy: Copy, yank: bool) {
+    if copy.anchor.is_some() {
+        if yank {
+            if let Some(text) = getsel() {
+                x::xsetsel(&text);
+                x::xclipcopy();
+            }
+        } else {
+            selclear();
+        }
+    }
+    tfulldirt();
+}
+
+/// Take the key press `e` for copy mode, if it is on, returning whether it
+/// was.
+pub(crate) fn kcopy(e: &mut XKeyEvent) -> bool {
+    // copy mode is taken out while it scrolls the view and reads the lines
+    let Some(mut copy) = unsafe { state() }.copy.take() else {
+        return false;
+    };
+    match copy.key(e) {
+        Some(yank) => leave(copy, yank),
+        None => unsafe { state() }.copy = Some(copy),
+    }
+    true
+}
+
+/// Return the position of the cursor of copy mode in the view, if it is on
+/// and the cursor is in the view.
+pub(crate) fn cursor() -> Option<(c_int, c_int)> {
+    let (y, x) = unsafe { state() }.copy.as_ref()?.cursor;
+    let y = y + unsafe { term.scr };
+    (0..unsafe { term.row }).contains(&y).then_some((x, y))
+}
@@ -0,0 +1,432 @@
+//! Export of the screen and history as plain text, as text with ANSI escape
+//! sequences, or as HTML.
+
+use std::{
+    ffi::c_int,
+    fmt::Write as _,
+    fs::{DirBuilder, OpenOptions},
+    io::{ErrorKind, Write as _},
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    bindgen::{defaultbg, defaultfg, term, Arg, Glyph_, Line},
+    config::EXPORTDIR,
+    gmarks, tlinelen, tmessage, x, ATTR_BLINK, ATTR_BOLD, ATTR_FAINT,
+    ATTR_INVISIBLE, ATTR_ITALIC, ATTR_REVERSE, ATTR_STRUCK, ATTR_UNDERLINE,
+    ATTR_USTYLE, ATTR_WDUMMY, ATTR_WRAP, MODE_ALTSCREEN, USTYLE_CURLY,
+    USTYLE_DASHED, USTYLE_DOTTED, USTYLE_DOUBLE,
+};
+
+/// The attributes of a glyph that affect how it looks.
+const STYLE_MASK: c_int = ATTR_BOLD
+    | ATTR_FAINT
+    | ATTR_ITALIC
+    | ATTR_UNDERLINE
+    | ATTR_USTYLE
+    | ATTR_BLINK
+    | ATTR_REVERSE
+    | ATTR_INVISIBLE
+    | ATTR_STRUCK;
+
+/// The colors of config.def.h, for terminals without an X display to look
+/// them up in.
+const COLORS: [u32; 16] = [
+    0x000000, 0xcd0000, 0x00cd00, 0xcdcd00, 0x0000ee, 0xcd00cd, 0x00cdcd,
+    0xe5e5e5, 0x7f7f7f, 0xff0000, 0x00ff00, 0xffff00, 0x5c5cff, 0xff00ff,
+    0x00ffff, 0xffffff,
+];
+
+/// The formats the screen can be exported in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// plain UTF-8 text
+    Text,
+    /// UTF-8 text with SGR sequences for the colors and attributes
+    Ansi,
+    /// a standalone HTML document with inline styles
+    Html,
+}
+
+impl Format {
+    /// Return the format selected by `a.i`, which is a `Format` cast to an
+    /// integer.
+    fn from_arg(a: &Arg) -> Self {
+        match unsafe { a.i } {
+            0 => Self::Text,
+            1 => Self::Ansi,
+            _ => Self::Html,
+        }
+    }
+
+    /// Return the usual file name extension for the format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Text => "txt",
+            Format::Ansi => "ans",
+            Format::Html => "html",
+        }
+    }
+}
+
+/// The colors and attributes of a glyph.
+#[derive(Clone, Copy, PartialEq)]
+struct Style {
+    mode: c_int,
+    fg: u32,
+    bg: u32,
+    /// the underline color plus one, or 0 for the color of the text
+    ucolor: u32,
+}
+
+impl Style {
+    fn new(g: &Glyph_) -> Self {
+        Self {
+            mode: g.mode as c_int & STYLE_MASK,
+            fg: g.fg,
+            bg: g.bg,
+            // the underline color has no effect without an underline
+            ucolor: if g.mode & ATTR_UNDERLINE as u16 != 0 {
+                g.ucolor
+            } else {
+                0
+            },
+        }
+    }
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        unsafe { Self { mode: 0, fg: defaultfg, bg: defaultbg, ucolor: 0 } }
+    }
+}
+
+/// Serialize the lines of history, unless the alternate screen is shown, and
+/// the lines of the screen in `format`. Empty lines at the bottom of the screen
+/// are left out.
+pub fn export(format: Format) -> Vec<u8> {
+    let lines = exportlines();
+    let mut s = String::new();
+    if format == Format::Html {
+        let style = Style::default();
+        let _ = write!(
+            s,
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+             <title>rt</title>\n</head>\n<body>\n\
+             <pre style=\"color:{};background-color:{};padding:1em\">",
+            csscolor(style.fg),
+            csscolor(style.bg),
+        );
+    }
+
+    let mut cur = Style::default();
+    for line in lines {
+        let col = unsafe { term.col };
+        let len = tlinelen(line, col);
+        for x in 0..len {
+            let g = unsafe { &*line.offset(x as isize) };
+            if g.mode & ATTR_WDUMMY as u16 != 0 {
+                continue;
+            }
+            let style = Style::new(g);
+            if style != cur {
+                match format {
+                    Format::Text => {}
+                    Format::Ansi => s.push_str(&sgr(style)),
+                    Format::Html => {
+                        if cur != Style::default() {
+                            s.push_str("</span>");
+                        }
+                        if style != Style::default() {
+                            let _ =
+                                write!(s, "<span style=\"{}\">", css(style));
+                        }
+                    }
+                }
+                cur = style;
+            }
+            let c = char::from_u32(g.u).unwrap_or(char::REPLACEMENT_CHARACTER);
+            match (format, c) {
+                (Format::Html, '&') => s.push_str("&amp;"),
+                (Format::Html, '<') => s.push_str("&lt;"),
+                (Format::Html, '>') => s.push_str("&gt;"),
+                _ => s.push(c),
+            }
+            s.extend(gmarks(g).into_iter().filter_map(char::from_u32));
+        }
+
+        // lines wrapped by the terminal are joined again
+        let wrapped = col > 0
+            && unsafe { (*line.offset(col as isize - 1)).mode }
+                & ATTR_WRAP as u16
+                != 0;
+        if wrapped {
+            continue;
+        }
+        if cur != Style::default() {
+            match format {
+                Format::Text => {}
+                Format::Ansi => s.push_str("\x1b[0m"),
+                Format::Html => s.push_str("</span>"),
+            }
+            cur = Style::default();
+        }
+        s.push('\n');
+    }
+
+    match format {
+        Format::Text => {}
+        Format::Ansi => {
+            if cur != Style::default() {
+                s.push_str("\x1b[0m");
+            }
+        }
+        Format::Html => {
+            if cur != Style::default() {
+                s.push_str("</span>");
+            }
+            s.push_str("</pre>\n</body>\n</html>\n");
+        }
+    }
+    s.into_bytes()
+}
+
+/// Return the lines to export: the history from the oldest line, if the
+/// primary screen is shown, and then the screen.
+fn exportlines() -> Vec<Line> {
+    unsafe {
+        let mut lines = Vec::new();
+        if term.mode & MODE_ALTSCREEN == 0 {
+            for i in 0..term.histn {
+                let i =
+                    (term.histi - term.histn + i + term.histmax) % term.histmax;
+                lines.push(*term.hist.offset(i as isize));
+            }
+        }
+        for y in 0..term.row {
+            lines.push(*term.line.offset(y as isize));
+        }
+        while lines.last().is_some_and(|&l| tlinelen(l, term.col) == 0) {
+            lines.pop();
+        }
+        lines
+    }
+}
+
+/// Return the SGR sequence that sets the attributes to `style`.
+fn sgr(style: Style) -> String {
+    let mut s = String::from("\x1b[0");
+    for (attr, n) in [
+        (ATTR_BOLD, 1),
+        (ATTR_FAINT, 2),
+        (ATTR_ITALIC, 3),
+        (ATTR_BLINK, 5),
+        (ATTR_REVERSE, 7),
+        (ATTR_INVISIBLE, 8),
+        (ATTR_STRUCK, 9),
+    ] {
+        if style.mode & attr != 0 {
+            let _ = write!(s, ";{n}");
+        }
+    }
+    if style.mode & ATTR_UNDERLINE != 0 {
+        let _ = match style.mode & ATTR_USTYLE {
+            USTYLE_DOUBLE => write!(s, ";4:2"),
+            USTYLE_CURLY => write!(s, ";4:3"),
+            USTYLE_DOTTED => write!(s, ";4:4"),
+            USTYLE_DASHED => write!(s, ";4:5"),
+            _ => write!(s, ";4"),
+        };
+    }
+    if style.ucolor != 0 {
+        let _ = match style.ucolor - 1 {
+            c @ 0..=255 => write!(s, ";58:5:{c}"),
+            c => {
+                let rgb = colorrgb(c);
+                write!(
+                    s,
+                    ";58:2::{}:{}:{}",
+                    rgb >> 16,
+                    rgb >> 8 & 0xff,
+                    rgb & 0xff
+                )
+            }
+        };
+    }
+    unsafe {
+        if style.fg != defaultfg {
+            sgrcolor(&mut s, style.fg, 30);
+        }
+        if style.bg != defaultbg {
+            sgrcolor(&mut s, style.bg, 40);
+        }
+    }
+    s.push('m');
+    s
+}
+
+/// Append the SGR parameters for color `c` to `s`, where `base` is 30 for the
+/// foreground and 40 for the background.
+fn sgrcolor(s: &mut String, c: u32, base: u32) {
+    let _ = match c {
+        0..=7 => write!(s, ";{}", base + c),
+        8..=15 => write!(s, ";{}", base + 60 + c - 8),
+        16..=255 => write!(s, ";{};5;{c}", base + 8),
+        _ => {
+            let rgb = colorrgb(c);
+            write!(
+                s,
+                ";{};2;{};{};{}",
+                base + 8,
+                rgb >> 16,
+                rgb >> 8 & 0xff,
+                rgb & 0xff
+            )
+        }
+    };
+}
+
+/// Return the inline CSS for `style`.
+fn css(style: Style) -> String {
+    let mut s = String::new();
+    let (mut fg, mut bg) = (style.fg, style.bg);
+    if style.mode & ATTR_REVERSE != 0 {
+        std::mem::swap(&mut fg, &mut bg);
+    }
+    if style.mode & ATTR_INVISIBLE != 0 {
+        fg = bg;
+    }
+    unsafe {
+        if fg != defaultfg {
+            let _ = write!(s, "color:{};", csscolor(fg));
+        }
+        if bg != defaultbg {
+            let _ = write!(s, "background-color:{};", csscolor(bg));
+        }
+    }
+    if style.mode & ATTR_BOLD != 0 {
+        s.push_str("font-weight:bold;");
+    }
+    if style.mode & ATTR_FAINT != 0 {
+        s.push_str("opacity:0.5;");
+    }
+    if style.mode & ATTR_ITALIC != 0 {
+        s.push_str("font-style:italic;");
+    }
+    match (
+        style.mode & ATTR_UNDERLINE != 0,
+        style.mode & ATTR_STRUCK != 0,
+    ) {
+        (true, true) => s.push_str("text-decoration:underline line-through;"),
+        (true, false) => s.push_str("text-decoration:underline;"),
+        (false, true) => s.push_str("text-decoration:line-through;"),
+        (false, false) => {}
+    }
+    if style.mode & ATTR_UNDERLINE != 0 {
+        match style.mode & ATTR_USTYLE {
+            USTYLE_DOUBLE => s.push_str("text-decoration-style:double;"),
+            USTYLE_CURLY => s.push_str("text-decoration-style:wavy;"),
+            USTYLE_DOTTED => s.push_str("text-decoration-style:dotted;"),
+            USTYLE_DASHED => s.push_str("text-decoration-style:dashed;"),
+            _ => {}
+        }
+        if style.ucolor != 0 && style.mode & ATTR_INVISIBLE == 0 {
+            let _ = write!(
+                s,
+                "text-decoration-color:{};",
+                csscolor(style.ucolor - 1)
+            );
+        }
+    }
+    s
+}
+
+fn csscolor(c: u32) -> String {
+    format!("#{:06x}", colorrgb(c))
+}
+
+/// Return color `c` of a glyph as 0xrrggbb.
+pub(crate) fn colorrgb(c: u32) -> u32 {
+    if c & 1 << 24 != 0 {
+        return c & 0xffffff;
+    }
+    let (mut r, mut g, mut b) = (0, 0, 0);
+    if x::xgetcolor(c as c_int, &mut r, &mut g, &mut b) == 0 {
+        return (r as u32) << 16 | (g as u32) << 8 | b as u32;
+    }
+    // same colors as xloadcolor
+    let sixd = |x: u32| if x == 0 { 0 } else { 0x37 + 0x28 * x };
+    match c {
+        0..=15 => COLORS[c as usize],
+        16..=231 => {
+            let i = c - 16;
+            sixd(i / 36 % 6) << 16 | sixd(i / 6 % 6) << 8 | sixd(i % 6)
+        }
+        232..=255 => (0x08 + 0x0a * (c - 232)) * 0x010101,
+        _ if c == unsafe { defaultbg } => COLORS[0],
+        _ => COLORS[7],
+    }
+}
+
+/// Copy the screen and history to the clipboard, in the [`Format`] in `a.i`.
+pub fn exportclip(a: &Arg) {
+    x::xsetsel(&export(Format::from_arg(a)));
+    x::xclipcopy();
+}
+
+/// Return the directory for the files written by [`exportfile`].
+fn exportdir() -> Option<PathBuf> {
+    if let Some(dir) = EXPORTDIR {
+        return Some(PathBuf::from(dir));
+    }
+    let dir = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".local/state"),
+    };
+    Some(dir.join("rt"))
+}
+
+/// Write the screen and history to a new file in `EXPORTDIR`, in the
+/// [`Format`] in `a.i`. Only the user can read it, since the history can hold
+/// anything.
+pub fn exportfile(a: &Arg) {
+    let format = Format::from_arg(a);
+    let Some(dir) = exportdir() else {
+        tmessage("couldn't export: HOME is not set".to_owned());
+        return;
+    };
+    if let Err(e) = DirBuilder::new().recursive(true).mode(0o700).create(&dir) {
+        tmessage(format!("couldn't export to {}: {e}", dir.display()));
+        return;
+    }
+    let stamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+
+    for n in 0.. {
+        let path = dir.join(if n == 0 {
+            format!("rt-{stamp}.{}", format.extension())
+        } else {
+            format!("rt-{stamp}-{n}.{}", format.extension())
+        });
+        let res = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .and_then(|mut f| f.write_all(&export(format)));
+        match res {
+            Ok(()) => {
+                tmessage(format!("exported to {}", path.display()));
+                return;
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => {
+                tmessage(format!("couldn't export to {}: {e}", path.display()));
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! Images shown on the grid of the terminal.
+//!
+//! An image is cut into strips one row of cells high, which are attached to
+//! the lines of `term` showing them. The strips move along with their lines
+//! when the screen scrolls, into the history too, and when the terminal is
+//! resized, and lose the cells that are cleared or written to.
+
+use std::{collections::HashSet, ffi::c_int, rc::Rc};
+
+use crate::{
+    bindgen::{term, win, Line},
+    state, tfulldirt, tsetdirt,
+};
+
+/// The size of a cell in pixels for terminals without a window.
+const CELL_WIDTH: usize = 10;
+const CELL_HEIGHT: usize = 20;
+/// Largest number of bytes of all images shown together, after which the
+/// oldest placements are dropped.
+pub(crate) const SHOWN_MAX: usize = 320 << 20;
+
+/// An image with an alpha channel.
+#[derive(Debug)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    /// the pixels, row by row, as 0xaarrggbb
+    pub pixels: Vec<u32>,
+}
+
+/// What a strip was shown for, to find all strips of an image again.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub(crate) struct Placement {
+    /// the id of the image and of the placement in the kitty graphics
+    /// protocol, or 0
+    pub(crate) id: u32,
+    pub(crate) placement: u32,
+    /// the z-index of the placement, which only matters for deleting it
+    pub(crate) z: i32,
+    /// a number telling apart each time an image is shown
+    pub(crate) serial: u32,
+}
+
+impl Placement {
+    /// Return a new placement of the image `id` with the placement id
+    /// `placement`.
+    pub(crate) fn new(id: u32, placement: u32, z: i32) -> Self {
+        let state = unsafe { state() };
+        state.serial = state.serial.wrapping_add(1);
+        Self { id, placement, z, serial: state.serial }
+    }
+}
+
+/// The part of an image shown on one row of cells.
+#[derive(Clone)]
+pub(crate) struct Strip {
+    pub(crate) image: Rc<Image>,
+    pub(crate) placement: Placement,
+    /// the column of the first cell
+    pub(crate) x: c_int,
+    /// the row of pixels of the image at the top of the cells
+    pub(crate) top: usize,
+    /// the size of a cell in pixels when the image was placed
+    pub(crate) cw: usize,
+    pub(crate) ch: usize,
+    /// for each cell from `x`, whether it still shows the image
+    pub(crate) cells: Vec<bool>,
+}
+
+impl Strip {
+    /// Return whether the strip shows its image in column `x`.
+    pub(crate) fn covers(&self, x: c_int) -> bool {
+        x >= self.x && self.cells.get((x - self.x) as usize) == Some(&true)
+    }
+}
+
+impl Image {
+    /// Return the number of bytes taken by the pixels.
+    pub(crate) fn size(&self) -> usize {
+        self.pixels.len() * 4
+    }
+}
+
+/// Return the size of a cell in pixels.
+pub(crate) fn cellsize() -> (usize, usize) {
+    unsafe {
+        if win.cw > 0 && win.ch > 0 {
+            (win.cw as usize, win.ch as usize)
+        } else {
+            (CELL_WIDTH, CELL_HEIGHT)
+        }
+    }
+}
+
+/// Return the number of rows of cells covered by `image`.
+pub(crate) fn rows(image: &Image) -> c_int {
+    image.height.div_ceil(cellsize().1) as c_int
+}
+
+/// Return the number of columns of cells covered by `image`.
+pub(crate) fn cols(image: &Image) -> c_int {
+    image.width.div_ceil(cellsize().0) as c_int
+}
+
+/// Show strip `row` of `image` on row `y` of the screen from column `x`,
+/// covering any image shown there before.
+pub(crate) fn attach(
+    image: &Rc<Image>,
+    placement: Placement,
+    x: c_int,
+    y: c_int,
+    row: c_int,
+) {
+    unsafe {
+        let (cw, ch) = cellsize();
+        let cols = cols(image).min(term.col - x);
+        if cols <= 0 {
+            return;
+        }
+        let line = *term.line.offset(y as isize);
+        clear(line, x, x + cols - 1);
+        state()
+            .images
+            .entry(line as usize)
+            .or_default()
+            .push(Strip {
+                image: image.clone(),
+                placement,
+                x,
+                top: row as usize * ch,
+                cw,
+                ch,
+                cells: vec![true; cols as usize],
+            });
+        tsetdirt(y, y);
+    }
+}
+
+/// Stop showing images in the cells from `x1` to `x2` of `line`.
+pub(crate) fn clear(line: Line, x1: c_int, x2: c_int) {
+    let images = &mut unsafe { state() }.images;
+    if images.is_empty() {
+        return;
+    }
+    let Some(strips) = images.get_mut(&(line as usize)) else {
+        return;
+    };
+    for s in strips.iter_mut() {
+        for (x, c) in (s.x..).zip(s.cells.iter_mut()) {
+            if (x1..=x2).contains(&x) {
+                *c = false;
+            }
+        }
+    }
+    strips.retain(|s| s.cells.contains(&true));
+    if strips.is_empty() {
+        images.remove(&(line as usize));
+    }
+}
+
+/// Stop showing the strips for which `f` returns true, on the screens and in
+/// the history. `f` can't call [`state`], which is borrowed while it runs.
+pub(crate) fn delete(mut f: impl FnMut(&Strip) -> bool) {
+    let images = &mut unsafe { state() }.images;
+    let n: usize = images.values().map(Vec::len).sum();
+    images.retain(|_, strips| {
+        strips.retain(|s| !f(s));
+        !strips.is_empty()
+    });
+    if images.values().map(Vec::len).sum::<usize>() != n {
+        tfulldirt();
+    }
+}
+
+/// Return whether `f` returns true for one of the strips on the screens or in
+/// the history. `f` can't call [`state`] either.
+pub(crate) fn shown(f: impl FnMut(&Strip) -> bool) -> bool {
+    unsafe { state() }.images.values().flatten().any(f)
+}
+
+/// Return the number of bytes taken by the images shown on the screens and in
+/// the history.
+pub(crate) fn used() -> usize {
+    let mut seen = HashSet::new();
+    unsafe { state() }
+        .images
+        .values()
+        .flatten()
+        .filter(|s| seen.insert(Rc::as_ptr(&s.image)))
+        .map(|s| s.image.size())
+        .sum()
+}
+
+/// Make room for showing a new image of `size` bytes by dropping the oldest
+/// placements while the images shown would take up more than `SHOWN_MAX`.
+/// Return false if the image alone is larger than that.
+pub(crate) fn reserve(size: usize) -> bool {
+    if size > SHOWN_MAX {
+        return false;
+    }
+    while used() + size > SHOWN_MAX {
+        let images = &unsafe { state() }.images;
+        let oldest =
+            images.values().flatten().map(|s| s.placement.serial).min();
+        let Some(oldest) = oldest else {
+            break;
+        };
+        delete(|s| s.placement.serial == oldest);
+    }
+    true
+}
+
+/// Return the placements of the images shown in the cell at column `x` of
+/// row `y` of the screen.
+pub(crate) fn placements(x: c_int, y: c_int) -> Vec<Placement> {
+    unsafe {
+        if !(0..term.row).contains(&y) {
+            return Vec::new();
+        }
+        let line = *term.line.offset(y as isize);
+        strips(line)
+            .into_iter()
+            .filter(|s| s.covers(x))
+            .map(|s| s.placement)
+            .collect()
+    }
+}
+
+/// Return a copy of the strips of images shown on `line`.
+pub(crate) fn strips(line: Line) -> Vec<Strip> {
+    unsafe { state() }
+        .images
+        .get(&(line as usize))
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Detach the strips of images shown on `line`, for when it goes away or moves.
+pub(crate) fn take(line: Line) -> Vec<Strip> {
+    unsafe { state() }
+        .images
+        .remove(&(line as usize))
+        .unwrap_or_default()
+}
+
+/// Attach `strips` to `line`, which is `col` cells wide, cutting off the cells
+/// past its end.
+pub(crate) fn put(line: Line, strips: Vec<Strip>, col: c_int) {
+    let strips: Vec<Strip> = strips
+        .into_iter()
+        .filter_map(|mut s| {
+            s.cells.truncate((col - s.x).max(0) as usize);
+            s.cells.contains(&true).then_some(s)
+        })
+        .collect();
+    if !strips.is_empty() {
+        unsafe { state() }.images.insert(line as usize, strips);
+    }
+}
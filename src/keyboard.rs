@@ -0,0 +1,638 @@
+//! The kitty keyboard protocol, which reports keys as escape sequences telling
+//! apart the keys st's `key[]` table sends the same bytes for, like Ctrl+I and
+//! Tab, and can report repeats and releases too.
+//!
+//! Programs turn parts of it on with flags kept on a stack for each screen.
+//! Without any, xterm's modifyOtherKeys can be turned on instead, which sends
+//! ordinary keys with modifiers as `CSI 27 ; <modifiers> ; <key> ~`. Keys left
+//! alone by both are still sent by kpress, from the `key[]` table in config.h.
+
+use std::{
+    ffi::{c_char, c_int, c_uint},
+    ptr::null_mut,
+};
+
+use x11::{
+    keysym::*,
+    xlib::{
+        ControlMask, KeyPress, LockMask, Mod1Mask, Mod2Mask, Mod4Mask,
+        ShiftMask,
+    },
+};
+
+use crate::{
+    bindgen::{self, win, KeySym, XKeyEvent},
+    state, ttywrite,
+    win::MODE_KBDLOCK,
+};
+
+// the flags of the protocol
+const DISAMBIGUATE: c_uint = 1 << 0;
+const EVENTS: c_uint = 1 << 1;
+const ALTERNATES: c_uint = 1 << 2;
+const ALLKEYS: c_uint = 1 << 3;
+const TEXT: c_uint = 1 << 4;
+const FLAGS: c_uint = DISAMBIGUATE | EVENTS | ALTERNATES | ALLKEYS | TEXT;
+
+/// The most flags kept on the stack of a screen, after which the oldest ones
+/// are dropped.
+const STACK_MAX: usize = 16;
+
+// the event types
+const PRESS: c_int = 1;
+const REPEAT: c_int = 2;
+const RELEASE: c_int = 3;
+
+// the modifier bits, reported plus one
+const SHIFT: c_uint = 1 << 0;
+const ALT: c_uint = 1 << 1;
+const CTRL: c_uint = 1 << 2;
+const SUPER: c_uint = 1 << 3;
+const CAPSLOCK: c_uint = 1 << 6;
+const NUMLOCK: c_uint = 1 << 7;
+
+/// Return the flags in effect on the current screen.
+fn flags() -> c_uint {
+    unsafe { state() }.kbd.last().copied().unwrap_or(0)
+}
+
+/// Handle `CSI <private> <a> ; <b> u`, which pushes the flags `a` for `>`, pops
+/// `a` entries for `<`, replaces, sets or clears the flags `a` for `=` as `b`
+/// is 1, 2 or 3, and queries them for `?`.
+pub(crate) fn csi(private: u8, a: c_int, b: c_int) {
+    let cur = flags();
+    let n = a.max(1) as usize;
+    let a = a as c_uint & FLAGS;
+    if private == b'?' {
+        ttywrite(format!("\x1b[?{cur}u").as_bytes(), false);
+        return;
+    }
+    let stack = &mut unsafe { state() }.kbd;
+    match private {
+        b'>' => {
+            if stack.len() == STACK_MAX {
+                stack.remove(0);
+            }
+            stack.push(a);
+        }
+        b'<' => {
+            stack.truncate(stack.len().saturating_sub(n));
+        }
+        b'=' => {
+            let new = match b {
+                2 => cur | a,
+                3 => cur & !a,
+                _ => a,
+            };
+            match stack.last_mut() {
+                Some(f) => *f = new,
+                None => stack.push(new),
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Handle XTMODKEYS, `CSI > 4 ; <level> m` or `CSI > 4 n` to reset it, and
+/// XTQMODKEYS, `CSI ? 4 m`, for the modifyOtherKeys level. The other resources
+/// they set are left alone.
+pub(crate) fn modkeys(private: Option<u8>, action: u8, params: &[c_int]) {
+    if params.first() != Some(&4) {
+        return;
+    }
+    let level = &mut unsafe { state() }.modkeys;
+    match (private, action) {
+        (Some(b'>'), b'm') => {
+            *level = params.get(1).copied().unwrap_or(0).clamp(0, 2)
+        }
+        (Some(b'>'), b'n') => *level = 0,
+        (Some(b'?'), b'm') => {
+            let s = format!("\x1b[>4;{level}m");
+            ttywrite(s.as_bytes(), false)
+        }
+        _ => {}
+    }
+}
+
+/// A key of an event, looked up with the modifiers of the event.
+struct Key {
+    ksym: KeySym,
+    /// the text it types with all the modifiers held
+    typed: Option<String>,
+    /// the text it types without the modifiers changing what it sends
+    text: Option<String>,
+    /// the text it types without shift and caps lock either
+    base: Option<String>,
+}
+
+impl Key {
+    fn new(e: &XKeyEvent, ksym: KeySym) -> Self {
+        let cmds = ControlMask | Mod1Mask | Mod4Mask;
+        Self {
+            ksym,
+            typed: lookup(e, e.state).1,
+            text: lookup(e, e.state & !cmds).1,
+            base: lookup(e, e.state & !(cmds | ShiftMask | LockMask)).1,
+        }
+    }
+}
+
+/// Report the key press or release `e` as the flags in effect or the
+/// modifyOtherKeys level ask, returning whether it was handled. Keys sent as
+/// they would be without either are left to kpress, and so are the shortcuts
+/// in config.h.
+pub(crate) fn kreport(e: &mut XKeyEvent) -> bool {
+    let press = e.type_ == KeyPress;
+    let event = event(e.keycode, press);
+    if unsafe { win.mode } & MODE_KBDLOCK != 0 {
+        return false;
+    }
+    let (ksym, _) = lookup(e, e.state);
+    if press && unsafe { bindgen::xshortcut(ksym, e.state) } != 0 {
+        forget(e.keycode);
+        return false;
+    }
+    let flags = flags();
+    let s = if flags == 0 {
+        if !press {
+            return false;
+        }
+        modother(unsafe { state() }.modkeys, e.state, &Key::new(e, ksym))
+    } else {
+        // without an event the press went to a shortcut
+        let Some(event) = event else {
+            return true;
+        };
+        encode(flags, event, e.state, &Key::new(e, ksym))
+    };
+    match s {
+        Some(s) if !s.is_empty() => ttywrite(s.as_bytes(), true),
+        Some(_) => {}
+        None => return false,
+    }
+    true
+}
+
+/// Return the escape sequence reporting the `event` of `key` with the X
+/// modifier `state` as `flags` ask, an empty one if nothing is sent, or `None`
+/// if the key is left to kpress.
+fn encode(
+    flags: c_uint,
+    event: c_int,
+    state: c_uint,
+    key: &Key,
+) -> Option<String> {
+    let all = flags & ALLKEYS != 0;
+    let mut mods = modifiers(state, key.ksym, event != RELEASE);
+    if !all {
+        mods &= !(CAPSLOCK | NUMLOCK);
+    }
+    let held = mods & !(CAPSLOCK | NUMLOCK);
+    let (code, last) = match functional(key.ksym) {
+        Some(f) => f,
+        None => {
+            let base = key.base.as_ref();
+            match base.map(|s| s.chars().collect::<Vec<_>>()).as_deref() {
+                Some(&[c]) => (c as u32, b'u'),
+                _ => return None,
+            }
+        }
+    };
+
+    if !all {
+        let modifier = matches!(code, 57358..=57360 | 57441..=57454);
+        let keypad = (57399..=57427).contains(&code);
+        let textkey =
+            functional(key.ksym).is_none() || keypad && key.text.is_some();
+        let legacy = if modifier || flags & DISAMBIGUATE == 0 {
+            true
+        } else if textkey {
+            held & !SHIFT == 0
+        } else {
+            held == 0 && (matches!(code, 9 | 13 | 127) || last != b'u')
+        };
+        if legacy {
+            // only the release of keys with a legacy escape sequence is
+            // reported, since it can be told apart from their press
+            if event != RELEASE {
+                return None;
+            }
+            if modifier || textkey || last == b'u' || flags & EVENTS == 0 {
+                return Some(String::new());
+            }
+        }
+    }
+    if event == RELEASE && flags & EVENTS == 0 {
+        return Some(String::new());
+    }
+
+    let mut k = if code == 1 { String::new() } else { code.to_string() };
+    if flags & ALTERNATES != 0 && held & SHIFT != 0 {
+        if let Some(c) = key.text.as_ref().and_then(|s| s.chars().next()) {
+            if c as u32 != code && last == b'u' {
+                k += &format!(":{}", c as u32);
+            }
+        }
+    }
+    let event = if flags & EVENTS != 0 { event } else { PRESS };
+    let text = key
+        .text
+        .as_ref()
+        .filter(|_| all && flags & TEXT != 0 && event != RELEASE);
+
+    let mut params = Vec::new();
+    if mods != 0 || event != PRESS || text.is_some() {
+        params.push(if event == PRESS {
+            (mods + 1).to_string()
+        } else {
+            format!("{}:{event}", mods + 1)
+        });
+    }
+    if let Some(text) = text {
+        let codes: Vec<_> =
+            text.chars().map(|c| (c as u32).to_string()).collect();
+        params.push(codes.join(":"));
+    }
+    if !params.is_empty() && k.is_empty() {
+        k = "1".into();
+    }
+    let mut s = format!("\x1b[{k}");
+    for p in params {
+        s += ";";
+        s += &p;
+    }
+    s.push(last as char);
+    Some(s)
+}
+
+/// Return the press of `key` with the X modifier `state` as `CSI 27 ;
+/// <modifiers> ; <key> ~` if the modifyOtherKeys `level` asks for it. Level 2
+/// does so for all keys typing text, and Return, Tab, Backspace and Escape,
+/// with modifiers other than shift. Level 1 only does so for the ones whose
+/// modifiers would be lost otherwise, leaving Alt and the control characters
+/// of Ctrl alone. Other keys are left to the `key[]` table.
+fn modother(level: c_int, state: c_uint, key: &Key) -> Option<String> {
+    let mods = modifiers(state, key.ksym, true) & !(CAPSLOCK | NUMLOCK);
+    if level == 0 || mods & !SHIFT == 0 {
+        return None;
+    }
+    #[allow(non_upper_case_globals)]
+    let code = match key.ksym as c_uint {
+        XK_Return => 13,
+        XK_Tab | XK_ISO_Left_Tab => 9,
+        XK_BackSpace => 127,
+        XK_Escape => 27,
+        _ if functional(key.ksym).is_some() => return None,
+        _ => {
+            let text = key.text.as_ref();
+            match text.map(|s| s.chars().collect::<Vec<_>>()).as_deref() {
+                Some(&[c]) => c as u32,
+                _ => return None,
+            }
+        }
+    };
+    if level == 1 {
+        // Ctrl with shift or a key that has no control character
+        let lost = mods & SHIFT != 0 || key.typed.is_some();
+        if mods & SUPER == 0
+            && (mods & CTRL == 0 || !lost && !matches!(code, 9 | 13 | 27 | 127))
+        {
+            return None;
+        }
+    }
+    Some(format!("\x1b[27;{};{code}~", mods + 1))
+}
+
+/// Return the event type of a press or release of the key `keycode`, or `None`
+/// for the release of a key that was not seen going down.
+fn event(keycode: c_uint, press: bool) -> Option<c_int> {
+    let down = &mut unsafe { state() }.down;
+    let held = down.iter().position(|&k| k == keycode);
+    match (press, held) {
+        (true, Some(_)) => Some(REPEAT),
+        (true, None) => {
+            down.push(keycode);
+            Some(PRESS)
+        }
+        (false, Some(i)) => {
+            down.swap_remove(i);
+            Some(RELEASE)
+        }
+        (false, None) => None,
+    }
+}
+
+/// Stop tracking the key `keycode`, so that its release is not reported.
+fn forget(keycode: c_uint) {
+    unsafe { state() }.down.retain(|&k| k != keycode);
+}
+
+/// Forget the keys held down when the window loses the focus, as their
+/// releases go to another window.
+pub(crate) fn unfocus() {
+    unsafe { state() }.down.clear();
+}
+
+/// Look up the keysym and the text of the key of `e` as if the modifiers in
+/// `state` were held. Text with control characters is left out.
+fn lookup(e: &XKeyEvent, state: c_uint) -> (KeySym, Option<String>) {
+    let mut e = *e;
+    e.state = state;
+    let mut ksym = 0;
+    let mut buf = [0 as c_char; 64];
+    let n = unsafe {
+        bindgen::XLookupString(
+            &mut e,
+            buf.as_mut_ptr(),
+            buf.len() as c_int,
+            &mut ksym,
+            null_mut(),
+        )
+    };
+    let bytes: Vec<u8> =
+        buf[..n.max(0) as usize].iter().map(|&c| c as u8).collect();
+    let text = String::from_utf8(bytes)
+        .ok()
+        .filter(|s| !s.is_empty() && !s.chars().any(char::is_control));
+    (ksym, text)
+}
+
+/// Return the modifier bits of the X modifier `state` of a key event for
+/// `ksym`. X leaves a modifier key out of the state of its own press and in
+/// that of its release, which is the other way around here.
+#[allow(non_upper_case_globals)]
+fn modifiers(state: c_uint, ksym: KeySym, press: bool) -> c_uint {
+    let mut mods = 0;
+    for (mask, bit) in [
+        (ShiftMask, SHIFT),
+        (Mod1Mask, ALT),
+        (ControlMask, CTRL),
+        (Mod4Mask, SUPER),
+        (LockMask, CAPSLOCK),
+        (Mod2Mask, NUMLOCK),
+    ] {
+        if state & mask != 0 {
+            mods |= bit;
+        }
+    }
+    let own = match ksym as c_uint {
+        XK_Shift_L | XK_Shift_R => SHIFT,
+        XK_Alt_L | XK_Alt_R => ALT,
+        XK_Control_L | XK_Control_R => CTRL,
+        XK_Super_L | XK_Super_R => SUPER,
+        _ => 0,
+    };
+    if press {
+        mods | own
+    } else {
+        mods & !own
+    }
+}
+
+/// Return the number and the final byte of the escape sequence for `ksym`, if
+/// it is not a key typing text.
+#[allow(non_upper_case_globals)]
+fn functional(ksym: KeySym) -> Option<(u32, u8)> {
+    let k = ksym as c_uint;
+    Some(match k {
+        XK_Escape => (27, b'u'),
+        XK_Return => (13, b'u'),
+        XK_Tab | XK_ISO_Left_Tab => (9, b'u'),
+        XK_BackSpace => (127, b'u'),
+        XK_Insert => (2, b'~'),
+        XK_Delete => (3, b'~'),
+        XK_Left => (1, b'D'),
+        XK_Right => (1, b'C'),
+        XK_Up => (1, b'A'),
+        XK_Down => (1, b'B'),
+        XK_Page_Up => (5, b'~'),
+        XK_Page_Down => (6, b'~'),
+        XK_Home => (1, b'H'),
+        XK_End => (1, b'F'),
+        XK_Caps_Lock => (57358, b'u'),
+        XK_Scroll_Lock => (57359, b'u'),
+        XK_Num_Lock => (57360, b'u'),
+        XK_Print => (57361, b'u'),
+        XK_Pause => (57362, b'u'),
+        XK_Menu => (57363, b'u'),
+        XK_F1 => (1, b'P'),
+        XK_F2 => (1, b'Q'),
+        XK_F3 => (13, b'~'),
+        XK_F4 => (1, b'S'),
+        XK_F5 => (15, b'~'),
+        XK_F6 => (17, b'~'),
+        XK_F7 => (18, b'~'),
+        XK_F8 => (19, b'~'),
+        XK_F9 => (20, b'~'),
+        XK_F10 => (21, b'~'),
+        XK_F11 => (23, b'~'),
+        XK_F12 => (24, b'~'),
+        XK_F13..=XK_F35 => (57376 + k - XK_F13, b'u'),
+        XK_KP_0..=XK_KP_9 => (57399 + k - XK_KP_0, b'u'),
+        XK_KP_Decimal => (57409, b'u'),
+        XK_KP_Divide => (57410, b'u'),
+        XK_KP_Multiply => (57411, b'u'),
+        XK_KP_Subtract => (57412, b'u'),
+        XK_KP_Add => (57413, b'u'),
+        XK_KP_Enter => (57414, b'u'),
+        XK_KP_Equal => (57415, b'u'),
+        XK_KP_Separator => (57416, b'u'),
+        XK_KP_Left => (57417, b'u'),
+        XK_KP_Right => (57418, b'u'),
+        XK_KP_Up => (57419, b'u'),
+        XK_KP_Down => (57420, b'u'),
+        XK_KP_Page_Up => (57421, b'u'),
+        XK_KP_Page_Down => (57422, b'u'),
+        XK_KP_Home => (57423, b'u'),
+        XK_KP_End => (57424, b'u'),
+        XK_KP_Insert => (57425, b'u'),
+        XK_KP_Delete => (57426, b'u'),
+        XK_KP_Begin => (57427, b'u'),
+        XK_Shift_L => (57441, b'u'),
+        XK_Control_L => (57442, b'u'),
+        XK_Alt_L => (57443, b'u'),
+        XK_Super_L => (57444, b'u'),
+        XK_Hyper_L => (57445, b'u'),
+        XK_Meta_L => (57446, b'u'),
+        XK_Shift_R => (57447, b'u'),
+        XK_Control_R => (57448, b'u'),
+        XK_Alt_R => (57449, b'u'),
+        XK_Super_R => (57450, b'u'),
+        XK_Hyper_R => (57451, b'u'),
+        XK_Meta_R => (57452, b'u'),
+        XK_ISO_Level3_Shift => (57453, b'u'),
+        XK_ISO_Level5_Shift => (57454, b'u'),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Return the key `ksym` typing `text`, or `base` without shift.
+    fn key(ksym: c_uint, base: Option<&str>, text: Option<&str>) -> Key {
+        Key {
+            ksym: ksym as KeySym,
+            typed: None,
+            text: text.map(Into::into),
+            base: base.map(Into::into),
+        }
+    }
+
+    #[test]
+    fn legacy_keys() {
+        let a = key(XK_a, Some("a"), Some("a"));
+        let f = DISAMBIGUATE;
+        // text and keys with legacy sequences are left to kpress
+        assert_eq!(encode(f, PRESS, 0, &a), None);
+        assert_eq!(encode(f, PRESS, ShiftMask, &a), None);
+        let left = key(XK_Left, None, None);
+        assert_eq!(encode(f, PRESS, 0, &left), None);
+        // and so are their releases, unless events are reported
+        assert_eq!(encode(f, RELEASE, 0, &a), Some(String::new()));
+        assert_eq!(
+            encode(f | EVENTS, RELEASE, 0, &left),
+            Some("\x1b[1;1:3D".into())
+        );
+    }
+
+    #[test]
+    fn disambiguate() {
+        let f = DISAMBIGUATE;
+        let i = key(XK_i, Some("i"), Some("i"));
+        assert_eq!(
+            encode(f, PRESS, ControlMask, &i),
+            Some("\x1b[105;5u".into())
+        );
+        let esc = key(XK_Escape, None, None);
+        assert_eq!(encode(f, PRESS, 0, &esc), Some("\x1b[27u".into()));
+        let tab = key(XK_Tab, None, None);
+        assert_eq!(encode(f, PRESS, 0, &tab), None);
+        assert_eq!(
+            encode(f, PRESS, ControlMask, &tab),
+            Some("\x1b[9;5u".into())
+        );
+        let up = key(XK_Up, None, None);
+        assert_eq!(
+            encode(f, PRESS, ShiftMask | Mod1Mask, &up),
+            Some("\x1b[1;4A".into())
+        );
+        let f5 = key(XK_F5, None, None);
+        assert_eq!(
+            encode(f, PRESS, ControlMask, &f5),
+            Some("\x1b[15;5~".into())
+        );
+    }
+
+    #[test]
+    fn all_keys() {
+        let f = DISAMBIGUATE | ALLKEYS;
+        let a = key(XK_a, Some("a"), Some("a"));
+        assert_eq!(encode(f, PRESS, 0, &a), Some("\x1b[97u".into()));
+        let shifted = key(XK_A, Some("a"), Some("A"));
+        assert_eq!(
+            encode(f, PRESS, ShiftMask, &shifted),
+            Some("\x1b[97;2u".into())
+        );
+        // with the shifted key and the text
+        assert_eq!(
+            encode(f | ALTERNATES, PRESS, ShiftMask, &shifted),
+            Some("\x1b[97:65;2u".into())
+        );
+        assert_eq!(
+            encode(f | TEXT, PRESS, ShiftMask, &shifted),
+            Some("\x1b[97;2;65u".into())
+        );
+        // caps lock is reported too
+        assert_eq!(
+            encode(f, PRESS, LockMask, &shifted),
+            Some("\x1b[97;65u".into())
+        );
+        // and so are the modifier keys themselves
+        let shift = key(XK_Shift_L, None, None);
+        assert_eq!(encode(f, PRESS, 0, &shift), Some("\x1b[57441;2u".into()));
+        assert_eq!(
+            encode(f | EVENTS, RELEASE, ShiftMask, &shift),
+            Some("\x1b[57441;1:3u".into())
+        );
+    }
+
+    #[test]
+    fn events() {
+        let f = DISAMBIGUATE | EVENTS | ALLKEYS;
+        let a = key(XK_a, Some("a"), Some("a"));
+        assert_eq!(encode(f, REPEAT, 0, &a), Some("\x1b[97;1:2u".into()));
+        assert_eq!(encode(f, RELEASE, 0, &a), Some("\x1b[97;1:3u".into()));
+        // releases are swallowed without the flag
+        assert_eq!(encode(ALLKEYS, RELEASE, 0, &a), Some(String::new()));
+        assert_eq!(encode(ALLKEYS, REPEAT, 0, &a), Some("\x1b[97u".into()));
+    }
+
+    #[test]
+    fn modify_other_keys() {
+        let a = key(XK_a, Some("a"), Some("a"));
+        assert_eq!(modother(0, ControlMask, &a), None);
+        // a plain key or one with shift is left alone
+        assert_eq!(modother(2, 0, &a), None);
+        assert_eq!(modother(2, ShiftMask, &a), None);
+        assert_eq!(modother(2, ControlMask, &a), Some("\x1b[27;5;97~".into()));
+        assert_eq!(modother(2, Mod1Mask, &a), Some("\x1b[27;3;97~".into()));
+        // level 1 leaves Alt and the control characters alone
+        assert_eq!(modother(1, Mod1Mask, &a), None);
+        assert_eq!(modother(1, ControlMask, &a), None);
+        assert_eq!(
+            modother(1, ControlMask | ShiftMask, &a),
+            Some("\x1b[27;6;97~".into())
+        );
+        let comma = Key {
+            typed: Some(",".into()),
+            ..key(XK_comma, Some(","), Some(","))
+        };
+        assert_eq!(
+            modother(1, ControlMask, &comma),
+            Some("\x1b[27;5;44~".into())
+        );
+        let tab = key(XK_Tab, None, None);
+        assert_eq!(modother(1, ControlMask, &tab), Some("\x1b[27;5;9~".into()));
+        // other function keys are left to the key table
+        let up = key(XK_Up, None, None);
+        assert_eq!(modother(2, ControlMask, &up), None);
+    }
+
+    #[test]
+    fn held_keys() {
+        let mut t = crate::terminal::Terminal::new(10, 2);
+        t.with(|| {
+            assert_eq!(event(38, false), None);
+            assert_eq!(event(38, true), Some(PRESS));
+            assert_eq!(event(38, true), Some(REPEAT));
+            assert_eq!(event(38, false), Some(RELEASE));
+            assert_eq!(event(38, true), Some(PRESS));
+            unfocus();
+            assert_eq!(event(38, false), None);
+        });
+    }
+
+    #[test]
+    fn flag_stack() {
+        let mut t = crate::terminal::Terminal::new(10, 2);
+        t.with(|| {
+            csi(b'>', 1, 0);
+            csi(b'>', 3, 0);
+            assert_eq!(flags(), 3);
+            csi(b'=', 8, 2);
+            assert_eq!(flags(), 11);
+            csi(b'=', 1, 3);
+            assert_eq!(flags(), 10);
+            csi(b'<', 1, 0);
+            assert_eq!(flags(), 1);
+            for _ in 0..STACK_MAX + 4 {
+                csi(b'>', 1, 0);
+            }
+            assert_eq!(unsafe { state() }.kbd.len(), STACK_MAX);
+            csi(b'<', 99, 0);
+            assert_eq!(flags(), 0);
+        });
+    }
+}
@@ -0,0 +1,790 @@
+//! The kitty graphics protocol, sent in APC strings:
+//!
+//! ESC '_' 'G' <key> = <value> [, <key> = <value>]... [; <payload>] ST
+//!
+//! Images are transmitted in the payload, as base64, or read from a file or
+//! shared memory object named by it, and kept in a [`Store`] under their id.
+//! Placing an image shows it on the grid like a sixel image, so it scrolls
+//! with the text and is cleared along with it.
+//!
+//! Placements are always drawn over the text, whatever their z-index, and
+//! Unicode placeholders and animation frames are not supported.
+
+use std::{
+    collections::BTreeMap,
+    ffi::{c_int, CString},
+    fs::{self, OpenOptions},
+    io::{self, Read, Seek, SeekFrom},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    rc::Rc,
+};
+
+use flate2::read::ZlibDecoder;
+
+use crate::{
+    base64dec,
+    bindgen::term,
+    graphics::{self, Image, Placement},
+    state, tmoveto, tnewline, ttywrite,
+};
+
+/// Largest width and height of an image.
+const IMAGE_MAX: usize = 10000;
+/// Largest number of bytes of all stored images together, after which the
+/// oldest ones are dropped.
+const STORE_MAX: usize = 320 << 20;
+
+/// The images transmitted to the terminal, by id.
+pub(crate) struct Store {
+    images: BTreeMap<u32, Stored>,
+    /// the id of the last image transmitted with each image number
+    numbers: BTreeMap<u32, u32>,
+    /// the first command and the payload so far of a chunked transmission, or
+    /// the error to reply with once it ends if it is dropped
+    chunk: Option<(Command, Result<Vec<u8>, Error>)>,
+    /// the number of images transmitted so far
+    count: u64,
+}
+
+struct Stored {
+    image: Rc<Image>,
+    /// the value of `Store::count` when the image was transmitted
+    age: u64,
+}
+
+impl Store {
+    pub(crate) const fn new() -> Self {
+        Self {
+            images: BTreeMap::new(),
+            numbers: BTreeMap::new(),
+            chunk: None,
+            count: 0,
+        }
+    }
+
+    /// Store `image` under `id`, dropping the oldest images if they take up
+    /// too much memory.
+    fn insert(&mut self, id: u32, image: Image) -> Rc<Image> {
+        let image = Rc::new(image);
+        self.count += 1;
+        self.images
+            .insert(id, Stored { image: image.clone(), age: self.count });
+        let size = |s: &Stored| s.image.pixels.len() * 4;
+        let mut total: usize = self.images.values().map(size).sum();
+        while total > STORE_MAX && self.images.len() > 1 {
+            let (&oldest, s) =
+                self.images.iter().min_by_key(|(_, s)| s.age).unwrap();
+            total -= size(s);
+            self.images.remove(&oldest);
+        }
+        image
+    }
+
+    /// Return an id that no stored image has.
+    fn unused(&self) -> u32 {
+        match self.images.last_key_value() {
+            Some((&id, _)) if id < u32::MAX => id + 1,
+            Some(_) => (1..).find(|i| !self.images.contains_key(i)).unwrap(),
+            None => 1,
+        }
+    }
+}
+
+/// The keys of a graphics command, with their defaults.
+#[derive(Clone)]
+struct Command {
+    /// `a`: transmit (`t`), transmit and place (`T`), query (`q`), place
+    /// (`p`) or delete (`d`)
+    action: u8,
+    /// `q`: 1 to suppress OK replies, 2 to suppress errors too
+    quiet: u32,
+    /// `f`: 24 for RGB, 32 for RGBA or 100 for PNG
+    format: u32,
+    /// `t`: direct (`d`), file (`f`), temporary file (`t`) or shared memory
+    /// (`s`)
+    medium: u8,
+    /// `s` and `v`: the size of the image in pixels, for RGB and RGBA
+    width: usize,
+    height: usize,
+    /// `S` and `O`: the number of bytes to read from a file and where to
+    /// start
+    size: usize,
+    offset: usize,
+    /// `i`, `I` and `p`: the image id, image number and placement id
+    id: u32,
+    number: u32,
+    placement: u32,
+    /// `m`: whether more chunks of the payload follow
+    more: bool,
+    /// `o`: `z` if the data is compressed with zlib
+    compression: u8,
+    /// `x`, `y`, `w` and `h`: the part of the image to show
+    src: [usize; 4],
+    /// `X` and `Y`: the offset of the image in its first cell, in pixels
+    cell_x: usize,
+    cell_y: usize,
+    /// `c` and `r`: the number of columns and rows to scale the image to
+    cols: c_int,
+    rows: c_int,
+    /// `C`: 1 to leave the cursor where it is after placing an image
+    stay: bool,
+    /// `z`: the z-index of the placement
+    z: i32,
+    /// `d`: what to delete
+    delete: u8,
+}
+
+impl Default for Command {
+    fn default() -> Self {
+        Self {
+            action: b't',
+            quiet: 0,
+            format: 32,
+            medium: b'd',
+            width: 0,
+            height: 0,
+            size: 0,
+            offset: 0,
+            id: 0,
+            number: 0,
+            placement: 0,
+            more: false,
+            compression: 0,
+            src: [0; 4],
+            cell_x: 0,
+            cell_y: 0,
+            cols: 0,
+            rows: 0,
+            stay: false,
+            z: 0,
+            delete: b'a',
+        }
+    }
+}
+
+impl Command {
+    /// Parse the keys in `s`. Unknown keys and bad values are ignored.
+    fn parse(s: &[u8]) -> Self {
+        let mut cmd = Self::default();
+        for kv in s.split(|&c| c == b',') {
+            let [k, b'=', v @ ..] = kv else {
+                continue;
+            };
+            let v = std::str::from_utf8(v).unwrap_or("");
+            let n = v.parse::<u32>().ok();
+            let c = v.as_bytes().first().copied();
+            match (k, n, c) {
+                (b'a', _, Some(c)) => cmd.action = c,
+                (b'q', Some(n), _) => cmd.quiet = n,
+                (b'f', Some(n), _) => cmd.format = n,
+                (b't', _, Some(c)) => cmd.medium = c,
+                (b's', Some(n), _) => cmd.width = n as usize,
+                (b'v', Some(n), _) => cmd.height = n as usize,
+                (b'S', Some(n), _) => cmd.size = n as usize,
+                (b'O', Some(n), _) => cmd.offset = n as usize,
+                (b'i', Some(n), _) => cmd.id = n,
+                (b'I', Some(n), _) => cmd.number = n,
+                (b'p', Some(n), _) => cmd.placement = n,
+                (b'm', Some(n), _) => cmd.more = n == 1,
+                (b'o', _, Some(c)) => cmd.compression = c,
+                (b'x', Some(n), _) => cmd.src[0] = n as usize,
+                (b'y', Some(n), _) => cmd.src[1] = n as usize,
+                (b'w', Some(n), _) => cmd.src[2] = n as usize,
+                (b'h', Some(n), _) => cmd.src[3] = n as usize,
+                (b'X', Some(n), _) => cmd.cell_x = n as usize,
+                (b'Y', Some(n), _) => cmd.cell_y = n as usize,
+                (b'c', Some(n), _) => {
+                    cmd.cols = n.min(i16::MAX as u32) as c_int
+                }
+                (b'r', Some(n), _) => {
+                    cmd.rows = n.min(i16::MAX as u32) as c_int
+                }
+                (b'C', Some(n), _) => cmd.stay = n == 1,
+                (b'z', ..) => cmd.z = v.parse().unwrap_or(0),
+                (b'd', _, Some(c)) => cmd.delete = c,
+                _ => {}
+            }
+        }
+        cmd
+    }
+}
+
+/// An error reported to the program, as the code and message kitty uses.
+type Error = (&'static str, &'static str);
+
+/// Handle the graphics command in the APC string `data`, which starts with
+/// the `G`.
+pub(crate) fn handle(data: &[u8]) {
+    let data = &data[1..];
+    let (keys, payload) = match data.iter().position(|&c| c == b';') {
+        Some(i) => (&data[..i], &data[i + 1..]),
+        None => (data, &[][..]),
+    };
+    let cmd = Command::parse(keys);
+
+    // the keys of a chunked transmission come with its first chunk
+    let chunk = unsafe { state() }.kitty.chunk.take();
+    let (cmd, payload) = match chunk {
+        Some((first, buf)) => {
+            // a transmission larger than the store is dropped, along with the
+            // rest of its chunks
+            let buf = buf.and_then(|mut buf| {
+                if buf.len() + payload.len() > STORE_MAX {
+                    return Err(("EFBIG", "image too large"));
+                }
+                buf.extend_from_slice(payload);
+                Ok(buf)
+            });
+            if cmd.more {
+                unsafe { state() }.kitty.chunk = Some((first, buf));
+                return;
+            }
+            match buf {
+                Ok(buf) => (first, buf),
+                Err(e) => return reply(&first, Err(e)),
+            }
+        }
+        None if cmd.more && matches!(cmd.action, b't' | b'T' | b'q') => {
+            unsafe { state() }.kitty.chunk = Some((cmd, Ok(payload.to_vec())));
+            return;
+        }
+        None => (cmd, payload.to_vec()),
+    };
+
+    let res = match cmd.action {
+        b't' | b'T' | b'q' => transmit(&cmd, &payload),
+        b'p' => place(&cmd),
+        b'd' => {
+            delete(&cmd);
+            return;
+        }
+        _ => Err(("EINVAL", "unknown action")),
+    };
+    reply(&cmd, res);
+}
+
+/// Send the result of `cmd` to the program, unless it asked to be quiet or
+/// did not give an image id or number to reply with.
+fn reply(cmd: &Command, res: Result<u32, Error>) {
+    if cmd.id == 0 && cmd.number == 0 {
+        return;
+    }
+    let id = *res.as_ref().unwrap_or(&cmd.id);
+    let msg = match res {
+        Ok(_) if cmd.quiet >= 1 => return,
+        Err(_) if cmd.quiet >= 2 => return,
+        Ok(_) => "OK".to_owned(),
+        Err((code, msg)) => format!("{code}:{msg}"),
+    };
+    let mut keys = format!("i={id}");
+    if cmd.number != 0 {
+        keys.push_str(&format!(",I={}", cmd.number));
+    }
+    if cmd.placement != 0 {
+        keys.push_str(&format!(",p={}", cmd.placement));
+    }
+    ttywrite(format!("\x1b_G{keys};{msg}\x1b\\").as_bytes(), false);
+}
+
+/// Load the image sent with `cmd`, store it unless it is only a query, and
+/// show it if asked to. Return the id of the image.
+fn transmit(cmd: &Command, payload: &[u8]) -> Result<u32, Error> {
+    let image = load(cmd, payload)?;
+    if cmd.action == b'q' {
+        return Ok(cmd.id);
+    }
+    let id = match (cmd.id, cmd.number) {
+        (0, 0) => 0,
+        (0, number) => {
+            let store = &mut unsafe { state() }.kitty;
+            let id = store.unused();
+            store.numbers.insert(number, id);
+            id
+        }
+        (id, _) => id,
+    };
+    // a new image replaces the old one with the same id, and its placements
+    if id != 0 {
+        graphics::delete(|s| s.placement.id == id);
+    }
+    // an image without an id can only be shown right away
+    let image = match id {
+        0 => Rc::new(image),
+        id => unsafe { state() }.kitty.insert(id, image),
+    };
+    if cmd.action == b'T' {
+        show(cmd, id, &image)?;
+    }
+    Ok(id)
+}
+
+/// Show the stored image given by `cmd`. Return its id.
+fn place(cmd: &Command) -> Result<u32, Error> {
+    let store = &unsafe { state() }.kitty;
+    let id = match (cmd.id, cmd.number) {
+        (0, 0) => return Err(("EINVAL", "no image id or number")),
+        (0, number) => store.numbers.get(&number).copied().unwrap_or(0),
+        (id, _) => id,
+    };
+    let Some(image) = store.images.get(&id).map(|s| s.image.clone()) else {
+        return Err(("ENOENT", "no such image"));
+    };
+    // placing an image again with the same placement id moves it
+    if cmd.placement != 0 {
+        graphics::delete(|s| {
+            s.placement.id == id && s.placement.placement == cmd.placement
+        });
+    }
+    show(cmd, id, &image)?;
+    Ok(id)
+}
+
+/// Show the part of `image` given by `cmd` at the cursor, scrolling the screen
+/// if it does not fit below it.
+fn show(cmd: &Command, id: u32, image: &Rc<Image>) -> Result<(), Error> {
+    let image = fit(cmd, image)?;
+    let placement = Placement::new(id, cmd.placement, cmd.z);
+    let rows = graphics::rows(&image);
+    let cols = graphics::cols(&image);
+    unsafe {
+        let (x, y) = (term.c.x, term.c.y);
+        for row in 0..rows {
+            if cmd.stay && y + row >= term.row {
+                break;
+            }
+            if row > 0 && !cmd.stay {
+                tnewline(false);
+            }
+            let y = if cmd.stay { y + row } else { term.c.y };
+            graphics::attach(&image, placement, x, y, row);
+        }
+        if cmd.stay {
+            tmoveto(x, y);
+        } else {
+            tmoveto(x + cols, term.c.y);
+        }
+    }
+    Ok(())
+}
+
+/// Return the part of `image` selected by `cmd`, scaled to the number of
+/// columns and rows it asks for and moved by the offset in its first cell.
+/// Room is made for it among the images shown, unless it is shown already.
+fn fit(cmd: &Command, image: &Rc<Image>) -> Result<Rc<Image>, Error> {
+    let full: Error = ("ENOSPC", "too many images shown");
+    let [sx, sy, sw, sh] = cmd.src;
+    let sx = sx.min(image.width);
+    let sy = sy.min(image.height);
+    let sw = if sw == 0 { image.width - sx } else { sw.min(image.width - sx) };
+    let sh =
+        if sh == 0 { image.height - sy } else { sh.min(image.height - sy) };
+
+    let (cw, ch) = graphics::cellsize();
+    let (ox, oy) = (cmd.cell_x.min(cw - 1), cmd.cell_y.min(ch - 1));
+    let (w, h) = match (cmd.cols as usize, cmd.rows as usize) {
+        (0, 0) => (sw, sh),
+        (c, 0) => (c * cw - ox, (c * cw - ox) * sh / sw.max(1)),
+        (0, r) => ((r * ch - oy) * sw / sh.max(1), r * ch - oy),
+        (c, r) => (c * cw - ox, r * ch - oy),
+    };
+    // the scaled image can't take more memory than all images shown
+    let w = w.min(IMAGE_MAX);
+    let h = h
+        .min(IMAGE_MAX)
+        .min((graphics::SHOWN_MAX / 4 / (w + ox).max(1)).saturating_sub(oy));
+    if (sx, sy, sw, sh) == (0, 0, image.width, image.height)
+        && (w, h) == (sw, sh)
+        && (ox, oy) == (0, 0)
+    {
+        if !graphics::shown(|s| Rc::ptr_eq(&s.image, image))
+            && !graphics::reserve(image.size())
+        {
+            return Err(full);
+        }
+        return Ok(image.clone());
+    }
+
+    let (width, height) = (w + ox, h + oy);
+    if !graphics::reserve(width * height * 4) {
+        return Err(full);
+    }
+    let mut pixels = vec![0; width * height];
+    if sw > 0 && sh > 0 {
+        for y in 0..h {
+            let src = (sy + y * sh / h) * image.width + sx;
+            let dst = (oy + y) * width + ox;
+            for x in 0..w {
+                pixels[dst + x] = image.pixels[src + x * sw / w];
+            }
+        }
+    }
+    Ok(Rc::new(Image { width, height, pixels }))
+}
+
+/// Stop showing the placements selected by `cmd`. The upper case variants of
+/// the `d` key delete the stored images too, once they are not shown anymore.
+fn delete(cmd: &Command) {
+    let (cx, cy) = unsafe { (term.c.x, term.c.y) };
+    // the cell given by the x and y keys, which start at 1
+    let (x, y) = (cmd.src[0] as c_int - 1, cmd.src[1] as c_int - 1);
+    let at = |x, y| graphics::placements(x, y);
+    let placements: Vec<Placement> = match cmd.delete.to_ascii_lowercase() {
+        b'c' => at(cx, cy),
+        b'p' => at(x, y),
+        b'q' => at(x, y).into_iter().filter(|p| p.z == cmd.z).collect(),
+        b'y' => unsafe { (0..term.col).flat_map(|x| at(x, y)).collect() },
+        _ => Vec::new(),
+    };
+
+    let id = match (cmd.id, cmd.number) {
+        (0, number) if cmd.delete.eq_ignore_ascii_case(&b'n') => {
+            let numbers = &unsafe { state() }.kitty.numbers;
+            numbers.get(&number).copied().unwrap_or(0)
+        }
+        (id, _) => id,
+    };
+    let mut deleted = Vec::new();
+    graphics::delete(|s| {
+        let p = &s.placement;
+        let hit = match cmd.delete.to_ascii_lowercase() {
+            b'a' => true,
+            b'i' | b'n' => {
+                id != 0
+                    && p.id == id
+                    && (cmd.placement == 0 || p.placement == cmd.placement)
+            }
+            b'c' | b'p' | b'q' | b'y' => placements.contains(p),
+            b'x' => s.x <= x && x < s.x + s.cells.len() as c_int,
+            b'z' => p.z == cmd.z,
+            b'r' => (cmd.src[0] as u32..=cmd.src[1] as u32).contains(&p.id),
+            _ => false,
+        };
+        if hit {
+            deleted.push(p.id);
+        }
+        hit
+    });
+
+    if cmd.delete.is_ascii_uppercase() {
+        if matches!(cmd.delete, b'I' | b'N') && id != 0 {
+            deleted.push(id);
+        }
+        for id in deleted {
+            if !graphics::shown(|s| s.placement.id == id) {
+                let store = &mut unsafe { state() }.kitty;
+                store.images.remove(&id);
+                store.numbers.retain(|_, &mut i| i != id);
+            }
+        }
+    }
+}
+
+/// Read the data of the image sent with `cmd` and decode it.
+fn load(cmd: &Command, payload: &[u8]) -> Result<Image, Error> {
+    let payload = base64dec(payload);
+    let mut data = match cmd.medium {
+        b'd' => payload,
+        b'f' | b't' => readfile(cmd, &payload)?,
+        b's' => readshm(cmd, &payload)?,
+        _ => return Err(("EINVAL", "unknown transmission medium")),
+    };
+    if cmd.compression == b'z' {
+        let mut buf = Vec::new();
+        ZlibDecoder::new(&data[..])
+            .take((IMAGE_MAX * IMAGE_MAX * 4) as u64)
+            .read_to_end(&mut buf)
+            .map_err(|_| ("EINVAL", "bad zlib data"))?;
+        data = buf;
+    }
+
+    match cmd.format {
+        24 | 32 => {
+            let (w, h) = (cmd.width, cmd.height);
+            if w == 0 || h == 0 || w > IMAGE_MAX || h > IMAGE_MAX {
+                return Err(("EINVAL", "bad image size"));
+            }
+            let bpp = cmd.format as usize / 8;
+            if data.len() < w * h * bpp {
+                return Err(("ENODATA", "not enough image data"));
+            }
+            let pixels = data
+                .chunks_exact(bpp)
+                .take(w * h)
+                .map(|p| {
+                    let a = if bpp == 4 { p[3] } else { 0xff };
+                    u32::from_be_bytes([a, p[0], p[1], p[2]])
+                })
+                .collect();
+            Ok(Image { width: w, height: h, pixels })
+        }
+        100 => png(&data),
+        _ => Err(("EINVAL", "unknown format")),
+    }
+}
+
+/// Decode the PNG image in `data`.
+fn png(data: &[u8]) -> Result<Image, Error> {
+    let err = |_| ("EBADPNG", "bad PNG data");
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(err)?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf).map_err(err)?;
+    let (width, height) = (info.width as usize, info.height as usize);
+    if width > IMAGE_MAX || height > IMAGE_MAX {
+        return Err(("EINVAL", "image too large"));
+    }
+    let bpp = info.color_type.samples();
+    let pixels = buf[..info.buffer_size()]
+        .chunks_exact(bpp)
+        .map(|p| {
+            let (rgb, a) = match *p {
+                [v] => ([v; 3], 0xff),
+                [v, a] => ([v; 3], a),
+                [r, g, b] => ([r, g, b], 0xff),
+                [r, g, b, a, ..] => ([r, g, b], a),
+                _ => unreachable!(),
+            };
+            u32::from_be_bytes([a, rgb[0], rgb[1], rgb[2]])
+        })
+        .collect();
+    Ok(Image { width, height, pixels })
+}
+
+/// Read the data of an image from the regular file named by `path`, deleting
+/// it afterwards if it is a temporary file.
+fn readfile(cmd: &Command, path: &[u8]) -> Result<Vec<u8>, Error> {
+    let path = std::str::from_utf8(path).map_err(|_| ("EINVAL", "bad path"))?;
+    let path = Path::new(path)
+        .canonicalize()
+        .map_err(|_| ("EBADF", "couldn't read the file"))?;
+    // like kitty, refuse the files of the kernel and devices, which could
+    // block or never end
+    if ["/proc", "/sys", "/dev"]
+        .iter()
+        .any(|dir| path.starts_with(dir))
+        || !fs::metadata(&path).is_ok_and(|m| m.is_file())
+    {
+        return Err(("EINVAL", "not a regular file"));
+    }
+    // only temporary files that are clearly meant for us are deleted
+    let temp = std::env::temp_dir().canonicalize();
+    if cmd.medium == b't'
+        && !(temp.is_ok_and(|temp| path.starts_with(temp))
+            && path.to_string_lossy().contains("tty-graphics-protocol"))
+    {
+        return Err(("EPERM", "not a temporary file"));
+    }
+    let res = (|| {
+        // the file could have been replaced since it was checked, so don't
+        // wait for a FIFO to be opened and check it again
+        let mut f = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_NOFOLLOW)
+            .open(&path)?;
+        if !f.metadata()?.is_file() {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        f.seek(SeekFrom::Start(cmd.offset as u64))?;
+        let mut buf = Vec::new();
+        let size = match cmd.size {
+            0 => STORE_MAX,
+            n => n.min(STORE_MAX),
+        };
+        f.take(size as u64).read_to_end(&mut buf)?;
+        Ok::<_, io::Error>(buf)
+    })();
+    if cmd.medium == b't' {
+        let _ = fs::remove_file(&path);
+    }
+    res.map_err(|_| ("EBADF", "couldn't read the file"))
+}
+
+/// Read the data of an image from the POSIX shared memory object named by
+/// `name`, which is removed afterwards.
+fn readshm(cmd: &Command, name: &[u8]) -> Result<Vec<u8>, Error> {
+    let name = CString::new(name).map_err(|_| ("EINVAL", "bad name"))?;
+    unsafe {
+        let fd = libc::shm_open(name.as_ptr(), libc::O_RDONLY, 0);
+        if fd < 0 {
+            return Err(("EBADF", "couldn't open the shared memory"));
+        }
+        let mut st: libc::stat = std::mem::zeroed();
+        let len =
+            if libc::fstat(fd, &mut st) == 0 { st.st_size as usize } else { 0 };
+        let end = match cmd.size {
+            0 => len,
+            n => (cmd.offset + n).min(len),
+        };
+        let mut buf = Vec::new();
+        if cmd.offset < end {
+            let p = libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            );
+            if p != libc::MAP_FAILED {
+                let data = std::slice::from_raw_parts(p.cast::<u8>(), len);
+                buf.extend_from_slice(&data[cmd.offset..end]);
+                libc::munmap(p, len);
+            }
+        }
+        libc::close(fd);
+        libc::shm_unlink(name.as_ptr());
+        if buf.is_empty() {
+            return Err(("EBADF", "couldn't read the shared memory"));
+        }
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Terminal;
+
+    #[test]
+    fn parse() {
+        let cmd = Command::parse(b"a=T,f=24,s=10,v=20,i=7,m=1,c=99999,z=-1,x");
+        assert_eq!(cmd.action, b'T');
+        assert_eq!(cmd.format, 24);
+        assert_eq!((cmd.width, cmd.height), (10, 20));
+        assert_eq!(cmd.id, 7);
+        assert!(cmd.more);
+        assert_eq!(cmd.cols, i16::MAX as c_int);
+        assert_eq!(cmd.z, -1);
+
+        // bad values are ignored
+        let cmd = Command::parse(b"a=,f=x,i=-1,q=2");
+        assert_eq!(cmd.action, b't');
+        assert_eq!(cmd.format, 32);
+        assert_eq!(cmd.id, 0);
+        assert_eq!(cmd.quiet, 2);
+    }
+
+    fn image(width: usize, height: usize) -> Rc<Image> {
+        let pixels = (0..width * height).map(|i| i as u32).collect();
+        Rc::new(Image { width, height, pixels })
+    }
+
+    /// Fit `image` with a terminal to make room in.
+    fn fit(cmd: &Command, image: &Rc<Image>) -> Rc<Image> {
+        Terminal::new(10, 5)
+            .with(|| super::fit(cmd, image))
+            .unwrap()
+    }
+
+    #[test]
+    fn fit_unchanged() {
+        let img = image(4, 3);
+        let cmd = Command::default();
+        assert!(Rc::ptr_eq(&fit(&cmd, &img), &img));
+    }
+
+    #[test]
+    fn fit_source() {
+        let img = image(4, 3);
+        let cmd = Command { src: [1, 1, 2, 0], ..Command::default() };
+        let fitted = fit(&cmd, &img);
+        assert_eq!((fitted.width, fitted.height), (2, 2));
+        assert_eq!(fitted.pixels, [5, 6, 9, 10]);
+
+        // the part is cut off at the edges of the image
+        let cmd = Command { src: [3, 9, 5, 5], ..Command::default() };
+        let fitted = fit(&cmd, &img);
+        assert_eq!((fitted.width, fitted.height), (1, 0));
+    }
+
+    #[test]
+    fn fit_scaled() {
+        let img = image(2, 1);
+        // the height follows the width given in columns
+        let cmd = Command { cols: 2, ..Command::default() };
+        let fitted = fit(&cmd, &img);
+        assert_eq!((fitted.width, fitted.height), (20, 10));
+        assert_eq!(fitted.pixels[9], 0);
+        assert_eq!(fitted.pixels[10], 1);
+
+        let cmd = Command { cols: 1, rows: 1, ..Command::default() };
+        let fitted = fit(&cmd, &img);
+        assert_eq!((fitted.width, fitted.height), (10, 20));
+
+        // the offset in the first cell is transparent
+        let cmd = Command { cell_x: 1, cell_y: 2, ..Command::default() };
+        let fitted = fit(&cmd, &img);
+        assert_eq!((fitted.width, fitted.height), (3, 3));
+        assert_eq!(fitted.pixels, [0, 0, 0, 0, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn transmit_and_place() {
+        let mut t = Terminal::new(10, 5);
+        // a red 1x1 RGB image
+        t.write(b"\x1b_Ga=T,f=24,s=1,v=1,i=3;/wAA\x1b\\");
+        assert_eq!(t.take_output(), b"\x1b_Gi=3;OK\x1b\\");
+        let image = t.image_at(0, 0).unwrap();
+        assert_eq!(image.pixels, [0xffff0000]);
+        assert_eq!(t.cursor(), (1, 0));
+
+        // in chunks, quietly
+        t.write(b"\r\n\x1b_Ga=T,f=24,s=1,v=1,i=4,q=1,m=1;/w\x1b\\");
+        t.write(b"\x1b_Gm=0;AA\x1b\\");
+        assert!(t.take_output().is_empty());
+        assert!(t.image_at(0, 1).is_some());
+
+        t.write(b"\x1b_Ga=p,i=9\x1b\\");
+        assert_eq!(t.take_output(), b"\x1b_Gi=9;ENOENT:no such image\x1b\\");
+        t.write(b"\x1b_Ga=d,d=A\x1b\\");
+        assert!(t.image_at(0, 0).is_none());
+    }
+
+    #[test]
+    fn shown_max() {
+        let mut t = Terminal::new(10, 5);
+        t.write(b"\x1b_Ga=t,f=24,s=1,v=1,i=1,q=1;/wAA\x1b\\");
+        // each placement is a copy scaled to 8000x2000 pixels, which scrolls
+        // into the history, and only five of them fit
+        for _ in 0..6 {
+            t.write(b"\x1b_Ga=p,i=1,q=1,c=800,r=100\x1b\\");
+        }
+        assert!(t.take_output().is_empty());
+        assert_eq!(t.with(graphics::used), 5 * 8000 * 2000 * 4);
+        assert!(t.image_at(9, 4).is_some());
+    }
+
+    #[test]
+    fn files() {
+        let mut t = Terminal::new(10, 5);
+        // devices and other files that aren't regular are refused
+        t.write(b"\x1b_Ga=t,t=f,f=24,s=1,v=1,i=1;L2Rldi96ZXJv\x1b\\");
+        assert_eq!(
+            t.take_output(),
+            b"\x1b_Gi=1;EINVAL:not a regular file\x1b\\"
+        );
+
+        let dir = std::env::temp_dir();
+        let path = dir.join("rt-tests-kitty-not-temporary");
+        fs::write(&path, [0xff, 0, 0]).unwrap();
+        let b64 = crate::base64enc(path.to_str().unwrap().as_bytes());
+        let cmd = |medium| {
+            let mut s =
+                format!("\x1b_Ga=t,t={medium},f=24,s=1,v=1,i=2;").into_bytes();
+            s.extend_from_slice(b64.as_bytes());
+            s.extend_from_slice(b"\x1b\\");
+            s
+        };
+        t.write(&cmd('t'));
+        assert_eq!(
+            t.take_output(),
+            b"\x1b_Gi=2;EPERM:not a temporary file\x1b\\"
+        );
+        assert!(path.exists());
+        t.write(&cmd('f'));
+        assert_eq!(t.take_output(), b"\x1b_Gi=2;OK\x1b\\");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,8 +1,13 @@
 use std::{
     cmp,
-    ffi::{c_char, c_int, c_uchar, c_void, CStr},
+    collections::BTreeMap,
+    ffi::{c_char, c_int, c_uchar, c_uint, c_void, CStr, CString},
     mem::MaybeUninit,
+    os::unix::{ffi::OsStringExt, process::CommandExt},
+    path::PathBuf,
+    process::Command,
     ptr::{null, null_mut},
+    rc::Rc,
 };
 
 use libc::{
@@ -11,23 +16,48 @@ use libc::{
     FD_ISSET, FD_SET, FD_ZERO, O_CREAT, O_RDWR, O_WRONLY, SIGCHLD, TIOCSCTTY,
 };
 use x11::xlib::{
-    False, GCGraphicsExposures, PropModeReplace, XA_CARDINAL, XA_STRING,
+    False, GCGraphicsExposures, PropModeReplace, True, XA_CARDINAL, XA_STRING,
 };
 
 use bindgen::{
-    blinktimeout, borderpx, cmdfd, colorname, dc, defaultbg, defaultfg, font,
-    handler, iofd, maxlatency, minlatency, mousebg, mousefg, mouseshape,
-    opt_cmd, opt_embed, opt_font, opt_io, opt_line, sel, shell, tabspaces,
-    term, usedfont, win, xsel, xw, ConfigureNotify, FcInit, GlyphFontSpec,
-    Glyph_, Line, MapNotify, TCursor, Term, XConnectionNumber, XFilterEvent,
-    XFlush, XGCValues, XNextEvent, XPending,
+    allowaltscreen, blinktimeout, borderpx, cmdfd, colorname, dc, defaultbg,
+    defaultcs, defaultfg, font, handler, histsize, iofd, maxlatency,
+    minlatency, mousebg, mousefg, mouseshape, opt_cmd, opt_embed, opt_font,
+    opt_io, opt_line, sel, shell, tabspaces, term, usedfont, vtiden, win,
+    worddelimiters, xsel, xw, Arg, ButtonPress, ButtonRelease, ConfigureNotify,
+    FcInit, FocusIn, FocusOut, GlyphFontSpec, Glyph_, KeyPress, KeyRelease,
+    Line, MapNotify, MotionNotify, Rune, SelectionNotify, TCursor, Term,
+    XConnectionNumber, XFilterEvent, XFlush, XGCValues, XNextEvent, XPending,
 };
-use win::{MODE_BLINK, MODE_NUMLOCK};
+use config::Policy;
+use graphics::{Image, Placement, Strip};
+use parser::{Csi, Dcs, Handler, Parser, ESC_ARG_SIZ};
+use win::{
+    MODE_8BIT, MODE_APPCURSOR, MODE_APPKEYPAD, MODE_BLINK, MODE_BRCKTPASTE,
+    MODE_CURSORBLINK, MODE_CURSOROFF, MODE_FOCUS, MODE_FOCUSED, MODE_HIDE,
+    MODE_KBDLOCK, MODE_MOUSE, MODE_MOUSEBTN, MODE_MOUSEMANY, MODE_MOUSEMOTION,
+    MODE_MOUSESGR, MODE_MOUSEX10, MODE_NUMLOCK, MODE_REVERSE, MODE_VBELL,
+    MODE_VISIBLE,
+};
+use x::Clip;
 
 pub mod bindgen;
+mod config;
+mod copymode;
+pub mod export;
+pub mod graphics;
+mod keyboard;
+mod kitty;
+pub mod parser;
+mod search;
+pub mod settings;
+mod sixel;
+pub mod terminal;
+mod urls;
 pub mod win;
 pub mod x;
 
+pub use terminal::Terminal;
 pub use x::xsetenv;
 
 // enum glyph_attribute
@@ -44,6 +74,28 @@ pub const ATTR_WRAP: c_int = 1 << 8;
 pub const ATTR_WIDE: c_int = 1 << 9;
 pub const ATTR_WDUMMY: c_int = 1 << 10;
 pub const ATTR_BOLD_FAINT: c_int = ATTR_BOLD | ATTR_FAINT;
+/// the style of `ATTR_UNDERLINE`, one of the `USTYLE_*` values in the bits of
+/// the mode above the attributes
+pub const ATTR_USTYLE: c_int = 0b111 << 11;
+
+pub const USTYLE_SINGLE: c_int = 0;
+pub const USTYLE_DOUBLE: c_int = 1 << 11;
+pub const USTYLE_CURLY: c_int = 2 << 11;
+pub const USTYLE_DOTTED: c_int = 3 << 11;
+pub const USTYLE_DASHED: c_int = 4 << 11;
+
+// DEC line size attributes, set with ESC # 3/4/5/6
+pub const LATTR_SINGLE: u8 = 0;
+pub const LATTR_DWIDTH: u8 = 1;
+pub const LATTR_DTOP: u8 = 2;
+pub const LATTR_DBOTTOM: u8 = 3;
+
+// semantic line marks, set with OSC 133 A/B/C/D where a prompt, the command
+// typed at it, its output and the end of the command start
+pub const SEM_PROMPT: u8 = 1 << 0;
+pub const SEM_INPUT: u8 = 1 << 1;
+pub const SEM_OUTPUT: u8 = 1 << 2;
+pub const SEM_END: u8 = 1 << 3;
 
 // enum selection_mode
 pub const SEL_IDLE: c_int = 0;
@@ -62,6 +114,12 @@ pub const MODE_CRLF: c_int = 1 << 3;
 pub const MODE_ECHO: c_int = 1 << 4;
 pub const MODE_PRINT: c_int = 1 << 5;
 pub const MODE_UTF8: c_int = 1 << 6;
+/// DECSDM -- sixel display mode, where images are shown at the top left corner
+/// instead of scrolling with the text
+pub const MODE_SIXELDISPLAY: c_int = 1 << 7;
+/// synchronized output, where the screen is not drawn until the program has
+/// finished updating it
+pub const MODE_SYNC: c_int = 1 << 8;
 
 // enum cursor_movement
 // TODO these are definitely used like rust enums
@@ -82,6 +140,200 @@ pub const CS_MULTI: c_int = 4;
 pub const CS_GER: c_int = 5;
 pub const CS_FI: c_int = 6;
 
+// enum selection_snap
+pub const SNAP_WORD: c_int = 1;
+pub const SNAP_LINE: c_int = 2;
+
+// Arbitrary sizes
+pub const UTF_INVALID: Rune = 0xFFFD;
+pub const UTF_SIZ: usize = 4;
+/// the most combining marks kept on one glyph
+const MARKS_MAX: usize = 8;
+/// the most distinct sequences of combining marks kept by a terminal, after
+/// which marks making new ones are dropped
+const MARKSEQ_MAX: usize = 1 << 16;
+/// the most distinct hyperlinks kept by a terminal, after which new ones are
+/// dropped
+const LINKS_MAX: usize = 1 << 16;
+/// the longest URI of a hyperlink
+const URI_MAX: usize = 2048;
+
+/// The parts of the terminal state that only the Rust side knows about, kept
+/// alongside the `term` global.
+pub(crate) struct TermState {
+    /// the parser for the control sequences written to the terminal
+    parser: Parser,
+    /// cursors saved by `tcursor` for the primary and alternate screens
+    cursors: [TCursor; 2],
+    /// if set, replies to the program are collected here instead of being
+    /// written to the tty
+    output: Option<Vec<u8>>,
+    /// the `LATTR_*` size attributes of the rows of the screen in `term.line`
+    lattr: Vec<u8>,
+    /// the size attributes of the rows of the other screen in `term.alt`
+    altlattr: Vec<u8>,
+    /// the size attributes of the lines in the history ring `term.hist`
+    histlattr: Vec<u8>,
+    /// the `SEM_*` marks of the rows of the screen, the other screen and the
+    /// lines in the history, like the size attributes
+    sem: Vec<u8>,
+    altsem: Vec<u8>,
+    histsem: Vec<u8>,
+    /// the exit status of the last command reported with OSC 133 D
+    status: Option<c_int>,
+    /// when `MODE_SYNC` was set
+    synctime: timespec,
+    /// when the bell last rang with the visual bell on
+    belltime: timespec,
+    /// when the last desktop notification was shown
+    notifytime: timespec,
+    /// the message shown in the bottom row of the window, and since when
+    message: Option<(String, timespec)>,
+    /// the stacks of kitty keyboard protocol flags of the screen and the other
+    /// screen, with the ones in effect last
+    kbd: Vec<c_uint>,
+    altkbd: Vec<c_uint>,
+    /// the modifyOtherKeys level set with XTMODKEYS
+    modkeys: c_int,
+    /// the keycodes of the keys held down, to tell repeats from presses
+    down: Vec<c_uint>,
+    /// the sequences of combining marks on glyphs, where `Glyph_::comb` is one
+    /// more than the index of its sequence
+    marks: Vec<Vec<Rune>>,
+    /// the `comb` values of the sequences in `marks`
+    markids: BTreeMap<Vec<Rune>, u32>,
+    /// the number of new sequences of marks to drop before looking for unused
+    /// ones again, after the last look found few
+    marksgc: usize,
+    /// the URIs of the hyperlinks on glyphs, where `Glyph_::link` is one more
+    /// than the index of its URI
+    links: Vec<Vec<u8>>,
+    /// the `link` values of the hyperlinks in `links`, by their id parameter
+    /// and URI
+    linkids: BTreeMap<(Vec<u8>, Vec<u8>), u32>,
+    /// the number of new hyperlinks to drop before looking for unused ones
+    /// again, like `marksgc`
+    linksgc: usize,
+    /// the `link` value of the hyperlink under the mouse pointer, or 0
+    hover: u32,
+    /// the first and the last cell of the URL detected under the mouse pointer
+    urlhover: Option<(Pos, Pos)>,
+    /// the question shown in the bottom row of the window, if any
+    prompt: Option<Prompt>,
+    /// the search in progress, if any
+    search: Option<search::Search>,
+    /// copy mode, if it is on
+    copy: Option<copymode::Copy>,
+    /// the selection read for an OSC 52 query, until it arrives
+    clipread: Option<Clip>,
+    /// the strips of the images shown on the lines of `term`, by the address
+    /// of the line
+    images: BTreeMap<usize, Vec<graphics::Strip>>,
+    /// the last `graphics::Placement::serial` handed out
+    serial: u32,
+    /// the images transmitted with the kitty graphics protocol
+    kitty: kitty::Store,
+    /// the working directory last reported by the program with OSC 7
+    cwd: Option<PathBuf>,
+    /// the process id of the shell, or 0
+    pid: libc::pid_t,
+}
+
+impl TermState {
+    pub(crate) const fn new() -> Self {
+        const CURSOR: TCursor = TCursor {
+            attr: Glyph_ {
+                u: 0,
+                mode: 0,
+                fg: 0,
+                bg: 0,
+                comb: 0,
+                link: 0,
+                ucolor: 0,
+            },
+            x: 0,
+            y: 0,
+            state: 0,
+        };
+        Self {
+            parser: Parser::new(),
+            cursors: [CURSOR; 2],
+            output: None,
+            lattr: Vec::new(),
+            altlattr: Vec::new(),
+            histlattr: Vec::new(),
+            sem: Vec::new(),
+            altsem: Vec::new(),
+            histsem: Vec::new(),
+            status: None,
+            synctime: timespec { tv_sec: 0, tv_nsec: 0 },
+            belltime: timespec { tv_sec: 0, tv_nsec: 0 },
+            notifytime: timespec { tv_sec: 0, tv_nsec: 0 },
+            message: None,
+            kbd: Vec::new(),
+            altkbd: Vec::new(),
+            modkeys: 0,
+            down: Vec::new(),
+            marks: Vec::new(),
+            markids: BTreeMap::new(),
+            marksgc: 0,
+            links: Vec::new(),
+            linkids: BTreeMap::new(),
+            linksgc: 0,
+            hover: 0,
+            urlhover: None,
+            prompt: None,
+            search: None,
+            copy: None,
+            clipread: None,
+            images: BTreeMap::new(),
+            serial: 0,
+            kitty: kitty::Store::new(),
+            cwd: None,
+            pid: 0,
+        }
+    }
+
+    /// Return the combining marks drawn over the character of `g`.
+    pub(crate) fn marks(&self, g: &Glyph_) -> &[Rune] {
+        match g.comb {
+            0 => &[],
+            i => &self.marks[i as usize - 1],
+        }
+    }
+
+    /// Return the URI of the hyperlink with the `Glyph_::link` value `link`.
+    pub(crate) fn link(&self, link: u32) -> Option<&[u8]> {
+        match link {
+            0 => None,
+            i => Some(&self.links[i as usize - 1]),
+        }
+    }
+}
+
+/// A yes or no question shown in the bottom row of the window, which takes the
+/// next key press as its answer.
+pub(crate) struct Prompt {
+    text: String,
+    answer: Box<dyn FnOnce(bool)>,
+}
+
+static mut STATE: TermState = TermState::new();
+
+/// Return the Rust side of the current terminal state.
+///
+/// # Safety
+///
+/// The reference must not be used anymore once `state` is called again, so it
+/// can't be held across calls to functions that may call `state` themselves.
+pub(crate) unsafe fn state() -> &'static mut TermState {
+    unsafe { &mut *std::ptr::addr_of_mut!(STATE) }
+}
+
+unsafe extern "C" {
+    fn wcwidth(c: libc::wchar_t) -> c_int;
+}
+
 #[macro_export]
 macro_rules! die {
     ($($t:tt)+) => {
@@ -118,96 +370,85 @@ pub fn tnew(col: c_int, row: c_int) {
     unsafe {
         term = Term {
             c: TCursor {
-                attr: Glyph_ { u: 0, mode: 0, fg: defaultfg, bg: defaultfg },
+                attr: Glyph_ {
+                    u: 0,
+                    mode: 0,
+                    fg: defaultfg,
+                    bg: defaultfg,
+                    comb: 0,
+                    link: 0,
+                    ucolor: 0,
+                },
                 x: 0,
                 y: 0,
                 state: 0,
             },
+            histmax: histsize as c_int,
             ..Default::default()
         };
+        let n = term.histmax as usize * size_of::<Line>();
+        term.hist = xmalloc(n).cast();
+        memset(term.hist.cast(), 0, n);
         tresize(col, row);
         treset();
     }
 }
 
 /// Resize the terminal to `col` x `row`.
+///
+/// The primary screen and its history are reflowed, so lines continued with
+/// `ATTR_WRAP` are joined and wrapped again at the new width. Double size
+/// lines, like the alternate screen, are only truncated or padded, as in xterm.
 pub fn tresize(col: c_int, row: c_int) {
     unsafe {
-        let minrow = row.min(term.row);
-        let mincol = col.min(term.col);
-
         if col < 1 || row < 1 {
             eprintln!("tresize: error resizing to {col}x{row}");
             return;
         }
 
-        // NOTE(st) slide screen to keep cursor where we expect it - tscrollup
-        // would work here, but we can optimize to memmove because we're freeing
-        // the earlier lines
-        let mut i = 0;
-        for i_ in 0..=term.c.y - row {
-            libc::free(term.line.offset(i_ as isize).cast());
-            libc::free(term.alt.offset(i_ as isize).cast());
-            i = i_;
+        // work on the primary screen in term.line, whichever one is showing
+        let altscreen = is_set(MODE_ALTSCREEN);
+        if altscreen {
+            (term.line, term.alt) = (term.alt, term.line);
+            let st = state();
+            std::mem::swap(&mut st.lattr, &mut st.altlattr);
+            std::mem::swap(&mut st.sem, &mut st.altsem);
         }
 
-        // ensure that both src and dst are not NULL
-        if i > 0 {
-            libc::memmove(
-                term.line.cast(),
-                term.line.offset(i as isize).cast(),
-                row as usize * size_of::<Line>(),
-            );
-            libc::memmove(
-                term.alt.cast(),
-                term.alt.offset(i as isize).cast(),
-                row as usize * size_of::<Line>(),
-            );
-        }
+        // the cursor of the primary screen is the saved one while the
+        // alternate screen is shown, and a pending wrap puts it after the last
+        // glyph of the line
+        let c = if altscreen { state().cursors[0] } else { term.c };
+        let wrapnext = c.state & CURSOR_WRAPNEXT as i8 != 0;
+        let (cx, cy) = treflow(col, row, Some((c.x + wrapnext as c_int, c.y)));
+        let st = state();
+        let mut lattr = std::mem::take(&mut st.altlattr);
+        let mut sem = std::mem::take(&mut st.altsem);
+        term.alt =
+            tresizescreen(term.alt, &mut lattr, &mut sem, col, row, altscreen);
+        let st = state();
+        (st.altlattr, st.altsem) = (lattr, sem);
 
-        for i_ in i + row..term.row {
-            libc::free(term.line.offset(i_ as isize).cast());
-            libc::free(term.alt.offset(i_ as isize).cast());
+        if altscreen {
+            (term.line, term.alt) = (term.alt, term.line);
+            let st = state();
+            std::mem::swap(&mut st.lattr, &mut st.altlattr);
+            std::mem::swap(&mut st.sem, &mut st.altsem);
+            let c = &mut st.cursors[0];
+            (c.x, c.y) = (cx, cy);
+            c.state &= !CURSOR_WRAPNEXT as i8;
+        } else {
+            term.c.x = cx;
+            term.c.y = cy;
         }
 
-        // resize to new height
-        term.line =
-            xrealloc(term.line.cast(), row as usize * size_of::<Line>()).cast();
-        term.alt =
-            xrealloc(term.alt.cast(), row as usize * size_of::<Line>()).cast();
-        term.dirty = xrealloc(
-            term.dirty.cast(),
-            row as usize * size_of_val(&*term.dirty),
-        )
-        .cast();
+        term.dirty =
+            xrealloc(term.dirty.cast(), row as usize * size_of::<c_int>())
+                .cast();
         term.tabs =
-            xrealloc(term.tabs.cast(), col as usize * size_of_val(&*term.tabs))
+            xrealloc(term.tabs.cast(), col as usize * size_of::<c_int>())
                 .cast();
 
-        // resize each row to new width, zero-pad if needed
-        for i_ in 0..minrow {
-            i = i_;
-            *term.line.offset(i as isize) = xrealloc(
-                term.line.offset(i as isize).cast(),
-                col as usize * size_of::<Glyph_>(),
-            )
-            .cast();
-            *term.alt.offset(i as isize) = xrealloc(
-                term.alt.offset(i as isize).cast(),
-                col as usize * size_of::<Glyph_>(),
-            )
-            .cast();
-        }
-
-        // allocate any new rows
-        for i_ in minrow..row {
-            i = i_;
-            *term.line.offset(i as isize) =
-                xmalloc(col as usize * size_of::<Glyph_>()).cast();
-            *term.alt.offset(i as isize) =
-                xmalloc(col as usize * size_of::<Glyph_>()).cast();
-        }
-
         if col > term.col {
             let mut bp = term.tabs.offset(term.col as isize);
             memset(
@@ -229,7 +470,7 @@ pub fn tresize(col: c_int, row: c_int) {
             // for (bp += tabspaces; bp < term.tabs + col; bp += tabspaces)
             //     *bp = 1;
             bp = bp.offset(tabspaces as isize);
-            while !std::ptr::addr_eq(bp, term.tabs.offset(col as isize)) {
+            while bp < term.tabs.offset(col as isize) {
                 *bp = 1;
                 bp = bp.offset(tabspaces as isize);
             }
@@ -242,19 +483,351 @@ pub fn tresize(col: c_int, row: c_int) {
         tsetscroll(0, row - 1);
         // make use of the LIMIT in tmoveto
         tmoveto(term.c.x, term.c.y);
-        // clear both screens (it makes dirty all lines)
-        let c = term.c;
-        for _ in 0..2 {
-            if mincol < col && 0 < minrow {
-                tclearregion(mincol, 0, col - 1, minrow - 1);
+        // the reflow invalidates selected coordinates
+        selclear();
+        tfulldirt();
+    }
+}
+
+/// Return a blank glyph in the current cursor colors, as left behind by
+/// `tclearregion`.
+fn tblank() -> Glyph_ {
+    unsafe {
+        Glyph_ {
+            u: ' ' as u32,
+            mode: 0,
+            fg: term.c.attr.fg,
+            bg: term.c.attr.bg,
+            comb: 0,
+            link: 0,
+            ucolor: 0,
+        }
+    }
+}
+
+/// Allocate a new line of `col` blank glyphs.
+fn tallocline(col: c_int) -> Line {
+    unsafe {
+        let line: Line = xmalloc(col as usize * size_of::<Glyph_>()).cast();
+        for x in 0..col as isize {
+            *line.offset(x) = tblank();
+        }
+        line
+    }
+}
+
+/// Resize the `lines` of a screen that is not reflowed, and their size
+/// attributes in `lattr` and `SEM_*` marks in `sem`, to `col` x `row`,
+/// truncating or blank-padding them along with their images.
+/// If `slide` is set, lines are dropped from the top to keep the cursor on the
+/// screen, leaving tmoveto to clamp it.
+fn tresizescreen(
+    lines: *mut Line,
+    lattr: &mut Vec<u8>,
+    sem: &mut Vec<u8>,
+    col: c_int,
+    row: c_int,
+    slide: bool,
+) -> *mut Line {
+    unsafe {
+        let mut lines = lines;
+        let mut oldrow = term.row;
+
+        let drop = if slide { (term.c.y - row + 1).max(0) } else { 0 };
+        lattr.drain(..(drop as usize).min(lattr.len()));
+        lattr.resize(row as usize, LATTR_SINGLE);
+        sem.drain(..(drop as usize).min(sem.len()));
+        sem.resize(row as usize, 0);
+        if drop > 0 {
+            for i in 0..drop as isize {
+                graphics::take(*lines.offset(i));
+                libc::free((*lines.offset(i)).cast());
             }
-            if 0 < col && minrow < row {
-                tclearregion(0, minrow, col - 1, row - 1);
+            libc::memmove(
+                lines.cast(),
+                lines.offset(drop as isize).cast(),
+                (oldrow - drop) as usize * size_of::<Line>(),
+            );
+            oldrow -= drop;
+        }
+        for i in row..oldrow {
+            graphics::take(*lines.offset(i as isize));
+            libc::free((*lines.offset(i as isize)).cast());
+        }
+
+        lines = xrealloc(lines.cast(), row as usize * size_of::<Line>()).cast();
+        for i in 0..row.min(oldrow) as isize {
+            let lp = lines.offset(i);
+            let strips = graphics::take(*lp);
+            *lp = xrealloc((*lp).cast(), col as usize * size_of::<Glyph_>())
+                .cast();
+            graphics::put(*lp, strips, col);
+            for x in term.col..col {
+                *(*lp).offset(x as isize) = tblank();
             }
-            tswapscreen();
-            tcursor(CURSOR_LOAD);
         }
-        term.c = c;
+        for i in oldrow..row {
+            *lines.offset(i as isize) = tallocline(col);
+        }
+
+        lines
+    }
+}
+
+/// A row moved by [`treflow`], with what it carries besides its glyphs.
+struct Row {
+    line: Line,
+    lattr: u8,
+    sem: u8,
+    strips: Vec<Strip>,
+}
+
+/// A logical line put together by [`treflow`].
+#[derive(Default)]
+struct Logical {
+    glyphs: Vec<Glyph_>,
+    lattr: u8,
+    /// the offset in `glyphs` of each physical line it was joined from, with
+    /// the `SEM_*` marks of the line and the strips of images shown on it
+    parts: Vec<(usize, u8, Vec<Strip>)>,
+}
+
+/// Reflow the history and the primary screen in `term.line` to `col` x `row`.
+///
+/// Physical lines continued with `ATTR_WRAP` are joined into logical lines,
+/// which are then wrapped again at the new width. Double size lines stay on
+/// their own and are cut off instead. The size attributes, the `SEM_*` marks
+/// and the images move along with the lines. The rows that no longer fit on the screen
+/// are pushed into the history. If `cursor` is given, its new position is
+/// returned.
+fn treflow(
+    col: c_int,
+    row: c_int,
+    cursor: Option<(c_int, c_int)>,
+) -> (c_int, c_int) {
+    unsafe {
+        let oldcol = term.col as usize;
+
+        // the attributes of the lines are taken out while the images are
+        // moved, which goes through the state too
+        let st = state();
+        let histlattr = std::mem::take(&mut st.histlattr);
+        let histsem = std::mem::take(&mut st.histsem);
+        let lattr = std::mem::take(&mut st.lattr);
+        let sem = std::mem::take(&mut st.sem);
+
+        // collect the physical lines, oldest first, skipping the blank lines
+        // at the bottom of the screen
+        let mut old = Vec::new();
+        for k in (1..=term.histn).rev() {
+            let i = ((term.histi - k + term.histmax) % term.histmax) as usize;
+            old.push(Row {
+                line: *term.hist.add(i),
+                lattr: histlattr.get(i).copied().unwrap_or(LATTR_SINGLE),
+                sem: histsem.get(i).copied().unwrap_or(0),
+                strips: graphics::take(*term.hist.add(i)),
+            });
+        }
+        let histn = old.len() as c_int;
+        let mut last = term.row - 1;
+        let cy = cursor.map_or(-1, |(_, y)| y);
+        while last > cy && {
+            let line = *term.line.offset(last as isize);
+            tlinelen(line, term.col) == 0 && graphics::strips(line).is_empty()
+        } {
+            last -= 1;
+        }
+        for y in 0..(last + 1) as usize {
+            old.push(Row {
+                line: *term.line.add(y),
+                lattr: lattr.get(y).copied().unwrap_or(LATTR_SINGLE),
+                sem: sem.get(y).copied().unwrap_or(0),
+                strips: graphics::take(*term.line.add(y)),
+            });
+        }
+
+        // join them into logical lines, remembering where the cursor is
+        let mut logical = Vec::new();
+        let mut cur = Logical::default();
+        let mut cpos = None;
+        for (i, r) in old.iter_mut().enumerate() {
+            // double size lines are not joined with others
+            if r.lattr != LATTR_SINGLE && !cur.parts.is_empty() {
+                logical.push(std::mem::take(&mut cur));
+            }
+            if let Some((cx, cy)) = cursor {
+                if i as c_int == histn + cy {
+                    cpos =
+                        Some((logical.len(), cur.glyphs.len() + cx as usize));
+                }
+            }
+            let glyphs = std::slice::from_raw_parts(r.line, oldcol);
+            let wrapped = r.lattr == LATTR_SINGLE
+                && glyphs[oldcol - 1].mode & ATTR_WRAP as u16 != 0;
+            let n = tlinelen(r.line, term.col) as usize;
+            cur.lattr = r.lattr;
+            cur.parts.push((
+                cur.glyphs.len(),
+                r.sem,
+                std::mem::take(&mut r.strips),
+            ));
+            cur.glyphs.extend_from_slice(&glyphs[..n]);
+            if let Some(g) = cur.glyphs.last_mut() {
+                g.mode &= !ATTR_WRAP as u16;
+            }
+            if !wrapped {
+                logical.push(std::mem::take(&mut cur));
+            }
+        }
+        if !cur.parts.is_empty() {
+            logical.push(cur);
+        }
+
+        // make sure the cursor cell exists in its logical line
+        if let Some((li, off)) = cpos {
+            let glyphs = &mut logical[li].glyphs;
+            while glyphs.len() <= off {
+                glyphs.push(tblank());
+            }
+        }
+
+        // wrap the logical lines again at the new width
+        let mut rows: Vec<Row> = Vec::new();
+        let mut crow = (0, 0);
+        for (li, l) in logical.into_iter().enumerate() {
+            let first = rows.len();
+            // the offsets in the logical line at which its rows start
+            let mut starts = vec![0];
+            let mut line = tallocline(col);
+            if l.lattr != LATTR_SINGLE {
+                for (x, &g) in l.glyphs.iter().take(col as usize).enumerate() {
+                    *line.add(x) = g;
+                }
+                if let Some((_, off)) = cpos.filter(|&(l, _)| l == li) {
+                    // only half the columns of double size lines are shown
+                    let x = (off as c_int).min((col / 2).max(1) - 1);
+                    crow = (x, rows.len() as c_int);
+                }
+            } else {
+                let mut x = 0;
+                for (off, &g) in l.glyphs.iter().enumerate() {
+                    let wide = g.mode & ATTR_WIDE as u16 != 0;
+                    if x == col || (wide && x == col - 1 && x > 0) {
+                        (*line.offset(col as isize - 1)).mode |=
+                            ATTR_WRAP as u16;
+                        rows.push(Row {
+                            line,
+                            lattr: LATTR_SINGLE,
+                            sem: 0,
+                            strips: Vec::new(),
+                        });
+                        line = tallocline(col);
+                        starts.push(off);
+                        x = 0;
+                    }
+                    if cpos == Some((li, off)) {
+                        crow = (x, rows.len() as c_int);
+                    }
+                    *line.offset(x as isize) = g;
+                    x += 1;
+                }
+            }
+            rows.push(Row { line, lattr: l.lattr, sem: 0, strips: Vec::new() });
+            // the marks go on the rows the physical lines started on, and the
+            // images on the rows of their first cells
+            let at = |off| starts.partition_point(|&s| s <= off) - 1;
+            for (off, sem, strips) in l.parts {
+                rows[first + at(off)].sem |= sem;
+                for mut s in strips {
+                    let off = off + s.x as usize;
+                    let k = at(off);
+                    s.x = (off - starts[k]) as c_int;
+                    rows[first + k].strips.push(s);
+                }
+            }
+        }
+
+        for r in &old {
+            libc::free(r.line.cast());
+        }
+        for y in last + 1..term.row {
+            libc::free((*term.line.offset(y as isize)).cast());
+        }
+        for i in 0..term.histmax {
+            let hp = term.hist.offset(i as isize);
+            // unused ring lines are not part of `old`
+            if (term.histi - i - 1 + term.histmax) % term.histmax >= term.histn
+            {
+                libc::free((*hp).cast());
+            }
+            *hp = null_mut();
+        }
+
+        // the bottom rows go on the screen, keeping the cursor visible, and
+        // everything above them into the history
+        let nrows = rows.len() as c_int;
+        let mut top = (nrows - row).max(0);
+        if cursor.is_some() {
+            top = top.min(crow.1);
+        }
+        for r in rows.iter().skip((top + row) as usize) {
+            libc::free(r.line.cast());
+        }
+
+        let hn = top.min(term.histmax);
+        let mut histlattr = vec![LATTR_SINGLE; term.histmax as usize];
+        let mut histsem = vec![0; term.histmax as usize];
+        let mut rows = rows.into_iter();
+        for (i, r) in rows.by_ref().take(top as usize).enumerate() {
+            let i = i as c_int - (top - hn);
+            if i < 0 {
+                libc::free(r.line.cast());
+            } else {
+                *term.hist.offset(i as isize) = r.line;
+                histlattr[i as usize] = r.lattr;
+                histsem[i as usize] = r.sem;
+                graphics::put(r.line, r.strips, col);
+            }
+        }
+        term.histn = hn;
+        term.histi = if term.histmax > 0 { hn % term.histmax } else { 0 };
+        term.scr = 0;
+
+        term.line =
+            xrealloc(term.line.cast(), row as usize * size_of::<Line>()).cast();
+        let mut lattr = vec![LATTR_SINGLE; row as usize];
+        let mut sem = vec![0; row as usize];
+        for y in 0..row as usize {
+            *term.line.add(y) = match rows.next() {
+                Some(r) => {
+                    lattr[y] = r.lattr;
+                    sem[y] = r.sem;
+                    graphics::put(r.line, r.strips, col);
+                    r.line
+                }
+                None => tallocline(col),
+            };
+        }
+        let st = state();
+        (st.histlattr, st.histsem) = (histlattr, histsem);
+        (st.lattr, st.sem) = (lattr, sem);
+
+        (crow.0, crow.1 - top)
+    }
+}
+
+/// Return the number of glyphs in `line`, of width `col`, ignoring trailing
+/// spaces unless the line is continued with `ATTR_WRAP`.
+fn tlinelen(line: Line, col: c_int) -> c_int {
+    unsafe {
+        let mut i = col;
+        if (*line.offset(i as isize - 1)).mode & ATTR_WRAP as u16 != 0 {
+            return i;
+        }
+        while i > 0 && (*line.offset(i as isize - 1)).u == ' ' as u32 {
+            i -= 1;
+        }
+        i
     }
 }
 
@@ -306,6 +879,9 @@ pub fn treset() {
                 mode: ATTR_NULL as u16,
                 fg: defaultfg,
                 bg: defaultbg,
+                comb: 0,
+                link: 0,
+                ucolor: 0,
             },
             x: 0,
             y: 0,
@@ -326,6 +902,10 @@ pub fn treset() {
         term.top = 0;
         term.bot = term.row - 1;
         term.mode = MODE_WRAP | MODE_UTF8;
+        let st = state();
+        st.kbd.clear();
+        st.altkbd.clear();
+        st.modkeys = 0;
 
         libc::memset(
             &raw mut term.trantbl as *mut _,
@@ -338,161 +918,2724 @@ pub fn treset() {
             4,
         );
 
-        term.charset = 0;
+        term.charset = 0;
+
+        for _ in 0..2 {
+            tmoveto(0, 0);
+            tcursor(CURSOR_SAVE);
+            tclearregion(0, 0, term.col - 1, term.row - 1);
+            for y in 0..term.row {
+                tsetlattr(y, LATTR_SINGLE);
+            }
+            state().sem.fill(0);
+            tswapscreen();
+        }
+    }
+}
+
+/// Move the cursor to `x, y`, clamping to the dimensions of the window.
+pub fn tmoveto(x: c_int, y: c_int) {
+    unsafe {
+        let (miny, maxy) = if term.c.state & CURSOR_ORIGIN as i8 != 0 {
+            (term.top, term.bot)
+        } else {
+            (0, term.row - 1)
+        };
+        term.c.state &= !CURSOR_WRAPNEXT as i8;
+        term.c.y = y.clamp(miny, maxy);
+        term.c.x = x.clamp(0, tlinecols(term.c.y) - 1);
+    }
+}
+
+#[inline]
+fn is_set(flag: c_int) -> bool {
+    unsafe { term.mode & flag != 0 }
+}
+
+/// Load or save cursor state depending on the value of `mode`, which should be
+/// either `CURSOR_SAVE` or `CURSOR_LOAD`.
+pub fn tcursor(mode: c_int) {
+    let alt = is_set(MODE_ALTSCREEN) as usize;
+    unsafe {
+        if mode == CURSOR_SAVE {
+            state().cursors[alt] = term.c;
+        } else if mode == CURSOR_LOAD {
+            let c = state().cursors[alt];
+            term.c = c;
+            tmoveto(c.x, c.y);
+        }
+    }
+}
+
+/// Clear the region defined by the provided coordinates, clamping to the bounds
+/// of the window.
+///
+/// If the bounds are provided in the "wrong" order (eg x1 > x2), these are
+/// swapped as well.
+pub fn tclearregion(
+    mut x1: c_int,
+    mut y1: c_int,
+    mut x2: c_int,
+    mut y2: c_int,
+) {
+    unsafe {
+        if x1 > x2 {
+            std::mem::swap(&mut x1, &mut x2);
+        }
+        if y1 > y2 {
+            std::mem::swap(&mut y1, &mut y2);
+        }
+
+        let x1 = x1.clamp(0, term.col - 1);
+        let x2 = x2.clamp(0, term.col - 1);
+        let y1 = y1.clamp(0, term.row - 1);
+        let y2 = y2.clamp(0, term.row - 1);
+
+        for y in y1..=y2 {
+            *term.dirty.offset(y as isize) = 1;
+            graphics::clear(*term.line.offset(y as isize), x1, x2);
+            for x in x1..=x2 {
+                let gp: &mut Glyph_ =
+                    &mut *(*term.line.offset(y as isize)).offset(x as isize);
+                if selected(x, y) != 0 {
+                    selclear();
+                }
+                gp.fg = term.c.attr.fg;
+                gp.bg = term.c.attr.bg;
+                gp.mode = 0;
+                gp.u = ' ' as u32;
+                gp.comb = 0;
+                gp.link = 0;
+                gp.ucolor = 0;
+            }
+        }
+    }
+}
+
+/// Return whether or not the character at `x`,`y` is selected.
+///
+/// TODO bool
+fn selected(x: c_int, y: c_int) -> c_int {
+    unsafe {
+        if sel.mode == SEL_EMPTY
+            || sel.ob.x == -1
+            || sel.alt != is_set(MODE_ALTSCREEN) as c_int
+        {
+            return 0;
+        }
+        if sel.type_ == SEL_RECTANGULAR {
+            return (between(y, sel.nb.y, sel.ne.y)
+                && between(x, sel.nb.x, sel.ne.x)) as c_int;
+        }
+
+        (between(y, sel.nb.y, sel.ne.y)
+            && (y != sel.nb.y || x >= sel.nb.x)
+            && (y != sel.ne.y || x <= sel.ne.x)) as c_int
+    }
+}
+
+/// Clear the current selection.
+fn selclear() {
+    unsafe {
+        if sel.ob.x == -1 {
+            return;
+        }
+        sel.mode = SEL_IDLE;
+        sel.ob.x = -1;
+        tsetdirt(sel.nb.y, sel.ne.y);
+    }
+}
+
+/// Mark the rows between `top` and `bot` dirty.
+fn tsetdirt(top: c_int, bot: c_int) {
+    unsafe {
+        let top = top.clamp(0, term.row - 1);
+        let bot = bot.clamp(0, term.row - 1);
+
+        for i in top..=bot {
+            // TODO term.dirty is obviously a dynamic array of bool with size
+            // equal to the number of rows in the terminal. thus, refactoring
+            // can happen in two steps: first, make *mut bool, then make it a
+            // vec that can be resized instead of xrealloc in C
+            *term.dirty.offset(i as isize) = 1;
+        }
+    }
+}
+
+/// Swap the current and alt screens and mark the whole terminal dirty;
+pub fn tswapscreen() {
+    unsafe {
+        term.scr = 0;
+        (term.line, term.alt) = (term.alt, term.line);
+        let st = state();
+        std::mem::swap(&mut st.lattr, &mut st.altlattr);
+        std::mem::swap(&mut st.sem, &mut st.altsem);
+        std::mem::swap(&mut st.kbd, &mut st.altkbd);
+        term.mode ^= MODE_ALTSCREEN;
+        tfulldirt();
+    }
+}
+
+/// Mark the whole terminal dirty.
+fn tfulldirt() {
+    unsafe {
+        tsetdirt(0, term.row - 1);
+    }
+}
+
+/// Mark the whole terminal dirty and draw it again.
+pub(crate) fn redraw() {
+    tfulldirt();
+    draw();
+}
+
+/// Move the cursor to `x, y`, relative to the scrolling region when the cursor
+/// is in origin mode. Used for absolute user moves.
+fn tmoveato(x: c_int, y: c_int) {
+    unsafe {
+        let top =
+            if term.c.state & CURSOR_ORIGIN as i8 != 0 { term.top } else { 0 };
+        tmoveto(x, y + top);
+    }
+}
+
+/// Move the cursor to the next line, scrolling if it is at the bottom of the
+/// scrolling region, and to the first column if `first_col` is set.
+fn tnewline(first_col: bool) {
+    unsafe {
+        let mut y = term.c.y;
+        if y == term.bot {
+            tscrollup(term.top, 1);
+        } else {
+            y += 1;
+        }
+        tmoveto(if first_col { 0 } else { term.c.x }, y);
+    }
+}
+
+/// Scroll the lines from `orig` to the bottom of the scrolling region down by
+/// `n` lines.
+fn tscrolldown(orig: c_int, n: c_int) {
+    unsafe {
+        let n = n.clamp(0, term.bot - orig + 1);
+
+        tsetdirt(orig, term.bot - n);
+        tclearregion(0, term.bot - n + 1, term.col - 1, term.bot);
+
+        for i in (orig + n..=term.bot).rev() {
+            std::ptr::swap(
+                term.line.offset(i as isize),
+                term.line.offset((i - n) as isize),
+            );
+        }
+        let lattr = &mut state().lattr[orig as usize..=term.bot as usize];
+        lattr.rotate_right(n as usize);
+        lattr[..n as usize].fill(LATTR_SINGLE);
+        let sem = &mut state().sem[orig as usize..=term.bot as usize];
+        sem.rotate_right(n as usize);
+        sem[..n as usize].fill(0);
+
+        selscroll(orig, n);
+    }
+}
+
+/// Scroll the lines from `orig` to the bottom of the scrolling region up by `n`
+/// lines. Lines scrolled off the top of the primary screen are pushed into the
+/// history.
+fn tscrollup(orig: c_int, n: c_int) {
+    unsafe {
+        let n = n.clamp(0, term.bot - orig + 1);
+
+        if orig == 0 && term.histmax > 0 && !is_set(MODE_ALTSCREEN) {
+            for i in 0..n {
+                thistpush(i);
+            }
+        }
+
+        tclearregion(0, orig, term.col - 1, orig + n - 1);
+        tsetdirt(orig + n, term.bot);
+
+        for i in orig..=term.bot - n {
+            std::ptr::swap(
+                term.line.offset(i as isize),
+                term.line.offset((i + n) as isize),
+            );
+        }
+        let lattr = &mut state().lattr[orig as usize..=term.bot as usize];
+        lattr.rotate_left(n as usize);
+        let len = lattr.len();
+        lattr[len - n as usize..].fill(LATTR_SINGLE);
+        let sem = &mut state().sem[orig as usize..=term.bot as usize];
+        sem.rotate_left(n as usize);
+        sem[len - n as usize..].fill(0);
+
+        selscroll(orig, -n);
+    }
+}
+
+/// Swap line `y` into the next slot of the history ring, reusing the buffer of
+/// the line it evicts.
+fn thistpush(y: c_int) {
+    unsafe {
+        let hp = term.hist.offset(term.histi as isize);
+        if (*hp).is_null() {
+            *hp = xmalloc(term.col as usize * size_of::<Glyph_>()).cast();
+        }
+        std::ptr::swap(hp, term.line.offset(y as isize));
+        let st = state();
+        st.histlattr[term.histi as usize] = st.lattr[y as usize];
+        st.histsem[term.histi as usize] = st.sem[y as usize];
+
+        term.histi = (term.histi + 1) % term.histmax;
+        if term.histn < term.histmax {
+            term.histn += 1;
+        }
+    }
+}
+
+/// Drop the history and scroll back to the bottom.
+fn thistclear() {
+    unsafe {
+        for i in 0..term.histmax {
+            let hp = *term.hist.offset(i as isize);
+            if !hp.is_null() {
+                graphics::clear(hp, 0, term.col - 1);
+            }
+        }
+        term.histn = 0;
+        term.scr = 0;
+        tfulldirt();
+    }
+}
+
+/// Move the selection along with `n` lines scrolled from `orig`, clearing it if
+/// it is only partially scrolled or leaves the scrolling region.
+fn selscroll(orig: c_int, n: c_int) {
+    unsafe {
+        if sel.ob.x == -1 || sel.alt != is_set(MODE_ALTSCREEN) as c_int {
+            return;
+        }
+
+        let nb = between(sel.nb.y, orig, term.bot);
+        if nb != between(sel.ne.y, orig, term.bot) {
+            selclear();
+        } else if nb {
+            sel.ob.y += n;
+            sel.oe.y += n;
+            if sel.ob.y < term.top
+                || sel.ob.y > term.bot
+                || sel.oe.y < term.top
+                || sel.oe.y > term.bot
+            {
+                selclear();
+            } else {
+                selnormalize();
+            }
+        }
+    }
+}
+
+/// Compute the normalized selection bounds in `sel.nb` and `sel.ne` from the
+/// original ones in `sel.ob` and `sel.oe`.
+fn selnormalize() {
+    unsafe {
+        if sel.type_ == SEL_REGULAR && sel.ob.y != sel.oe.y {
+            let forward = sel.ob.y < sel.oe.y;
+            sel.nb.x = if forward { sel.ob.x } else { sel.oe.x };
+            sel.ne.x = if forward { sel.oe.x } else { sel.ob.x };
+        } else {
+            sel.nb.x = sel.ob.x.min(sel.oe.x);
+            sel.ne.x = sel.ob.x.max(sel.oe.x);
+        }
+        sel.nb.y = sel.ob.y.min(sel.oe.y);
+        sel.ne.y = sel.ob.y.max(sel.oe.y);
+
+        (sel.nb.x, sel.nb.y) = selsnap(sel.nb.x, sel.nb.y, -1);
+        (sel.ne.x, sel.ne.y) = selsnap(sel.ne.x, sel.ne.y, 1);
+
+        // expand selection over line breaks
+        if sel.type_ == SEL_RECTANGULAR {
+            return;
+        }
+        let i = tlinelen(Term::tline(&raw mut term, sel.nb.y), term.col);
+        if i < sel.nb.x {
+            sel.nb.x = i;
+        }
+        if tlinelen(Term::tline(&raw mut term, sel.ne.y), term.col) <= sel.ne.x
+        {
+            sel.ne.x = term.col - 1;
+        }
+    }
+}
+
+/// Return `x, y` moved in `direction` to the edge of the word or line around
+/// it, depending on `sel.snap`.
+fn selsnap(mut x: c_int, mut y: c_int, direction: c_int) -> (c_int, c_int) {
+    unsafe {
+        let glyph = |x: c_int, y: c_int| {
+            *Term::tline(&raw mut term, y).offset(x as isize)
+        };
+
+        match sel.snap {
+            SNAP_WORD => {
+                // snap around if the word wraps around at the end or beginning
+                // of a line
+                let mut prevgp = glyph(x, y);
+                let mut prevdelim = isdelim(prevgp.u);
+                loop {
+                    let mut newx = x + direction;
+                    let mut newy = y;
+                    if !between(newx, 0, term.col - 1) {
+                        newy += direction;
+                        newx = (newx + term.col) % term.col;
+                        if !between(newy, 0, term.row - 1) {
+                            break;
+                        }
+
+                        let (xt, yt) =
+                            if direction > 0 { (x, y) } else { (newx, newy) };
+                        if glyph(xt, yt).mode & ATTR_WRAP as u16 == 0 {
+                            break;
+                        }
+                    }
+
+                    let line = Term::tline(&raw mut term, newy);
+                    if newx >= tlinelen(line, term.col) {
+                        break;
+                    }
+
+                    let gp = glyph(newx, newy);
+                    let delim = isdelim(gp.u);
+                    if gp.mode & ATTR_WDUMMY as u16 == 0
+                        && (delim != prevdelim || (delim && gp.u != prevgp.u))
+                    {
+                        break;
+                    }
+
+                    x = newx;
+                    y = newy;
+                    prevgp = gp;
+                    prevdelim = delim;
+                }
+            }
+            SNAP_LINE => {
+                // snap around if the previous line or the current one has set
+                // ATTR_WRAP at its end. then the whole next or previous line
+                // will be selected
+                let last = term.col - 1;
+                if direction < 0 {
+                    x = 0;
+                    while y > 0
+                        && glyph(last, y - 1).mode & ATTR_WRAP as u16 != 0
+                    {
+                        y += direction;
+                    }
+                } else if direction > 0 {
+                    x = last;
+                    while y < term.row - 1
+                        && glyph(last, y).mode & ATTR_WRAP as u16 != 0
+                    {
+                        y += direction;
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        (x, y)
+    }
+}
+
+/// Return whether `u` is one of the `worddelimiters`.
+fn isdelim(u: Rune) -> bool {
+    unsafe {
+        if u == 0 {
+            return false;
+        }
+        let mut p = worddelimiters;
+        while *p != 0 {
+            if *p as Rune == u {
+                return true;
+            }
+            p = p.offset(1);
+        }
+        false
+    }
+}
+
+/// Return the selected text, or `None` if there is no selection.
+pub fn getsel() -> Option<Vec<u8>> {
+    unsafe {
+        if sel.ob.x == -1 {
+            return None;
+        }
+
+        let rectangular = sel.type_ == SEL_RECTANGULAR;
+        let mut buf = [0; UTF_SIZ];
+        let mut s = Vec::new();
+
+        // append every set & selected glyph to the selection
+        for y in sel.nb.y..=sel.ne.y {
+            let line = Term::tline(&raw mut term, y);
+            let linelen = tlinelen(line, term.col);
+            if linelen == 0 {
+                s.push(b'\n');
+                continue;
+            }
+
+            let (first, lastx) = if rectangular {
+                (sel.nb.x, sel.ne.x)
+            } else {
+                (
+                    if sel.nb.y == y { sel.nb.x } else { 0 },
+                    if sel.ne.y == y { sel.ne.x } else { term.col - 1 },
+                )
+            };
+            let mut last = lastx.min(linelen - 1);
+            while last >= first && (*line.offset(last as isize)).u == ' ' as u32
+            {
+                last -= 1;
+            }
+
+            for x in first..=last {
+                let gp = *line.offset(x as isize);
+                if gp.mode & ATTR_WDUMMY as u16 != 0 {
+                    continue;
+                }
+                for u in std::iter::once(gp.u).chain(gmarks(&gp)) {
+                    let n = utf8encode(u, &mut buf);
+                    s.extend_from_slice(&buf[..n]);
+                }
+            }
+
+            // NOTE(st): Copy and pasting of line endings is inconsistent in the
+            // inconsistent terminal and GUI world. The best solution seems like
+            // to produce '\n' when something is copied from st and convert
+            // '\n' to '\r', when something to be pasted is received by st.
+            let wrapped = last >= 0
+                && (*line.offset(last as isize)).mode & ATTR_WRAP as u16 != 0;
+            if (y < sel.ne.y || lastx >= linelen) && (!wrapped || rectangular) {
+                s.push(b'\n');
+            }
+        }
+
+        Some(s)
+    }
+}
+
+/// Write `s` to the tty, echoing it on the terminal too if `may_echo` is set
+/// and `MODE_ECHO` is on.
+pub(crate) fn ttywrite(mut s: &[u8], may_echo: bool) {
+    unsafe {
+        if term.scr > 0 {
+            kscrolldown(&Arg { i: term.scr });
+        }
+    }
+
+    if may_echo && is_set(MODE_ECHO) {
+        twrite(s, true);
+    }
+
+    if !is_set(MODE_CRLF) {
+        ttywriteraw(s);
+        return;
+    }
+
+    // this is similar to how the kernel handles ONLCR for ttys
+    while !s.is_empty() {
+        let next = if s[0] == b'\r' {
+            ttywriteraw(b"\r\n");
+            1
+        } else {
+            let next = s.iter().position(|&c| c == b'\r').unwrap_or(s.len());
+            ttywriteraw(&s[..next]);
+            next
+        };
+        s = &s[next..];
+    }
+}
+
+/// [`ttywrite`] for the C code, which calls it for keys, mouse reports and
+/// pastes.
+#[export_name = "ttywrite"]
+extern "C" fn cttywrite(s: *const c_char, n: usize, may_echo: c_int) {
+    if n == 0 {
+        return;
+    }
+    let s = unsafe { std::slice::from_raw_parts(s.cast(), n) };
+    ttywrite(s, may_echo != 0);
+}
+
+/// Write `s` to the tty without any translation. Headless terminals collect it
+/// in `output` instead.
+fn ttywriteraw(mut s: &[u8]) {
+    if let Some(out) = unsafe { state() }.output.as_mut() {
+        out.extend_from_slice(s);
+        return;
+    }
+
+    unsafe {
+        let mut wfd = MaybeUninit::uninit();
+        let mut rfd = MaybeUninit::uninit();
+        let mut lim = 256;
+
+        // NOTE(st): Remember that we are using a pty, which might be a modem
+        // line. Writing too much will clog the line. That's why we are doing
+        // this dance.
+        // FIXME(st): Migrate the world to Plan 9.
+        while !s.is_empty() {
+            FD_ZERO(wfd.as_mut_ptr());
+            FD_ZERO(rfd.as_mut_ptr());
+            FD_SET(cmdfd, wfd.as_mut_ptr());
+            FD_SET(cmdfd, rfd.as_mut_ptr());
+
+            // check if we can write
+            if pselect(
+                cmdfd + 1,
+                rfd.as_mut_ptr(),
+                wfd.as_mut_ptr(),
+                null_mut(),
+                null(),
+                null(),
+            ) < 0
+            {
+                if *__errno_location() == EINTR {
+                    continue;
+                }
+                die!("select failed: {}", strerrno());
+            }
+            if FD_ISSET(cmdfd, wfd.as_mut_ptr()) {
+                // Only write the bytes written by ttywrite() or the default of
+                // 256. This seems to be a reasonable value for a serial line.
+                // Bigger values might clog the I/O.
+                let r = libc::write(cmdfd, s.as_ptr().cast(), s.len().min(lim));
+                if r < 0 {
+                    die!("write error on tty: {}", strerrno());
+                }
+                let r = r as usize;
+                if r < s.len() {
+                    // We weren't able to write out everything. This means the
+                    // buffer is getting full again. Empty it.
+                    if s.len() < lim {
+                        lim = ttyread();
+                    }
+                    s = &s[r..];
+                } else {
+                    // all bytes have been written
+                    break;
+                }
+            }
+            if FD_ISSET(cmdfd, rfd.as_mut_ptr()) {
+                lim = ttyread();
+            }
+        }
+    }
+}
+
+/// Scroll the view of a terminal scrolled back into its history up by `a.i`
+/// lines, or by a screen minus `-a.i` lines if it is negative.
+pub fn kscrollup(a: &Arg) {
+    unsafe {
+        let mut n = a.i;
+        if is_set(MODE_ALTSCREEN) {
+            return;
+        }
+        if n < 0 {
+            n = cmp::max(term.row + n, 1);
+        }
+        n = cmp::min(n, term.histn - term.scr);
+        if n <= 0 {
+            return;
+        }
+
+        term.scr += n;
+        selscroll(0, n);
+        tfulldirt();
+    }
+}
+
+/// Scroll the view of a terminal scrolled back into its history down by `a.i`
+/// lines, or by a screen minus `-a.i` lines if it is negative.
+pub fn kscrolldown(a: &Arg) {
+    unsafe {
+        let mut n = a.i;
+        if n < 0 {
+            n = cmp::max(term.row + n, 1);
+        }
+        n = cmp::min(n, term.scr);
+        if n <= 0 {
+            return;
+        }
+
+        term.scr -= n;
+        selscroll(0, -n);
+        tfulldirt();
+    }
+}
+
+/// Process the bytes in `buf`, returning the number of bytes consumed. An
+/// incomplete UTF-8 sequence at the end of `buf` is left for the next call. If
+/// `show_ctrl` is set, control characters are shown in caret notation.
+pub(crate) fn twrite(buf: &[u8], show_ctrl: bool) -> usize {
+    // take the parser out of the state while it runs, since the handler may
+    // need the rest of it
+    let mut parser = std::mem::take(&mut unsafe { state() }.parser);
+    let mut n = 0;
+    while n < buf.len() {
+        let mut u = 0;
+        let charsize = if is_set(MODE_UTF8) {
+            // process a complete utf8 char
+            let charsize = utf8decode(&buf[n..], &mut u);
+            if charsize == 0 {
+                break;
+            }
+            charsize
+        } else {
+            u = buf[n] as Rune;
+            1
+        };
+
+        if show_ctrl && iscontrol(u) {
+            if u & 0x80 != 0 {
+                u &= 0x7f;
+                tputc(&mut parser, '^' as Rune);
+                tputc(&mut parser, '[' as Rune);
+            } else if u != '\n' as Rune
+                && u != '\r' as Rune
+                && u != '\t' as Rune
+            {
+                u ^= 0x40;
+                tputc(&mut parser, '^' as Rune);
+            }
+        }
+        tputc(&mut parser, u);
+        n += charsize;
+    }
+    unsafe { state() }.parser = parser;
+    n
+}
+
+/// Process a single character `u` written to the terminal with `parser`.
+fn tputc(parser: &mut Parser, u: Rune) {
+    tprinterchar(u);
+    parser.advance(&mut TermHandler, u);
+}
+
+/// Copy the character `u` to the printer if `MODE_PRINT` is set.
+fn tprinterchar(u: Rune) {
+    if !is_set(MODE_PRINT) {
+        return;
+    }
+    let mut c = [0; UTF_SIZ];
+    if u < 127 || !is_set(MODE_UTF8) {
+        c[0] = u as u8;
+        tprinter(&c[..1]);
+    } else {
+        let len = utf8encode(u, &mut c);
+        tprinter(&c[..len]);
+    }
+}
+
+/// The [`Handler`] applying the actions of the parser to the `term` global.
+struct TermHandler;
+
+impl Handler for TermHandler {
+    fn print(&mut self, c: Rune) {
+        tprint(c);
+    }
+
+    fn execute(&mut self, byte: u8) {
+        tcontrolcode(byte);
+        // control codes are not shown ever
+        unsafe {
+            term.lastc = 0;
+        }
+    }
+
+    fn csi_dispatch(&mut self, csi: &Csi) {
+        csihandle(csi);
+    }
+
+    fn esc_dispatch(&mut self, intermediates: &[u8], byte: u8) {
+        eschandle(intermediates, byte);
+    }
+
+    fn osc_dispatch(&mut self, params: &[&[u8]]) {
+        strhandle(params);
+    }
+
+    fn dcs_dispatch(&mut self, dcs: &Dcs) {
+        dcshandle(dcs);
+    }
+
+    fn apc_dispatch(&mut self, data: &[u8]) {
+        apchandle(data);
+    }
+
+    fn utf8(&self) -> bool {
+        is_set(MODE_UTF8)
+    }
+}
+
+/// Print the graphic character `u` at the cursor.
+fn tprint(u: Rune) {
+    unsafe {
+        let width = if u < 127 || !is_set(MODE_UTF8) {
+            1
+        } else {
+            match wcwidth(u as libc::wchar_t) {
+                -1 => 1,
+                w => w,
+            }
+        };
+
+        if width == 0 {
+            tcombine(u);
+            return;
+        }
+
+        if selected(term.c.x, term.c.y) != 0 {
+            selclear();
+        }
+
+        let mut gp = Term::line(&raw mut term, term.c.y, term.c.x);
+        if is_set(MODE_WRAP) && term.c.state & CURSOR_WRAPNEXT as i8 != 0 {
+            (*gp).mode |= ATTR_WRAP as u16;
+            tnewline(true);
+            gp = Term::line(&raw mut term, term.c.y, term.c.x);
+        }
+
+        if is_set(MODE_INSERT) && term.c.x + width < term.col {
+            libc::memmove(
+                gp.offset(width as isize).cast(),
+                gp.cast(),
+                (term.col - term.c.x - width) as usize * size_of::<Glyph_>(),
+            );
+            (*gp).mode &= !ATTR_WIDE as u16;
+        }
+
+        // double size lines only have half the columns
+        if term.c.x + width > tlinecols(term.c.y) {
+            if is_set(MODE_WRAP) {
+                tnewline(true);
+            } else {
+                tmoveto(tlinecols(term.c.y) - width, term.c.y);
+            }
+            gp = Term::line(&raw mut term, term.c.y, term.c.x);
+        }
+
+        tsetchar(u, term.c.attr, term.c.x, term.c.y);
+        term.lastc = u;
+
+        if width == 2 {
+            (*gp).mode |= ATTR_WIDE as u16;
+            if term.c.x + 1 < term.col {
+                if (*gp.offset(1)).mode == ATTR_WIDE as u16
+                    && term.c.x + 2 < term.col
+                {
+                    (*gp.offset(2)).u = ' ' as Rune;
+                    (*gp.offset(2)).mode &= !ATTR_WDUMMY as u16;
+                }
+                (*gp.offset(1)).u = 0;
+                (*gp.offset(1)).mode = ATTR_WDUMMY as u16;
+                (*gp.offset(1)).link = term.c.attr.link;
+            }
+        }
+        if term.c.x + width < tlinecols(term.c.y) {
+            tmoveto(term.c.x + width, term.c.y);
+        } else {
+            term.c.state |= CURSOR_WRAPNEXT as i8;
+        }
+    }
+}
+
+/// Add the zero width character `u` to the glyph before the cursor, or the one
+/// under it if a wrap is pending. It is dropped at the start of a line.
+fn tcombine(u: Rune) {
+    unsafe {
+        let mut x = term.c.x;
+        if term.c.state & CURSOR_WRAPNEXT as i8 == 0 {
+            x -= 1;
+        }
+        if x < 0 {
+            return;
+        }
+        let mut gp = Term::line(&raw mut term, term.c.y, x);
+        if (*gp).mode & ATTR_WDUMMY as u16 != 0 && x > 0 {
+            gp = gp.offset(-1);
+        }
+        gaddmark(&mut *gp, u);
+        *term.dirty.offset(term.c.y as isize) = 1;
+    }
+}
+
+/// Return the combining marks drawn over the character of `g`.
+pub(crate) fn gmarks(g: &Glyph_) -> Vec<Rune> {
+    unsafe { state() }.marks(g).to_vec()
+}
+
+/// Add the combining mark `u` to the marks of `g`, unless it has `MARKS_MAX`
+/// of them already.
+fn gaddmark(g: &mut Glyph_, u: Rune) {
+    let mut seq = gmarks(g);
+    if seq.len() >= MARKS_MAX {
+        return;
+    }
+    seq.push(u);
+
+    let st = unsafe { state() };
+    if let Some(&comb) = st.markids.get(&seq) {
+        g.comb = comb;
+        return;
+    }
+    if st.marks.len() >= MARKSEQ_MAX {
+        tgcmarks();
+    }
+    let st = unsafe { state() };
+    if st.marks.len() < MARKSEQ_MAX {
+        st.marks.push(seq.clone());
+        g.comb = st.marks.len() as u32;
+        st.markids.insert(seq, g.comb);
+    }
+}
+
+/// Drop the sequences of combining marks that no glyph has anymore and number
+/// the rest again, so that new ones can be added.
+fn tgcmarks() {
+    let st = unsafe { state() };
+    if st.marksgc > 0 {
+        st.marksgc -= 1;
+        return;
+    }
+    let old = std::mem::take(&mut st.marks);
+    let mut used = vec![false; old.len() + 1];
+    tforeachglyph(|g| used[g.comb as usize] = true);
+
+    // the new `comb` value for each old one
+    let mut comb = vec![0; used.len()];
+    let mut marks = Vec::new();
+    for (i, seq) in old.into_iter().enumerate() {
+        if used[i + 1] {
+            marks.push(seq);
+            comb[i + 1] = marks.len() as u32;
+        }
+    }
+    tforeachglyph(|g| g.comb = comb[g.comb as usize]);
+    let st = unsafe { state() };
+    st.markids = (1..).zip(&marks).map(|(i, seq)| (seq.clone(), i)).collect();
+    st.marks = marks;
+    // looking again right away would be a waste if most are still in use
+    if st.marks.len() > MARKSEQ_MAX / 2 {
+        st.marksgc = MARKSEQ_MAX / 4;
+    }
+}
+
+/// Call `f` on every glyph of the screens and the history, and on the
+/// attributes of the cursor and the saved cursors.
+fn tforeachglyph(mut f: impl FnMut(&mut Glyph_)) {
+    unsafe {
+        let screens = (0..term.row as usize)
+            .flat_map(|y| [*term.line.add(y), *term.alt.add(y)]);
+        let hist = (0..term.histmax as usize).map(|i| *term.hist.add(i));
+        for lp in screens.chain(hist).filter(|lp| !lp.is_null()) {
+            for x in 0..term.col as usize {
+                f(&mut *lp.add(x));
+            }
+        }
+        let t = &raw mut term;
+        f(&mut (*t).c.attr);
+        for c in &mut state().cursors {
+            f(&mut c.attr);
+        }
+    }
+}
+
+/// Write `u` with the attributes of `attr` to `x, y`, translating it through
+/// the current charset.
+fn tsetchar(mut u: Rune, attr: Glyph_, x: c_int, y: c_int) {
+    // NOTE(st): The table is proudly stolen from rxvt.
+    #[rustfmt::skip]
+    const VT100_0: [Option<char>; 62] = [ // 0x41 - 0x7e
+        Some('↑'), Some('↓'), Some('→'), Some('←'), Some('█'), Some('▚'), Some('☃'), // A - G
+        None, None, None, None, None, None, None, None, // H - O
+        None, None, None, None, None, None, None, None, // P - W
+        None, None, None, None, None, None, None, Some(' '), // X - _
+        Some('◆'), Some('▒'), Some('␉'), Some('␌'), Some('␍'), Some('␊'), Some('°'), Some('±'), // ` - g
+        Some('␤'), Some('␋'), Some('┘'), Some('┐'), Some('┌'), Some('└'), Some('┼'), Some('⎺'), // h - o
+        Some('⎻'), Some('─'), Some('⎼'), Some('⎽'), Some('├'), Some('┤'), Some('┴'), Some('┬'), // p - w
+        Some('│'), Some('≤'), Some('≥'), Some('π'), Some('≠'), Some('£'), Some('·'), // x - ~
+    ];
+
+    unsafe {
+        if term.trantbl[term.charset as usize] == CS_GRAPHIC0 as c_char
+            && between(u, 0x41, 0x7e)
+        {
+            if let Some(c) = VT100_0[(u - 0x41) as usize] {
+                u = c as Rune;
+            }
+        }
+
+        let gp = Term::line(&raw mut term, y, x);
+        if (*gp).mode & ATTR_WIDE as u16 != 0 {
+            if x + 1 < term.col {
+                (*gp.offset(1)).u = ' ' as Rune;
+                (*gp.offset(1)).mode &= !ATTR_WDUMMY as u16;
+            }
+        } else if (*gp).mode & ATTR_WDUMMY as u16 != 0 {
+            (*gp.offset(-1)).u = ' ' as Rune;
+            (*gp.offset(-1)).mode &= !ATTR_WIDE as u16;
+            (*gp.offset(-1)).comb = 0;
+        }
+
+        *term.dirty.offset(y as isize) = 1;
+        graphics::clear(*term.line.offset(y as isize), x, x);
+        *gp = attr;
+        (*gp).u = u;
+    }
+}
+
+/// Delete `n` characters at the cursor, shifting the rest of the line left.
+fn tdeletechar(n: c_int) {
+    unsafe {
+        let n = n.clamp(0, term.col - term.c.x);
+        let dst = term.c.x;
+        let src = term.c.x + n;
+        let size = term.col - src;
+        let line = *term.line.offset(term.c.y as isize);
+
+        libc::memmove(
+            line.offset(dst as isize).cast(),
+            line.offset(src as isize).cast(),
+            size as usize * size_of::<Glyph_>(),
+        );
+        tclearregion(term.col - n, term.c.y, term.col - 1, term.c.y);
+    }
+}
+
+/// Insert `n` blank characters at the cursor, shifting the rest of the line
+/// right.
+fn tinsertblank(n: c_int) {
+    unsafe {
+        let n = n.clamp(0, term.col - term.c.x);
+        let dst = term.c.x + n;
+        let src = term.c.x;
+        let size = term.col - dst;
+        let line = *term.line.offset(term.c.y as isize);
+
+        libc::memmove(
+            line.offset(dst as isize).cast(),
+            line.offset(src as isize).cast(),
+            size as usize * size_of::<Glyph_>(),
+        );
+        tclearregion(src, term.c.y, dst - 1, term.c.y);
+    }
+}
+
+/// Insert `n` blank lines at the cursor if it is in the scrolling region.
+fn tinsertblankline(n: c_int) {
+    unsafe {
+        if between(term.c.y, term.top, term.bot) {
+            tscrolldown(term.c.y, n);
+        }
+    }
+}
+
+/// Delete `n` lines at the cursor if it is in the scrolling region.
+fn tdeleteline(n: c_int) {
+    unsafe {
+        if between(term.c.y, term.top, term.bot) {
+            tscrollup(term.c.y, n);
+        }
+    }
+}
+
+/// Parse the extended color in the SGR parameters `attr`, starting at the 38
+/// or 48 at `npar`, which is advanced past the color. Returns `None` if the
+/// color is invalid.
+fn tdefcolor(attr: &[c_int], npar: &mut usize) -> Option<u32> {
+    let l = attr.len();
+    match attr.get(*npar + 1) {
+        // direct color in RGB space
+        Some(2) => {
+            if *npar + 4 >= l {
+                eprintln!(
+                    "erresc(38): Incorrect number of parameters ({})",
+                    *npar
+                );
+                return None;
+            }
+            let (r, g, b) = (attr[*npar + 2], attr[*npar + 3], attr[*npar + 4]);
+            *npar += 4;
+            if !between(r, 0, 255) || !between(g, 0, 255) || !between(b, 0, 255)
+            {
+                eprintln!(
+                    "erresc: bad rgb color ({},{},{})",
+                    r as u32, g as u32, b as u32
+                );
+                None
+            } else {
+                Some(truecolor(r as u32, g as u32, b as u32))
+            }
+        }
+        // indexed color
+        Some(5) => {
+            if *npar + 2 >= l {
+                eprintln!(
+                    "erresc(38): Incorrect number of parameters ({})",
+                    *npar
+                );
+                return None;
+            }
+            *npar += 2;
+            if !between(attr[*npar], 0, 255) {
+                eprintln!("erresc: bad fgcolor {}", attr[*npar]);
+                None
+            } else {
+                Some(attr[*npar] as u32)
+            }
+        }
+        // 0: implemented defined (only foreground), 1: transparent, 3: direct
+        // color in CMY space, 4: direct color in CMYK space
+        _ => {
+            eprintln!("erresc(38): gfx attr {} unknown", attr[*npar]);
+            None
+        }
+    }
+}
+
+#[inline]
+fn truecolor(r: u32, g: u32, b: u32) -> u32 {
+    1 << 24 | r << 16 | g << 8 | b
+}
+
+/// Parse the extended color in the colon separated sub-parameters `sub` of an
+/// SGR 38, 48 or 58, which are `2:<colorspace>:r:g:b`, `2:r:g:b` or `5:n`.
+fn tsubcolor(sub: &[c_int]) -> Option<u32> {
+    let byte = |c: c_int| u8::try_from(c).ok().map(u32::from);
+    match *sub {
+        [2, _, r, g, b, ..] | [2, r, g, b] => {
+            Some(truecolor(byte(r)?, byte(g)?, byte(b)?))
+        }
+        [5, n] => byte(n),
+        _ => {
+            eprintln!("erresc: gfx attr {sub:?} unknown");
+            None
+        }
+    }
+}
+
+/// Apply the SGR parameters of `csi` to the cursor attributes.
+fn tsetattr(csi: &Csi) {
+    unsafe {
+        let attr = csi.params;
+        let mut a = term.c.attr;
+        let mut i = 0;
+        while i < attr.len() {
+            // the sub-parameters separated by colons from this one
+            let end = (i + 1..attr.len())
+                .find(|&j| !csi.subparams[j])
+                .unwrap_or(attr.len());
+            let sub = &attr[i + 1..end];
+            let color = |i: &mut usize| {
+                if sub.is_empty() {
+                    tdefcolor(attr, i)
+                } else {
+                    tsubcolor(sub)
+                }
+            };
+            let underline = |mode: u16, style: c_int| {
+                mode & !(ATTR_USTYLE as u16) | (ATTR_UNDERLINE | style) as u16
+            };
+            match attr[i] {
+                0 => {
+                    a.mode &= !(ATTR_BOLD
+                        | ATTR_FAINT
+                        | ATTR_ITALIC
+                        | ATTR_UNDERLINE
+                        | ATTR_USTYLE
+                        | ATTR_BLINK
+                        | ATTR_REVERSE
+                        | ATTR_INVISIBLE
+                        | ATTR_STRUCK) as u16;
+                    a.fg = defaultfg;
+                    a.bg = defaultbg;
+                    a.ucolor = 0;
+                }
+                1 => a.mode |= ATTR_BOLD as u16,
+                2 => a.mode |= ATTR_FAINT as u16,
+                3 => a.mode |= ATTR_ITALIC as u16,
+                4 => match sub.first() {
+                    None | Some(1) => a.mode = underline(a.mode, USTYLE_SINGLE),
+                    Some(0) => a.mode &= !(ATTR_UNDERLINE | ATTR_USTYLE) as u16,
+                    Some(2) => a.mode = underline(a.mode, USTYLE_DOUBLE),
+                    Some(3) => a.mode = underline(a.mode, USTYLE_CURLY),
+                    Some(4) => a.mode = underline(a.mode, USTYLE_DOTTED),
+                    Some(5) => a.mode = underline(a.mode, USTYLE_DASHED),
+                    Some(n) => eprintln!("erresc: underline style {n} unknown"),
+                },
+                21 => a.mode = underline(a.mode, USTYLE_DOUBLE),
+                // slow blink, rapid blink
+                5 | 6 => a.mode |= ATTR_BLINK as u16,
+                7 => a.mode |= ATTR_REVERSE as u16,
+                8 => a.mode |= ATTR_INVISIBLE as u16,
+                9 => a.mode |= ATTR_STRUCK as u16,
+                22 => a.mode &= !(ATTR_BOLD | ATTR_FAINT) as u16,
+                23 => a.mode &= !ATTR_ITALIC as u16,
+                24 => a.mode &= !(ATTR_UNDERLINE | ATTR_USTYLE) as u16,
+                25 => a.mode &= !ATTR_BLINK as u16,
+                27 => a.mode &= !ATTR_REVERSE as u16,
+                28 => a.mode &= !ATTR_INVISIBLE as u16,
+                29 => a.mode &= !ATTR_STRUCK as u16,
+                38 => {
+                    if let Some(idx) = color(&mut i) {
+                        a.fg = idx;
+                    }
+                }
+                39 => a.fg = defaultfg,
+                48 => {
+                    if let Some(idx) = color(&mut i) {
+                        a.bg = idx;
+                    }
+                }
+                49 => a.bg = defaultbg,
+                58 => {
+                    if let Some(idx) = color(&mut i) {
+                        a.ucolor = idx + 1;
+                    }
+                }
+                59 => a.ucolor = 0,
+                n @ 30..=37 => a.fg = (n - 30) as u32,
+                n @ 40..=47 => a.bg = (n - 40) as u32,
+                n @ 90..=97 => a.fg = (n - 90 + 8) as u32,
+                n @ 100..=107 => a.bg = (n - 100 + 8) as u32,
+                n => {
+                    eprintln!("erresc(default): gfx attr {n} unknown");
+                    csidump(csi);
+                }
+            }
+            i = i.max(end - 1) + 1;
+        }
+        term.c.attr = a;
+    }
+}
+
+/// Set or reset the modes in `args`, which are DEC private modes if `priv_` is
+/// set.
+fn tsetmode(priv_: bool, set: bool, args: &[c_int]) {
+    unsafe {
+        for &arg in args {
+            if !priv_ {
+                match arg {
+                    // Error (IGNORED)
+                    0 => {}
+                    2 => x::xsetmode(set, MODE_KBDLOCK),
+                    // IRM -- Insertion-replacement
+                    4 => term.mode = modbit(term.mode, set, MODE_INSERT),
+                    // SRM -- Send/Receive
+                    12 => term.mode = modbit(term.mode, !set, MODE_ECHO),
+                    // LNM -- Linefeed/new line
+                    20 => term.mode = modbit(term.mode, set, MODE_CRLF),
+                    _ => eprintln!("erresc: unknown set/reset mode {arg}"),
+                }
+                continue;
+            }
+
+            match arg {
+                // DECCKM -- Cursor key
+                1 => x::xsetmode(set, MODE_APPCURSOR),
+                // DECSCNM -- Reverse video
+                5 => x::xsetmode(set, MODE_REVERSE),
+                // DECOM -- Origin
+                6 => {
+                    term.c.state =
+                        modbit(term.c.state as c_int, set, CURSOR_ORIGIN)
+                            as c_char;
+                    tmoveato(0, 0);
+                }
+                // DECAWM -- Auto wrap
+                7 => term.mode = modbit(term.mode, set, MODE_WRAP),
+                // DECSDM -- Sixel display mode
+                80 => term.mode = modbit(term.mode, set, MODE_SIXELDISPLAY),
+                // att610 -- Start blinking cursor
+                12 => {
+                    x::xsetmode(set, MODE_CURSORBLINK);
+                    x::xsetmode(false, MODE_CURSOROFF);
+                }
+                // Error, DECANM -- ANSI/VT52, DECCOLM -- Column, DECSCLM --
+                // Scroll, DECARM -- Auto repeat, DECPFF -- Printer feed, DECPEX
+                // -- Printer extent, DECNRCM -- National characters (IGNORED)
+                0 | 2 | 3 | 4 | 8 | 18 | 19 | 42 => {}
+                // DECTCEM -- Text Cursor Enable Mode
+                25 => x::xsetmode(!set, MODE_HIDE),
+                // X10 mouse compatibility mode
+                9 => {
+                    x::xsetpointermotion(false);
+                    x::xsetmode(false, MODE_MOUSE);
+                    x::xsetmode(set, MODE_MOUSEX10);
+                }
+                // report button press
+                1000 => {
+                    x::xsetpointermotion(false);
+                    x::xsetmode(false, MODE_MOUSE);
+                    x::xsetmode(set, MODE_MOUSEBTN);
+                }
+                // report motion on button press
+                1002 => {
+                    x::xsetpointermotion(false);
+                    x::xsetmode(false, MODE_MOUSE);
+                    x::xsetmode(set, MODE_MOUSEMOTION);
+                }
+                // enable all mouse motions
+                1003 => {
+                    x::xsetpointermotion(set);
+                    x::xsetmode(false, MODE_MOUSE);
+                    x::xsetmode(set, MODE_MOUSEMANY);
+                }
+                // send focus events to tty
+                1004 => x::xsetmode(set, MODE_FOCUS),
+                // extended reporting mode
+                1006 => x::xsetmode(set, MODE_MOUSESGR),
+                1034 => x::xsetmode(set, MODE_8BIT),
+                // 1049: swap screen & set/restore cursor as xterm, 47 and 1047:
+                // swap screen, 1048: set/restore cursor
+                1049 | 47 | 1047 | 1048 => {
+                    if arg != 1048 {
+                        if allowaltscreen == 0 {
+                            continue;
+                        }
+                        if arg == 1049 {
+                            tcursor(if set {
+                                CURSOR_SAVE
+                            } else {
+                                CURSOR_LOAD
+                            });
+                        }
+                        let alt = is_set(MODE_ALTSCREEN);
+                        if alt {
+                            tclearregion(0, 0, term.col - 1, term.row - 1);
+                        }
+                        if set != alt {
+                            tswapscreen();
+                        }
+                        if arg != 1049 {
+                            continue;
+                        }
+                    }
+                    tcursor(if set { CURSOR_SAVE } else { CURSOR_LOAD });
+                }
+                // bracketed paste mode
+                2004 => x::xsetmode(set, MODE_BRCKTPASTE),
+                // synchronized output mode
+                2026 => {
+                    if set && !is_set(MODE_SYNC) {
+                        clock_gettime(CLOCK_MONOTONIC, &mut state().synctime);
+                    }
+                    term.mode = modbit(term.mode, set, MODE_SYNC);
+                }
+                // Not implemented mouse modes. 1001: mouse highlight mode; can
+                // hang the terminal by design when implemented. 1005: UTF-8
+                // mouse mode; will confuse applications not supporting UTF-8
+                // and luit. 1015: urxvt mangled mouse mode; incompatible and
+                // can be mistaken for other control codes.
+                1001 | 1005 | 1015 => {}
+                _ => eprintln!("erresc: unknown private set/reset mode {arg}"),
+            }
+        }
+    }
+}
+
+/// Reply to DECRQM with whether the mode `arg` is set, or the private mode if
+/// `priv_` is set, as `tsetmode` sets them. The private modes it ignores are
+/// reported as permanently reset.
+fn tmodereport(priv_: bool, arg: c_int) {
+    let (tmode, wmode, cstate) =
+        unsafe { (term.mode, win.mode, term.c.state as c_int) };
+    let t = |bit| Some(tmode & bit != 0);
+    let w = |bit| Some(wmode & bit != 0);
+    let set = if priv_ {
+        match arg {
+            1 => w(MODE_APPCURSOR),
+            5 => w(MODE_REVERSE),
+            12 => w(MODE_CURSORBLINK),
+            6 => Some(cstate & CURSOR_ORIGIN != 0),
+            7 => t(MODE_WRAP),
+            80 => t(MODE_SIXELDISPLAY),
+            25 => Some(wmode & MODE_HIDE == 0),
+            9 => w(MODE_MOUSEX10),
+            1000 => w(MODE_MOUSEBTN),
+            1002 => w(MODE_MOUSEMOTION),
+            1003 => w(MODE_MOUSEMANY),
+            1004 => w(MODE_FOCUS),
+            1006 => w(MODE_MOUSESGR),
+            1034 => w(MODE_8BIT),
+            47 | 1047 | 1049 => t(MODE_ALTSCREEN),
+            2004 => w(MODE_BRCKTPASTE),
+            2026 => t(MODE_SYNC),
+            _ => None,
+        }
+    } else {
+        match arg {
+            2 => w(MODE_KBDLOCK),
+            4 => t(MODE_INSERT),
+            12 => Some(tmode & MODE_ECHO == 0),
+            20 => t(MODE_CRLF),
+            _ => None,
+        }
+    };
+    // 0: not recognized, 1: set, 2: reset, 4: permanently reset
+    let value = match set {
+        Some(true) => 1,
+        Some(false) => 2,
+        // the modes tsetmode ignores
+        None if priv_ && matches!(arg, 2 | 3 | 4 | 8 | 18 | 19 | 42) => 4,
+        None => 0,
+    };
+    let p = if priv_ { "?" } else { "" };
+    ttywrite(format!("\x1b[{p}{arg};{value}$y").as_bytes(), false);
+}
+
+/// Return `x` with `bit` set or cleared depending on `set`.
+#[inline]
+fn modbit(x: c_int, set: bool, bit: c_int) -> c_int {
+    if set {
+        x | bit
+    } else {
+        x & !bit
+    }
+}
+
+/// Parse a decimal number with an optional sign at the start of `s`, returning
+/// the number and the count of bytes it took up. Out of range numbers are
+/// returned as -1.
+fn strtoi(s: &[u8]) -> (c_int, usize) {
+    let mut p = 0;
+    let neg = match s.first() {
+        Some(b'-') => {
+            p += 1;
+            true
+        }
+        Some(b'+') => {
+            p += 1;
+            false
+        }
+        _ => false,
+    };
+    let digits = s[p..].iter().take_while(|c| c.is_ascii_digit()).count();
+    if digits == 0 {
+        return (0, 0);
+    }
+    let v = s[p..p + digits].iter().fold(0_i64, |v, c| {
+        v.saturating_mul(10).saturating_add((c - b'0') as i64)
+    });
+    let v = if neg { -v } else { v };
+    (c_int::try_from(v).unwrap_or(-1), p + digits)
+}
+
+/// Return the leading number in `s`, or 0 if there is none.
+fn atoi(s: &[u8]) -> c_int {
+    strtoi(s).0
+}
+
+/// Handle the CSI sequence `csi`.
+fn csihandle(csi: &Csi) {
+    unsafe {
+        let mut arg = [0; ESC_ARG_SIZ];
+        arg[..csi.params.len()].copy_from_slice(csi.params);
+        let narg = csi.params.len();
+        let priv_ = csi.private == Some(b'?');
+        let unknown = || {
+            eprint!("erresc: unknown csi ");
+            csidump(csi);
+        };
+        let default = |a: c_int, b: c_int| if a != 0 { a } else { b };
+
+        // the kitty keyboard protocol, where CSI u without a private marker is
+        // still SCORC below
+        if let (Some(p), [], b'u') =
+            (csi.private, csi.intermediates, csi.action)
+        {
+            return keyboard::csi(p, arg[0], arg[1]);
+        }
+        // XTMODKEYS, XTQMODKEYS
+        if let (Some(b'>'), [], b'm' | b'n') | (Some(b'?'), [], b'm') =
+            (csi.private, csi.intermediates, csi.action)
+        {
+            return keyboard::modkeys(csi.private, csi.action, csi.params);
+        }
+
+        // the final characters, as st kept them
+        let mode = match *csi.intermediates {
+            _ if csi.private.is_some() && !priv_ => return unknown(),
+            [] => [csi.action, 0],
+            [c] => [c, csi.action],
+            _ => return unknown(),
+        };
+
+        match mode[0] {
+            // ICH -- Insert <n> blank char
+            b'@' => tinsertblank(default(arg[0], 1)),
+            // CUU -- Cursor <n> Up
+            b'A' => tmoveto(term.c.x, term.c.y - default(arg[0], 1)),
+            // CUD, VPR -- Cursor <n> Down
+            b'B' | b'e' => tmoveto(term.c.x, term.c.y + default(arg[0], 1)),
+            // MC -- Media Copy
+            b'i' => match arg[0] {
+                0 => tdump(),
+                1 => tdumpline(term.c.y),
+                2 => tdumpsel(),
+                4 => term.mode &= !MODE_PRINT,
+                5 => term.mode |= MODE_PRINT,
+                _ => {}
+            },
+            // DA -- Device Attributes
+            b'c' => {
+                if arg[0] == 0 {
+                    ttywrite(CStr::from_ptr(vtiden).to_bytes(), false);
+                }
+            }
+            // REP -- if last char is printable print it <n> more times
+            b'b' => {
+                if term.lastc != 0 {
+                    for _ in 0..arg[0].clamp(1, 65535) {
+                        tprinterchar(term.lastc);
+                        tprint(term.lastc);
+                    }
+                }
+            }
+            // CUF, HPR -- Cursor <n> Forward
+            b'C' | b'a' => tmoveto(term.c.x + default(arg[0], 1), term.c.y),
+            // CUB -- Cursor <n> Backward
+            b'D' => tmoveto(term.c.x - default(arg[0], 1), term.c.y),
+            // CNL -- Cursor <n> Down and first col
+            b'E' => tmoveto(0, term.c.y + default(arg[0], 1)),
+            // CPL -- Cursor <n> Up and first col
+            b'F' => tmoveto(0, term.c.y - default(arg[0], 1)),
+            // TBC -- Tabulation clear
+            b'g' => match arg[0] {
+                // clear current tab stop
+                0 => *term.tabs.offset(term.c.x as isize) = 0,
+                // clear all the tabs
+                3 => {
+                    libc::memset(
+                        term.tabs.cast(),
+                        0,
+                        term.col as usize * size_of::<c_int>(),
+                    );
+                }
+                _ => unknown(),
+            },
+            // CHA -- Move to <col>, HPA
+            b'G' | b'`' => tmoveto(default(arg[0], 1) - 1, term.c.y),
+            // CUP -- Move to <row> <col>, HVP
+            b'H' | b'f' => {
+                tmoveato(default(arg[1], 1) - 1, default(arg[0], 1) - 1)
+            }
+            // CHT -- Cursor Forward Tabulation <n> tab stops
+            b'I' => tputtab(default(arg[0], 1)),
+            // ED -- Clear screen
+            b'J' => match arg[0] {
+                // below
+                0 => {
+                    tclearregion(term.c.x, term.c.y, term.col - 1, term.c.y);
+                    if term.c.y < term.row - 1 {
+                        tclearregion(
+                            0,
+                            term.c.y + 1,
+                            term.col - 1,
+                            term.row - 1,
+                        );
+                    }
+                }
+                // above
+                1 => {
+                    if term.c.y > 0 {
+                        tclearregion(0, 0, term.col - 1, term.c.y - 1);
+                    }
+                    tclearregion(0, term.c.y, term.c.x, term.c.y);
+                }
+                // all, which also makes every line single size
+                2 => {
+                    tclearregion(0, 0, term.col - 1, term.row - 1);
+                    for y in 0..term.row {
+                        tsetlattr(y, LATTR_SINGLE);
+                    }
+                    state().sem.fill(0);
+                }
+                // scrollback
+                3 => thistclear(),
+                _ => unknown(),
+            },
+            // EL -- Clear line
+            b'K' => match arg[0] {
+                // right
+                0 => tclearregion(term.c.x, term.c.y, term.col - 1, term.c.y),
+                // left
+                1 => tclearregion(0, term.c.y, term.c.x, term.c.y),
+                // all
+                2 => tclearregion(0, term.c.y, term.col - 1, term.c.y),
+                _ => {}
+            },
+            // SU -- Scroll <n> line up, XTSMGRAPHICS -- Set or request
+            // graphics attribute
+            b'S' => {
+                if priv_ {
+                    tgraphicsattr(arg[0], arg[1]);
+                } else {
+                    tscrollup(term.top, default(arg[0], 1));
+                }
+            }
+            // SD -- Scroll <n> line down
+            b'T' => tscrolldown(term.top, default(arg[0], 1)),
+            // IL -- Insert <n> blank lines
+            b'L' => tinsertblankline(default(arg[0], 1)),
+            // RM -- Reset Mode
+            b'l' => tsetmode(priv_, false, &arg[..narg]),
+            // DL -- Delete <n> lines
+            b'M' => tdeleteline(default(arg[0], 1)),
+            // ECH -- Erase <n> char
+            b'X' => tclearregion(
+                term.c.x,
+                term.c.y,
+                term.c.x + default(arg[0], 1) - 1,
+                term.c.y,
+            ),
+            // DCH -- Delete <n> char
+            b'P' => tdeletechar(default(arg[0], 1)),
+            // CBT -- Cursor Backward Tabulation <n> tab stops
+            b'Z' => tputtab(-default(arg[0], 1)),
+            // VPA -- Move to <row>
+            b'd' => tmoveato(term.c.x, default(arg[0], 1) - 1),
+            // SM -- Set terminal mode
+            b'h' => tsetmode(priv_, true, &arg[..narg]),
+            // SGR -- Terminal attribute (color)
+            b'm' => tsetattr(csi),
+            // DSR -- Device Status Report
+            b'n' => match arg[0] {
+                // Status Report "OK" `0n`
+                5 => ttywrite(b"\x1b[0n", false),
+                // Report Cursor Position (CPR) "<row>;<column>R"
+                6 => {
+                    let buf =
+                        format!("\x1b[{};{}R", term.c.y + 1, term.c.x + 1);
+                    ttywrite(buf.as_bytes(), false);
+                }
+                _ => unknown(),
+            },
+            // DECSTBM -- Set Scrolling Region
+            b'r' => {
+                if priv_ {
+                    unknown();
+                } else {
+                    arg[0] = default(arg[0], 1);
+                    arg[1] = default(arg[1], term.row);
+                    tsetscroll(arg[0] - 1, arg[1] - 1);
+                    tmoveato(0, 0);
+                }
+            }
+            // DECSC -- Save cursor position (ANSI.SYS)
+            b's' => tcursor(CURSOR_SAVE),
+            // DECRC -- Restore cursor position (ANSI.SYS)
+            b'u' => tcursor(CURSOR_LOAD),
+            b'$' => match mode[1] {
+                // DECRQM -- Request Mode
+                b'p' => tmodereport(priv_, arg[0]),
+                _ => unknown(),
+            },
+            b' ' => match mode[1] {
+                // DECSCUSR -- Set Cursor Style
+                b'q' => {
+                    if x::xsetcursor(arg[0]) != 0 {
+                        unknown();
+                    }
+                }
+                _ => unknown(),
+            },
+            _ => unknown(),
+        }
+    }
+}
+
+/// Print the CSI sequence in `csi` to stderr.
+fn csidump(csi: &Csi) {
+    eprint!("ESC[");
+    for &c in csi.raw {
+        dumpchar(c);
+    }
+    eprintln!();
+}
+
+/// Print `c` from an escape sequence to stderr.
+fn dumpchar(c: u8) {
+    match c {
+        b' '..=b'~' => eprint!("{}", c as char),
+        b'\n' => eprint!("(\\n)"),
+        b'\r' => eprint!("(\\r)"),
+        0x1b => eprint!("(\\e)"),
+        _ => eprint!("({c:02x})"),
+    }
+}
+
+/// Reply to an OSC color query for color `index`, or color `num` of the
+/// palette if `is_osc4` is set.
+fn osc_color_response(num: c_int, index: c_int, is_osc4: bool) {
+    let (mut r, mut g, mut b) = (0, 0, 0);
+    let x = if is_osc4 { num } else { index };
+    if x::xgetcolor(x, &mut r, &mut g, &mut b) != 0 {
+        eprintln!(
+            "erresc: failed to fetch {} color {x}",
+            if is_osc4 { "osc4" } else { "osc" },
+        );
+        return;
+    }
+
+    let buf = format!(
+        "\x1b]{}{num};rgb:{r:02x}{r:02x}/{g:02x}{g:02x}/{b:02x}{b:02x}\x07",
+        if is_osc4 { "4;" } else { "" },
+    );
+    ttywrite(buf.as_bytes(), true);
+}
+
+/// Handle the OSC string with the parameters in `args`.
+fn strhandle(args: &[&[u8]]) {
+    unsafe {
+        let osc_table = [
+            (defaultfg as c_int, "foreground"),
+            (defaultbg as c_int, "background"),
+            (defaultcs as c_int, "cursor"),
+        ];
+
+        let narg = args.len();
+        let par = if narg > 0 { atoi(args[0]) } else { 0 };
+        let cstr = |s: &[u8]| CString::new(s).unwrap_or_default();
+
+        // OSC -- Operating System Command
+        match par {
+            0..=2 => {
+                if narg > 1 {
+                    let title = cstr(args[1]);
+                    if par != 1 {
+                        x::xsettitle(title.as_ptr().cast_mut());
+                    }
+                    if par != 2 {
+                        x::xseticontitle(title.as_ptr().cast_mut());
+                    }
+                }
+                return;
+            }
+            7 => {
+                if narg > 1 {
+                    tsetcwd(&args[1..].join(&b';'));
+                }
+                return;
+            }
+            133 => {
+                if narg > 1 {
+                    tsetsem(args[1], args.get(2).copied());
+                }
+                return;
+            }
+            // 9;4 is the progress report of ConEmu, which isn't shown
+            9 if narg > 1 && args[1] != b"4" => {
+                x::xnotify(b"", &args[1..].join(&b';'));
+                return;
+            }
+            9 => return,
+            777 if narg > 1 && args[1] == b"notify" => {
+                x::xnotify(
+                    args.get(2).copied().unwrap_or_default(),
+                    &args.get(3..).unwrap_or_default().join(&b';'),
+                );
+                return;
+            }
+            8 => {
+                // the URI may contain semicolons of its own
+                if narg > 1 {
+                    tsetlink(args[1], &args[2..].join(&b';'));
+                }
+                return;
+            }
+            52 => {
+                if narg > 2 {
+                    tosc52(args[1], args[2]);
+                }
+                return;
+            }
+            10..=12 if narg >= 2 => {
+                let p = args[1];
+                let (idx, name) = osc_table[(par - 10) as usize];
+                if p == b"?" {
+                    osc_color_response(par, idx, false);
+                } else if x::xsetcolorname(idx, Some(&cstr(p))) != 0 {
+                    eprintln!(
+                        "erresc: invalid {name} color: {}",
+                        String::from_utf8_lossy(p)
+                    );
+                } else {
+                    tfulldirt();
+                }
+                return;
+            }
+            // 4: color set, 104: color reset
+            4 | 104 if par == 104 || narg >= 3 => {
+                let p = if par == 4 { Some(args[2]) } else { None };
+                let j = if narg > 1 { atoi(args[1]) } else { -1 };
+
+                if p == Some(b"?") {
+                    osc_color_response(j, 0, true);
+                } else if x::xsetcolorname(j, p.map(cstr).as_deref()) != 0 {
+                    if par == 104 && narg <= 1 {
+                        // color reset without parameter
+                        x::xloadcols();
+                        return;
+                    }
+                    eprintln!(
+                        "erresc: invalid color j={j}, p={}",
+                        p.map_or("(null)".into(), String::from_utf8_lossy)
+                    );
+                } else {
+                    // TODO(st) if defaultbg color is changed, borders are
+                    // dirty
+                    tfulldirt();
+                }
+                return;
+            }
+            _ => {}
+        }
+
+        eprint!("erresc: unknown str ");
+        strdump(args);
+    }
+}
+
+/// Print the OSC string with the parameters in `args` to stderr.
+fn strdump(args: &[&[u8]]) {
+    eprint!("ESC]");
+    for (i, arg) in args.iter().enumerate() {
+        if i > 0 {
+            dumpchar(b';');
+        }
+        for &c in *arg {
+            dumpchar(c);
+        }
+    }
+    eprintln!("ESC\\");
+}
+
+/// Make the characters printed from now on part of a hyperlink to `uri`, or of
+/// none if it is empty. Links with the same `id` in the `:` separated `params`
+/// and the same URI are the same link, and are highlighted together.
+fn tsetlink(params: &[u8], uri: &[u8]) {
+    unsafe {
+        if uri.is_empty() || uri.len() > URI_MAX {
+            term.c.attr.link = 0;
+            return;
+        }
+        let id = params
+            .split(|&c| c == b':')
+            .find_map(|p| p.strip_prefix(b"id="))
+            .unwrap_or_default();
+        let key = (id.to_vec(), uri.to_vec());
+        let st = state();
+        if !st.linkids.contains_key(&key) && st.links.len() >= LINKS_MAX {
+            tgclinks();
+        }
+        let st = state();
+        term.c.attr.link = match st.linkids.get(&key) {
+            Some(&link) => link,
+            None if st.links.len() < LINKS_MAX => {
+                st.links.push(uri.to_vec());
+                st.linkids.insert(key, st.links.len() as u32);
+                st.links.len() as u32
+            }
+            None => 0,
+        };
+    }
+}
+
+/// Drop the hyperlinks that no glyph is part of anymore and number the rest
+/// again, so that new ones can be added.
+fn tgclinks() {
+    let st = unsafe { state() };
+    if st.linksgc > 0 {
+        st.linksgc -= 1;
+        return;
+    }
+    let old = std::mem::take(&mut st.links);
+    let mut used = vec![false; old.len() + 1];
+    tforeachglyph(|g| used[g.link as usize] = true);
+
+    // the new `link` value for each old one
+    let mut link = vec![0; used.len()];
+    let mut links = Vec::new();
+    for (i, uri) in old.into_iter().enumerate() {
+        if used[i + 1] {
+            links.push(uri);
+            link[i + 1] = links.len() as u32;
+        }
+    }
+    tforeachglyph(|g| g.link = link[g.link as usize]);
+    let st = unsafe { state() };
+    st.linkids.retain(|_, l| {
+        *l = link[*l as usize];
+        *l != 0
+    });
+    st.links = links;
+    st.hover = link[st.hover as usize];
+    if st.links.len() > LINKS_MAX / 2 {
+        st.linksgc = LINKS_MAX / 4;
+    }
+}
+
+/// Mark the cursor row with the OSC 133 `kind`, which is `A` for a prompt, `B`
+/// for the command typed at it, `C` for its output and `D` for its end, where
+/// `param` is the exit status of the command.
+fn tsetsem(kind: &[u8], param: Option<&[u8]>) {
+    let mark = match kind.first() {
+        Some(b'A') => SEM_PROMPT,
+        Some(b'B') => SEM_INPUT,
+        Some(b'C') => SEM_OUTPUT,
+        Some(b'D') => SEM_END,
+        _ => return,
+    };
+    let st = unsafe { state() };
+    unsafe {
+        st.sem[term.c.y as usize] |= mark;
+    }
+    if mark == SEM_END {
+        st.status = param.map(atoi);
+    }
+}
+
+/// Return the `SEM_*` marks of the line displayed on row `y`, which may be
+/// above or below the view for as far as there are lines, like [`tlattr`].
+pub(crate) fn tsem(y: c_int) -> u8 {
+    unsafe {
+        let st = state();
+        if y < term.scr {
+            let i = (term.histi + y - term.scr + term.histmax) % term.histmax;
+            st.histsem[i as usize]
+        } else {
+            st.sem[(y - term.scr) as usize]
+        }
+    }
+}
+
+/// Return the rows of the oldest line in the history and the bottom line of
+/// the screen, relative to the view.
+fn tlines() -> (c_int, c_int) {
+    unsafe { (term.scr - term.histn, term.scr + term.row - 1) }
+}
+
+/// A position on the terminal as a row and a column, where the rows are
+/// counted from the top of the screen as if it were not scrolled back, so they
+/// are negative in the history.
+pub(crate) type Pos = (c_int, c_int);
+
+/// The text of a line joined with the rows it wraps onto, for looking for
+/// things in it.
+pub(crate) struct LineText {
+    pub(crate) text: String,
+    /// the byte offset in `text` at which each cell starts, and its position
+    cells: Vec<(usize, Pos)>,
+}
+
+impl LineText {
+    /// Return the position of the cell the byte at `offset` of the text is
+    /// part of.
+    pub(crate) fn cell(&self, offset: usize) -> Pos {
+        let i = self.cells.partition_point(|&(o, _)| o <= offset);
+        self.cells[i - 1].1
+    }
+}
+
+/// Return the text of the line starting on row `y`, a [`Pos`] row, with the
+/// rows it wraps onto, and the row after them.
+pub(crate) fn tlinetext(mut y: c_int) -> (LineText, c_int) {
+    let mut line = LineText { text: String::new(), cells: Vec::new() };
+    unsafe {
+        loop {
+            let l = Term::tline(&raw mut term, y + term.scr);
+            for x in 0..tlinelen(l, term.col) {
+                let g = &*l.offset(x as isize);
+                if g.mode & ATTR_WDUMMY as u16 != 0 {
+                    continue;
+                }
+                line.cells.push((line.text.len(), (y, x)));
+                line.text.extend(std::iter::once(g.u).chain(gmarks(g)).map(
+                    |u| {
+                        char::from_u32(u).unwrap_or(char::REPLACEMENT_CHARACTER)
+                    },
+                ));
+            }
+            y += 1;
+            if !twrapped(y - 1) || y >= term.row {
+                return (line, y);
+            }
+        }
+    }
+}
+
+/// Return the row the line on row `y` starts on, going back over the rows
+/// wrapping onto the next, with rows counted like in [`Pos`].
+pub(crate) fn tlinestart(mut y: c_int) -> c_int {
+    let first = unsafe { tlines().0 - term.scr };
+    while y > first && twrapped(y - 1) {
+        y -= 1;
+    }
+    y
+}
+
+/// Return whether row `y`, counted like in [`Pos`], wraps onto the next.
+fn twrapped(y: c_int) -> bool {
+    unsafe {
+        let l = Term::tline(&raw mut term, y + term.scr);
+        term.col > 0
+            && (*l.offset(term.col as isize - 1)).mode & ATTR_WRAP as u16 != 0
+    }
+}
+
+/// Scroll the view to put the previous prompt at its top if `a.i` is negative,
+/// or else the next one, as far as the history goes.
+pub(crate) fn kscrollprompt(a: &Arg) {
+    let (first, last) = tlines();
+    let prompt = |y: &c_int| tsem(*y) & SEM_PROMPT != 0;
+    unsafe {
+        if a.i < 0 {
+            if let Some(y) = (first..0).rev().find(prompt) {
+                kscrollup(&Arg { i: -y });
+            }
+        } else if let Some(y) = (1..=last).find(prompt) {
+            kscrolldown(&Arg { i: y });
+        }
+    }
+}
+
+/// Select the output of the last command that finished, scrolling back to its
+/// start, and copy it into the primary selection.
+pub(crate) fn selcmdoutput(_: &Arg) {
+    let (first, last) = tlines();
+    let Some(end) = (first..=last).rev().find(|&y| tsem(y) & SEM_END != 0)
+    else {
+        return;
+    };
+    let Some(start) = (first..=end).rev().find(|&y| tsem(y) & SEM_OUTPUT != 0)
+    else {
+        return;
+    };
+    if start == end {
+        return;
+    }
+    unsafe {
+        let scr = term.scr;
+        if start < 0 {
+            kscrollup(&Arg { i: -start });
+        } else {
+            kscrolldown(&Arg { i: start });
+        }
+        let n = term.scr - scr;
+
+        selclear();
+        sel.mode = SEL_READY;
+        sel.type_ = SEL_REGULAR;
+        sel.snap = 0;
+        sel.alt = is_set(MODE_ALTSCREEN) as c_int;
+        // the end may be below the view, which getsel can still reach
+        (sel.ob.x, sel.ob.y) = (0, start + n);
+        (sel.oe.x, sel.oe.y) = (term.col - 1, end + n - 1);
+        selnormalize();
+        tfulldirt();
+        if let Some(text) = getsel() {
+            x::xsetsel(&text);
+        }
+    }
+}
+
+/// Record the working directory reported with OSC 7 as the URI
+/// `file://host/path`. Directories on other hosts are forgotten, since a new
+/// window could not start in them.
+fn tsetcwd(uri: &[u8]) {
+    let st = unsafe { state() };
+    st.cwd = None;
+    let Some(rest) = uri.strip_prefix(b"file://") else {
+        return;
+    };
+    let (host, path) = rest
+        .split_at(rest.iter().position(|&c| c == b'/').unwrap_or(rest.len()));
+    if !host.is_empty() && host != b"localhost" && host != hostname() {
+        return;
+    }
+    if let Some(path) = percentdecode(path).filter(|p| !p.is_empty()) {
+        st.cwd = Some(PathBuf::from(std::ffi::OsString::from_vec(path)));
+    }
+}
+
+/// Return the name of this host, as used in the URIs of OSC 7.
+fn hostname() -> Vec<u8> {
+    let mut buf = [0u8; 256];
+    unsafe {
+        if libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) < 0 {
+            return Vec::new();
+        }
+    }
+    let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+    buf[..len].to_vec()
+}
+
+/// Decode the `%XX` escapes in the URI component `s`, or return `None` if one
+/// of them is malformed.
+fn percentdecode(s: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        if s[i] != b'%' {
+            out.push(s[i]);
+            i += 1;
+            continue;
+        }
+        let hex = s.get(i + 1..i + 3)?;
+        if !hex.iter().all(u8::is_ascii_hexdigit) {
+            return None;
+        }
+        out.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+        i += 3;
+    }
+    Some(out)
+}
+
+/// Start another rt window in the working directory last reported with OSC 7,
+/// or else in the current directory of the shell.
+pub(crate) fn newterm(_: &Arg) {
+    let st = unsafe { state() };
+    let dir = st.cwd.clone().or_else(|| {
+        (st.pid > 0)
+            .then(|| std::fs::read_link(format!("/proc/{}/cwd", st.pid)).ok())
+            .flatten()
+    });
+    let exe = std::env::current_exe().unwrap_or_else(|_| "rt".into());
+    let mut cmd = Command::new(exe);
+    if let Some(dir) = dir.filter(|d| d.is_dir()) {
+        cmd.current_dir(dir);
+    }
+    let fds = unsafe { [cmdfd, iofd] };
+    unsafe {
+        cmd.pre_exec(move || {
+            // don't keep this window's tty open, and don't die with it
+            for fd in fds.into_iter().filter(|&fd| fd > 2) {
+                libc::close(fd);
+            }
+            libc::setsid();
+            Ok(())
+        });
+    }
+    x::spawncmd(&mut cmd, || {});
+}
+
+/// Set the selections named by `targets` to the base64 encoded `data`, or reply
+/// with the contents of the first of them if `data` is `?`, as far as
+/// `OSC52WRITE` and `OSC52READ` allow. The targets are `c` for the clipboard
+/// and `p` or `s` for the primary selection, and others are ignored. Without
+/// any, both are set, or the clipboard is read.
+fn tosc52(targets: &[u8], data: &[u8]) {
+    let mut clips: Vec<Clip> = targets
+        .iter()
+        .filter_map(|c| match c {
+            b'c' => Some(Clip::Clipboard),
+            b'p' | b's' => Some(Clip::Primary),
+            _ => None,
+        })
+        .collect();
+    if data == b"?" {
+        let clip = clips.first().copied().unwrap_or(Clip::Clipboard);
+        tpermit(
+            config::OSC52READ,
+            "Let the program read the clipboard? (y/n)",
+            move || x::xgetclip(clip),
+        );
+        return;
+    }
+    if clips.is_empty() {
+        clips = vec![Clip::Clipboard, Clip::Primary];
+    }
+    let data = base64dec(data);
+    tpermit(
+        config::OSC52WRITE,
+        "Let the program set the clipboard? (y/n)",
+        move || {
+            for clip in clips {
+                x::xsetclip(clip, &data);
+            }
+        },
+    );
+}
+
+/// Reply to an OSC 52 query with the contents `data` of the selection `clip`.
+pub(crate) fn tclipreply(clip: Clip, data: &[u8]) {
+    let c = match clip {
+        Clip::Clipboard => 'c',
+        Clip::Primary => 'p',
+    };
+    let reply = format!("\x1b]52;{c};{}\x07", base64enc(data));
+    ttywrite(reply.as_bytes(), false);
+}
+
+/// Run `f` if `policy` allows it, asking the user `question` first if it says
+/// to. Without a window there is nobody to ask, and `f` is not run.
+fn tpermit(policy: Policy, question: &str, f: impl FnOnce() + 'static) {
+    match policy {
+        Policy::Deny => {}
+        Policy::Allow => f(),
+        Policy::Prompt => unsafe {
+            if xw.dpy.is_null() {
+                return;
+            }
+            // a new question replaces one that was not answered yet
+            state().prompt = Some(Prompt {
+                text: question.to_owned(),
+                answer: Box::new(move |yes| {
+                    if yes {
+                        f();
+                    }
+                }),
+            });
+        },
+    }
+}
+
+/// Show `text` in the bottom row of the window for `MESSAGETIMEOUT`, or print
+/// it to stderr without a window.
+pub(crate) fn tmessage(text: String) {
+    unsafe {
+        if xw.dpy.is_null() {
+            eprintln!("{text}");
+            return;
+        }
+        let mut now = MaybeUninit::uninit();
+        clock_gettime(CLOCK_MONOTONIC, now.as_mut_ptr());
+        state().message = Some((text, now.assume_init()));
+        tfulldirt();
+    }
+}
+
+/// Answer the question of the prompt shown in the bottom row, if any.
+pub(crate) fn tanswer(yes: bool) {
+    if let Some(prompt) = unsafe { state() }.prompt.take() {
+        tfulldirt();
+        (prompt.answer)(yes);
+    }
+}
+
+/// Handle the DCS string `dcs`.
+fn dcshandle(dcs: &Dcs) {
+    // DECSIXEL -- Sixel graphics. Other DCS strings are ignored.
+    if let ([], b'q') = (dcs.intermediates, dcs.action) {
+        let bg = export::colorrgb(unsafe { defaultbg });
+        let max = sixelmax();
+        if let Some(image) = sixel::decode(dcs.params, dcs.data, bg, max) {
+            timage(Rc::new(image));
+        }
+    }
+}
+
+/// Handle the APC string `data`.
+fn apchandle(data: &[u8]) {
+    // kitty graphics protocol. Other APC strings are ignored.
+    if data.first() == Some(&b'G') {
+        kitty::handle(data);
+    }
+}
+
+/// Show the sixel `image` at the cursor, which moves to the line below it,
+/// scrolling the screen if needed. In sixel display mode it is shown at the
+/// top left corner instead, and the cursor stays put. It is dropped if there
+/// is no room for it among the images shown.
+fn timage(image: Rc<Image>) {
+    if !graphics::reserve(image.size()) {
+        return;
+    }
+    unsafe {
+        let rows = graphics::rows(&image);
+        let placement = Placement::new(0, 0, 0);
+        if is_set(MODE_SIXELDISPLAY) {
+            for row in 0..rows.min(term.row) {
+                graphics::attach(&image, placement, 0, row, row);
+            }
+            return;
+        }
+        for row in 0..rows {
+            if row > 0 {
+                tnewline(false);
+            }
+            graphics::attach(&image, placement, term.c.x, term.c.y, row);
+        }
+        tnewline(false);
+    }
+}
+
+/// Return the largest width and height of a sixel image, which is cropped to
+/// the size of the screen.
+fn sixelmax() -> (usize, usize) {
+    let (cw, ch) = graphics::cellsize();
+    let (col, row) = unsafe { (term.col as usize, term.row as usize) };
+    (
+        (col * cw).min(sixel::SIXEL_MAX),
+        (row * ch).min(sixel::SIXEL_MAX),
+    )
+}
 
-        for _ in 0..2 {
-            tmoveto(0, 0);
-            tcursor(CURSOR_SAVE);
-            tclearregion(0, 0, term.col - 1, term.row - 1);
-            tswapscreen();
+/// Reply to a request for the graphics attribute `item`, the number of color
+/// registers (1) or the largest sixel image (2), with the `action` to read,
+/// reset, set, or read the maximum of it. The attributes are fixed, so they
+/// are always reported as they are.
+fn tgraphicsattr(item: c_int, action: c_int) {
+    let reply = match (item, action) {
+        (1, 1..=4) => format!("\x1b[?1;0;{}S", sixel::REGISTERS),
+        (2, 1..=4) => {
+            let (width, height) = sixelmax();
+            format!("\x1b[?2;0;{width};{height}S")
         }
-    }
+        (1 | 2, _) => format!("\x1b[?{item};2S"),
+        _ => format!("\x1b[?{item};1S"),
+    };
+    ttywrite(reply.as_bytes(), false);
 }
 
-/// Move the cursor to `x, y`, clamping to the dimensions of the window.
-pub fn tmoveto(x: c_int, y: c_int) {
+/// Write `s` to the `iofd` printer, closing it on errors.
+fn tprinter(s: &[u8]) {
     unsafe {
-        let (miny, maxy) = if term.c.state & CURSOR_ORIGIN as i8 != 0 {
-            (term.top, term.bot)
-        } else {
-            (0, term.row - 1)
-        };
-        term.c.state &= !CURSOR_WRAPNEXT as i8;
-        term.c.x = x.clamp(0, term.col - 1);
-        term.c.y = y.clamp(miny, maxy);
+        if iofd != -1 && xwrite(iofd, s) < 0 {
+            eprintln!("Error writing to output file: {}", strerrno());
+            libc::close(iofd);
+            iofd = -1;
+        }
     }
 }
 
-#[inline]
-fn is_set(flag: c_int) -> bool {
-    unsafe { term.mode & flag != 0 }
+/// Print the selection.
+fn tdumpsel() {
+    if let Some(s) = getsel() {
+        tprinter(&s);
+    }
 }
 
-/// Load or save cursor state depending on the value of `mode`, which should be
-/// either `CURSOR_SAVE` or `CURSOR_LOAD`.
-pub fn tcursor(mode: c_int) {
-    static mut C: [TCursor; 2] = [TCursor {
-        attr: Glyph_ { u: 0, mode: 0, fg: 0, bg: 0 },
-        x: 0,
-        y: 0,
-        state: 0,
-    }; 2];
-    let alt = is_set(MODE_ALTSCREEN) as usize;
+/// Print line `n` of the screen.
+fn tdumpline(n: c_int) {
     unsafe {
-        if mode == CURSOR_SAVE {
-            C[alt] = term.c;
-        } else if mode == CURSOR_LOAD {
-            term.c = C[alt];
-            tmoveto(C[alt].x, C[alt].y);
+        let mut buf = [0; UTF_SIZ];
+        let line = *term.line.offset(n as isize);
+        let len = tlinelen(line, term.col).min(term.col);
+        if len != 1 || (*line).u != ' ' as Rune {
+            for x in 0..len {
+                let g = &*line.offset(x as isize);
+                for u in std::iter::once(g.u).chain(gmarks(g)) {
+                    let len = utf8encode(u, &mut buf);
+                    tprinter(&buf[..len]);
+                }
+            }
         }
+        tprinter(b"\n");
     }
 }
 
-/// Clear the region defined by the provided coordinates, clamping to the bounds
-/// of the window.
-///
-/// If the bounds are provided in the "wrong" order (eg x1 > x2), these are
-/// swapped as well.
-pub fn tclearregion(
-    mut x1: c_int,
-    mut y1: c_int,
-    mut x2: c_int,
-    mut y2: c_int,
-) {
+/// Print the whole screen.
+fn tdump() {
     unsafe {
-        if x1 > x2 {
-            std::mem::swap(&mut x1, &mut x2);
-        }
-        if y1 > y2 {
-            std::mem::swap(&mut y1, &mut y2);
+        for i in 0..term.row {
+            tdumpline(i);
         }
+    }
+}
 
-        let x1 = x1.clamp(0, term.col - 1);
-        let x2 = x2.clamp(0, term.col - 1);
-        let y1 = y1.clamp(0, term.row - 1);
-        let y2 = y2.clamp(0, term.row - 1);
+/// Move the cursor forward over `n` tab stops, or backward if `n` is negative.
+fn tputtab(mut n: c_int) {
+    unsafe {
+        let mut x = term.c.x;
+        let col = tlinecols(term.c.y);
+        let tab = |x: c_int| *term.tabs.offset(x as isize) != 0;
 
-        for y in y1..=y2 {
-            *term.dirty.offset(y as isize) = 1;
-            for x in x1..=x2 {
-                let gp: &mut Glyph_ =
-                    &mut *(*term.line.offset(y as isize)).offset(x as isize);
-                if selected(x, y) != 0 {
-                    selclear();
+        if n > 0 {
+            while x < col && n > 0 {
+                n -= 1;
+                x += 1;
+                while x < col && !tab(x) {
+                    x += 1;
+                }
+            }
+        } else if n < 0 {
+            while x > 0 && n < 0 {
+                n += 1;
+                x -= 1;
+                while x > 0 && !tab(x) {
+                    x -= 1;
                 }
-                gp.fg = term.c.attr.fg;
-                gp.bg = term.c.attr.bg;
-                gp.mode = 0;
-                gp.u = ' ' as u32;
             }
         }
+        term.c.x = x.clamp(0, col - 1);
     }
 }
 
-/// Return whether or not the character at `x`,`y` is selected.
-///
-/// TODO bool
-fn selected(x: c_int, y: c_int) -> c_int {
+fn tdefutf8(ascii: u8) {
     unsafe {
-        if sel.mode == SEL_EMPTY
-            || sel.ob.x == -1
-            || sel.alt != is_set(MODE_ALTSCREEN) as c_int
-        {
-            return 0;
-        }
-        if sel.type_ == SEL_RECTANGULAR {
-            return (between(y, sel.nb.y, sel.ne.y)
-                && between(x, sel.nb.y, sel.ne.x)) as c_int;
+        if ascii == b'G' {
+            term.mode |= MODE_UTF8;
+        } else if ascii == b'@' {
+            term.mode &= !MODE_UTF8;
         }
+    }
+}
 
-        (between(y, sel.nb.y, sel.ne.y)
-            && (y != sel.nb.y || x >= sel.nb.x)
-            && (y != sel.ne.y || x <= sel.ne.x)) as c_int
+/// Designate the charset `ascii` to the charset being set in `term.icharset`.
+fn tdeftran(ascii: u8) {
+    unsafe {
+        let cs = match ascii {
+            b'0' => CS_GRAPHIC0,
+            b'B' => CS_USA,
+            _ => {
+                eprintln!("esc unhandled charset: ESC ( {}", ascii as char);
+                return;
+            }
+        };
+        term.trantbl[term.icharset as usize] = cs as c_char;
     }
 }
 
-/// Clear the current selection.
-fn selclear() {
+/// Return the number of columns that can be written on row `y` of the screen,
+/// which is half the width of the terminal on double size lines.
+fn tlinecols(y: c_int) -> c_int {
     unsafe {
-        if sel.ob.x == -1 {
+        match state().lattr.get(y as usize) {
+            Some(&LATTR_SINGLE) | None => term.col,
+            Some(_) => (term.col / 2).max(1),
+        }
+    }
+}
+
+/// Set the size attribute of row `y` of the screen to `attr`, one of the
+/// `LATTR_*` constants, keeping the cursor within the columns left on it.
+fn tsetlattr(y: c_int, attr: u8) {
+    unsafe {
+        let lattr = &mut state().lattr[y as usize];
+        if *lattr == attr {
             return;
         }
-        sel.mode = SEL_IDLE;
-        sel.ob.x = -1;
-        tsetdirt(sel.nb.y, sel.ne.y);
+        *lattr = attr;
+        tsetdirt(y, y);
+        if y == term.c.y {
+            term.c.x = term.c.x.min(tlinecols(y) - 1);
+        }
     }
 }
 
-/// Mark the rows between `top` and `bot` dirty.
-fn tsetdirt(top: c_int, bot: c_int) {
+/// Return the size attribute of the line displayed on row `y`, which comes from
+/// the history when the view is scrolled back, like [`Term::tline`].
+pub(crate) fn tlattr(y: c_int) -> u8 {
     unsafe {
-        let top = top.clamp(0, term.row - 1);
-        let bot = bot.clamp(0, term.row - 1);
+        let st = state();
+        if y < term.scr {
+            let i = (term.histi + y - term.scr + term.histmax) % term.histmax;
+            st.histlattr[i as usize]
+        } else {
+            st.lattr[(y - term.scr) as usize]
+        }
+    }
+}
 
-        for i in top..=bot {
-            // TODO term.dirty is obviously a dynamic array of bool with size
-            // equal to the number of rows in the terminal. thus, refactoring
-            // can happen in two steps: first, make *mut bool, then make it a
-            // vec that can be resized instead of xrealloc in C
-            *term.dirty.offset(i as isize) = 1;
+fn tdectest(c: u8) {
+    unsafe {
+        match c {
+            // DECDHL -- double height line, top and bottom half
+            b'3' => tsetlattr(term.c.y, LATTR_DTOP),
+            b'4' => tsetlattr(term.c.y, LATTR_DBOTTOM),
+            // DECSWL -- single width line
+            b'5' => tsetlattr(term.c.y, LATTR_SINGLE),
+            // DECDWL -- double width line
+            b'6' => tsetlattr(term.c.y, LATTR_DWIDTH),
+            // DECALN -- screen alignment test
+            b'8' => {
+                for y in 0..term.row {
+                    tsetlattr(y, LATTR_SINGLE);
+                }
+                for x in 0..term.col {
+                    for y in 0..term.row {
+                        tsetchar('E' as Rune, term.c.attr, x, y);
+                    }
+                }
+            }
+            _ => {}
         }
     }
 }
 
-/// Swap the current and alt screens and mark the whole terminal dirty;
-pub fn tswapscreen() {
+/// Handle the C0 or C1 control character `ascii`.
+fn tcontrolcode(ascii: u8) {
     unsafe {
-        (term.line, term.alt) = (term.alt, term.line);
-        term.mode ^= MODE_ALTSCREEN;
-        tfulldirt();
+        match ascii {
+            // HT
+            b'\t' => tputtab(1),
+            // BS
+            0x08 => tmoveto(term.c.x - 1, term.c.y),
+            // CR
+            b'\r' => tmoveto(0, term.c.y),
+            // FF, VT, LF: go to first col if the mode is set
+            0x0c | 0x0b | b'\n' => tnewline(is_set(MODE_CRLF)),
+            // BEL
+            0x07 => x::xbell(),
+            // SO (LS1 -- Locking shift 1), SI (LS0 -- Locking shift 0)
+            0x0e | 0x0f => term.charset = 1 - (ascii - 0x0e) as c_int,
+            // SUB
+            0x1a => tsetchar('?' as Rune, term.c.attr, term.c.x, term.c.y),
+            // CAN, ENQ, NUL, XON, XOFF, DEL (IGNORED)
+            0x18 | 0x05 | 0x00 | 0x11 | 0x13 | 0x7f => {}
+            // NEL -- Next line: always go to first col
+            0x85 => tnewline(true),
+            // HTS -- Horizontal tab stop
+            0x88 => *term.tabs.offset(term.c.x as isize) = 1,
+            // DECID -- Identify Terminal
+            0x9a => ttywrite(CStr::from_ptr(vtiden).to_bytes(), false),
+            // TODO(st): PAD, HOP, BPH, NBH, IND, SSA, ESA, HTJ, VTS, PLD, PLU,
+            // RI, SS2, SS3, PU1, PU2, STS, CCH, MW, SPA, EPA, SOS, SGCI
+            _ => {}
+        }
     }
 }
 
-/// Mark the whole terminal dirty.
-fn tfulldirt() {
+/// Handle the escape sequence ESC `intermediates` `ascii`.
+fn eschandle(intermediates: &[u8], ascii: u8) {
     unsafe {
-        tsetdirt(0, term.row - 1);
+        match *intermediates {
+            [] => {}
+            // GZD4, G1D4, G2D4, G3D4 -- set primary, secondary, tertiary or
+            // quaternary charset
+            [c @ b'('..=b'+'] => {
+                term.icharset = (c - b'(') as c_int;
+                return tdeftran(ascii);
+            }
+            // DECALN -- DEC screen alignment test
+            [b'#'] => return tdectest(ascii),
+            // select UTF-8 or the default character set
+            [b'%'] => return tdefutf8(ascii),
+            _ => {
+                return eprintln!(
+                    "erresc: unknown sequence ESC {} 0x{ascii:02X}",
+                    String::from_utf8_lossy(intermediates)
+                );
+            }
+        }
+        match ascii {
+            // LS2 -- Locking shift 2, LS3 -- Locking shift 3
+            b'n' | b'o' => term.charset = 2 + (ascii - b'n') as c_int,
+            // IND -- Linefeed
+            b'D' => {
+                if term.c.y == term.bot {
+                    tscrollup(term.top, 1);
+                } else {
+                    tmoveto(term.c.x, term.c.y + 1);
+                }
+            }
+            // NEL -- Next line: always go to first col
+            b'E' => tnewline(true),
+            // HTS -- Horizontal tab stop
+            b'H' => *term.tabs.offset(term.c.x as isize) = 1,
+            // RI -- Reverse index
+            b'M' => {
+                if term.c.y == term.top {
+                    tscrolldown(term.top, 1);
+                } else {
+                    tmoveto(term.c.x, term.c.y - 1);
+                }
+            }
+            // DECID -- Identify Terminal
+            b'Z' => ttywrite(CStr::from_ptr(vtiden).to_bytes(), false),
+            // RIS -- Reset to initial state
+            b'c' => {
+                treset();
+                resettitle();
+                x::xloadcols();
+                x::xsetmode(false, MODE_HIDE);
+            }
+            // DECPAM -- Application keypad
+            b'=' => x::xsetmode(true, MODE_APPKEYPAD),
+            // DECPNM -- Normal keypad
+            b'>' => x::xsetmode(false, MODE_APPKEYPAD),
+            // DECSC -- Save Cursor
+            b'7' => tcursor(CURSOR_SAVE),
+            // DECRC -- Restore Cursor
+            b'8' => tcursor(CURSOR_LOAD),
+            // ST -- String Terminator, without a string to end
+            b'\\' => {}
+            _ => eprintln!(
+                "erresc: unknown sequence ESC 0x{ascii:02X} '{}'",
+                if ascii.is_ascii_graphic() || ascii == b' ' {
+                    ascii as char
+                } else {
+                    '.'
+                }
+            ),
+        }
+    }
+}
+
+#[inline]
+fn iscontrolc0(c: Rune) -> bool {
+    between(c, 0, 0x1f) || c == 0x7f
+}
+
+#[inline]
+fn iscontrolc1(c: Rune) -> bool {
+    between(c, 0x80, 0x9f)
+}
+
+#[inline]
+fn iscontrol(c: Rune) -> bool {
+    iscontrolc0(c) || iscontrolc1(c)
+}
+
+const UTFBYTE: [u8; UTF_SIZ + 1] = [0x80, 0, 0xC0, 0xE0, 0xF0];
+const UTFMASK: [u8; UTF_SIZ + 1] = [0xC0, 0x80, 0xE0, 0xF0, 0xF8];
+const UTFMIN: [Rune; UTF_SIZ + 1] = [0, 0, 0x80, 0x800, 0x10000];
+const UTFMAX: [Rune; UTF_SIZ + 1] = [0x10FFFF, 0x7F, 0x7FF, 0xFFFF, 0x10FFFF];
+
+/// Decode the UTF-8 character at the start of `c` into `u`, returning its
+/// length in bytes, or 0 if `c` ends before the character does. Invalid
+/// sequences are decoded as `UTF_INVALID`.
+pub fn utf8decode(c: &[u8], u: &mut Rune) -> usize {
+    *u = UTF_INVALID;
+    let Some(&first) = c.first() else {
+        return 0;
+    };
+    let (mut udecoded, len) = utf8decodebyte(first);
+    if !between(len, 1, UTF_SIZ) {
+        return 1;
+    }
+    let mut j = 1;
+    while j < c.len() && j < len {
+        let (d, type_) = utf8decodebyte(c[j]);
+        udecoded = (udecoded << 6) | d;
+        if type_ != 0 {
+            return j;
+        }
+        j += 1;
+    }
+    if j < len {
+        return 0;
+    }
+    *u = udecoded;
+    utf8validate(u, len);
+
+    len
+}
+
+fn utf8decodebyte(c: u8) -> (Rune, usize) {
+    for (i, (&mask, &byte)) in UTFMASK.iter().zip(&UTFBYTE).enumerate() {
+        if c & mask == byte {
+            return ((c & !mask) as Rune, i);
+        }
+    }
+    (0, UTFMASK.len())
+}
+
+/// Encode `u` as UTF-8 into `c`, returning the number of bytes written.
+pub fn utf8encode(mut u: Rune, c: &mut [u8; UTF_SIZ]) -> usize {
+    let len = utf8validate(&mut u, 0);
+    if len > UTF_SIZ {
+        return 0;
+    }
+
+    for i in (1..len).rev() {
+        c[i] = utf8encodebyte(u, 0);
+        u >>= 6;
+    }
+    c[0] = utf8encodebyte(u, len);
+
+    len
+}
+
+fn utf8encodebyte(u: Rune, i: usize) -> u8 {
+    UTFBYTE[i] | (u as u8 & !UTFMASK[i])
+}
+
+/// Replace `u` with `UTF_INVALID` if it is not a valid character of the
+/// encoded length `i`, returning its encoded length.
+fn utf8validate(u: &mut Rune, i: usize) -> usize {
+    if !between(*u, UTFMIN[i], UTFMAX[i]) || between(*u, 0xD800, 0xDFFF) {
+        *u = UTF_INVALID;
+    }
+    let mut i = 1;
+    while *u > UTFMAX[i] {
+        i += 1;
+    }
+
+    i
+}
+
+/// Encode `src` as base64, with padding.
+fn base64enc(src: &[u8]) -> String {
+    const DIGITS: &[u8; 64] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut dst = String::with_capacity(src.len().div_ceil(3) * 4);
+    for chunk in src.chunks(3) {
+        let n = chunk.iter().fold(0u32, |n, &c| n << 8 | c as u32)
+            << (8 * (3 - chunk.len()));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                dst.push(DIGITS[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                dst.push('=');
+            }
+        }
+    }
+    dst
+}
+
+/// Decode the base64 in `src`, skipping any non-printable characters.
+fn base64dec(src: &[u8]) -> Vec<u8> {
+    let digit = |c: u8| match c {
+        b'A'..=b'Z' => (c - b'A') as i32,
+        b'a'..=b'z' => (c - b'a' + 26) as i32,
+        b'0'..=b'9' => (c - b'0' + 52) as i32,
+        b'+' => 62,
+        b'/' => 63,
+        b'=' => -1,
+        _ => 0,
+    };
+    let mut src = src.iter().copied().filter(|&c| between(c, b' ', b'~'));
+    let mut getc = || digit(src.next().unwrap_or(b'='));
+
+    let mut dst = Vec::new();
+    loop {
+        let (a, b, c, d) = (getc(), getc(), getc(), getc());
+
+        // invalid input. 'b' can be -1 only if the input is truncated
+        if a == -1 || b == -1 {
+            break;
+        }
+        dst.push(((a << 2) | ((b & 0x30) >> 4)) as u8);
+        if c == -1 {
+            break;
+        }
+        dst.push((((b & 0x0f) << 4) | ((c & 0x3c) >> 2)) as u8);
+        if d == -1 {
+            break;
+        }
+        dst.push((((c & 0x03) << 6) | d) as u8);
     }
+
+    dst
+}
+
+/// Write all of `s` to `fd`, returning the number of bytes written or -1 on
+/// errors.
+fn xwrite(fd: c_int, s: &[u8]) -> isize {
+    let mut aux = 0;
+    while aux < s.len() {
+        let r =
+            unsafe { libc::write(fd, s[aux..].as_ptr().cast(), s.len() - aux) };
+        if r < 0 {
+            return r;
+        }
+        aux += r as usize;
+    }
+
+    aux as isize
 }
 
 pub fn xinit(cols: c_int, rows: c_int) {
@@ -503,17 +3646,16 @@ pub fn xinit(cols: c_int, rows: c_int) {
         }
         xw.scr = bindgen::XDefaultScreen(xw.dpy);
         xw.vis = bindgen::XDefaultVisual(xw.dpy, xw.scr);
+        // held keys send presses without releases in between, which tells
+        // repeats apart for the kitty keyboard protocol
+        bindgen::XkbSetDetectableAutoRepeat(xw.dpy, True as c_int, null_mut());
 
         // font
         if FcInit() == 0 {
             die!("could not init fontconfig");
         }
 
-        usedfont = if opt_font.is_null() {
-            font.as_ptr() as *const i8
-        } else {
-            opt_font
-        };
+        usedfont = if opt_font.is_null() { font } else { opt_font };
         x::xloadfonts(usedfont, 0.0);
 
         // colors
@@ -543,6 +3685,9 @@ pub fn xinit(cols: c_int, rows: c_int) {
             | bindgen::ButtonMotionMask
             | bindgen::ButtonPressMask
             | bindgen::ButtonReleaseMask) as i64;
+        if config::LINKHOVER {
+            xw.attrs.event_mask |= bindgen::PointerMotionMask as i64;
+        }
         xw.attrs.colormap = xw.cmap;
 
         let root = bindgen::XRootWindow(xw.dpy, xw.scr);
@@ -719,7 +3864,11 @@ pub fn xinit(cols: c_int, rows: c_int) {
             1,
         );
 
-        win.mode = MODE_NUMLOCK;
+        // resizes go through tresize here, which reflows the screen
+        handler[ConfigureNotify as usize] = Some(x::resize);
+
+        // whether the cursor blinks was set with its shape before
+        win.mode = MODE_NUMLOCK | win.mode & MODE_CURSORBLINK;
         resettitle();
         x::xhints();
         bindgen::XMapWindow(xw.dpy, xw.win);
@@ -760,6 +3909,7 @@ pub fn run() {
         let mut seltv = timespec { tv_sec: 0, tv_nsec: 0 };
         let mut now = timespec { tv_sec: 0, tv_nsec: 0 };
         let mut lastblink = timespec { tv_sec: 0, tv_nsec: 0 };
+        let mut lastcursor = timespec { tv_sec: 0, tv_nsec: 0 };
         let mut trigger = timespec { tv_sec: 0, tv_nsec: 0 };
         let mut tv: *mut timespec;
         // emulating do while, waiting for window mapping
@@ -782,7 +3932,7 @@ pub fn run() {
         }
 
         let ttyfd = ttynew(opt_line, shell, opt_io, opt_cmd);
-        bindgen::cresize(w, h);
+        x::cresize(w, h);
 
         let mut timeout = -1;
         let mut drawing = false;
@@ -816,8 +3966,12 @@ pub fn run() {
             }
             clock_gettime(CLOCK_MONOTONIC, &mut now);
 
+            let synced = is_set(MODE_SYNC);
             if FD_ISSET(ttyfd, rfd.as_mut_ptr()) {
                 ttyread();
+                // the cursor is shown while the program writes
+                win.mode &= !MODE_CURSOROFF;
+                lastcursor = now;
             }
 
             xev = 0;
@@ -828,11 +3982,75 @@ pub fn run() {
                     continue;
                 }
                 let mut ev = ev.assume_init();
+                // and while typing, starting again when focused
+                if ev.type_ == KeyPress as i32 || ev.type_ == FocusIn as i32 {
+                    win.mode &= !MODE_CURSOROFF;
+                    lastcursor = now;
+                }
+                if ev.type_ == KeyPress as i32 && x::kprompt(&mut ev.xkey) {
+                    continue;
+                }
+                if ev.type_ == KeyPress as i32 && search::ksearch(&mut ev.xkey)
+                {
+                    continue;
+                }
+                if ev.type_ == KeyPress as i32 && copymode::kcopy(&mut ev.xkey)
+                {
+                    continue;
+                }
+                if ev.type_ == SelectionNotify as i32
+                    && x::clipnotify(&ev.xselection)
+                {
+                    continue;
+                }
+                if ev.type_ == KeyPress as i32 && x::kshortcut(&mut ev.xkey) {
+                    continue;
+                }
+                if (ev.type_ == KeyPress as i32
+                    || ev.type_ == KeyRelease as i32)
+                    && keyboard::kreport(&mut ev.xkey)
+                {
+                    continue;
+                }
+                if ev.type_ == KeyPress as i32 && x::kmap(&mut ev.xkey) {
+                    continue;
+                }
+                if ev.type_ == ButtonPress as i32 && x::linkpress(&ev.xbutton) {
+                    continue;
+                }
+                if (ev.type_ == ButtonPress as i32
+                    || ev.type_ == ButtonRelease as i32)
+                    && x::mshortcut(
+                        &ev.xbutton,
+                        ev.type_ == ButtonRelease as i32,
+                    )
+                {
+                    continue;
+                }
+                if ev.type_ == MotionNotify as i32 {
+                    x::linkhover(&ev.xmotion);
+                }
+                if ev.type_ == FocusOut as i32 {
+                    keyboard::unfocus();
+                }
                 if let Some(h) = handler[ev.type_ as usize] {
                     h(&mut ev);
                 }
             }
 
+            // hold off drawing while the program updates the screen in
+            // synchronized output mode, for as long as SYNCTIMEOUT allows, and
+            // draw right away when it is done
+            if is_set(MODE_SYNC) {
+                let left =
+                    config::SYNCTIMEOUT - timediff(now, state().synctime);
+                if left > 0 {
+                    timeout = left;
+                    continue;
+                }
+                term.mode &= !MODE_SYNC;
+            }
+
             // To reduce flicker and tearing, when new content or event triggers
             // drawing, we first wait a bit to ensure we got everything, and if
             // nothing new arrives - we draw. We start with trying to wait
@@ -842,7 +4060,8 @@ pub fn run() {
             // while interacting, maximum latency intervals during `cat
             // huge.txt`, and perfect sync with periodic updates from
             // animations/key-repeats/etc.
-            if FD_ISSET(ttyfd, rfd.as_mut_ptr()) || xev != 0 {
+            let syncend = synced && !is_set(MODE_SYNC);
+            if !syncend && (FD_ISSET(ttyfd, rfd.as_mut_ptr()) || xev != 0) {
                 if !drawing {
                     trigger = now;
                     drawing = true;
@@ -871,9 +4090,55 @@ pub fn run() {
                 }
             }
 
+            // turn the visual bell off after VISUALBELL
+            if win.mode & MODE_VBELL != 0 {
+                let left = config::VISUALBELL - timediff(now, state().belltime);
+                if left <= 0 {
+                    win.mode &= !MODE_VBELL;
+                    tfulldirt();
+                } else {
+                    timeout =
+                        if timeout < 0 { left } else { timeout.min(left) };
+                }
+            }
+
+            // hide the message after MESSAGETIMEOUT
+            if let Some((_, since)) = state().message {
+                let left = config::MESSAGETIMEOUT - timediff(now, since);
+                if left <= 0 {
+                    state().message = None;
+                    tfulldirt();
+                } else {
+                    timeout =
+                        if timeout < 0 { left } else { timeout.min(left) };
+                }
+            }
+
+            // blink the cursor only while it can be seen to blink, so that an
+            // unfocused window sleeps
+            if config::CURSORBLINK > 0
+                && win.mode & (MODE_CURSORBLINK | MODE_FOCUSED | MODE_VISIBLE)
+                    == MODE_CURSORBLINK | MODE_FOCUSED | MODE_VISIBLE
+            {
+                let mut left = config::CURSORBLINK - timediff(now, lastcursor);
+                if left <= 0 {
+                    win.mode ^= MODE_CURSOROFF;
+                    lastcursor = now;
+                    left = config::CURSORBLINK;
+                }
+                timeout = if timeout < 0 { left } else { timeout.min(left) };
+            } else {
+                win.mode &= !MODE_CURSOROFF;
+            }
+
             draw();
             XFlush(xw.dpy);
             drawing = false;
+
+            // wake up to find the search matches in the changed lines again
+            if let Some(left) = search::pending(now) {
+                timeout = if timeout < 0 { left } else { timeout.min(left) };
+            }
         }
     }
 }
@@ -903,15 +4168,31 @@ fn draw() {
             cx -= 1;
         }
 
+        let status = search::refresh();
         drawregion(0, 0, term.col, term.row);
-        x::drawcursor(
-            cx,
-            term.c.y,
-            *Term::line(&raw mut term, term.c.y, cx),
-            term.ocx,
-            term.ocy,
-            *Term::line(&raw mut term, term.ocy, term.ocx),
-        );
+        // copy mode shows its own cursor instead of the terminal's, which is
+        // not part of the history, so hide it while scrolled back too
+        if state().copy.is_some() {
+            if let Some((x, y)) = copymode::cursor() {
+                x::drawcopycursor(x, y);
+            }
+        } else if term.scr == 0 {
+            x::drawcursor(
+                cx,
+                term.c.y,
+                *Term::line(&raw mut term, term.c.y, cx),
+                term.ocx,
+                term.ocy,
+                *Term::line(&raw mut term, term.ocy, term.ocx),
+            );
+        }
+        if let Some(prompt) = &state().prompt {
+            x::drawprompt(&prompt.text);
+        } else if let Some((message, _)) = &state().message {
+            x::drawprompt(message);
+        } else if let Some(status) = status {
+            x::drawprompt(&status);
+        }
         term.ocx = cx;
         term.ocy = term.c.y;
         x::finishdraw();
@@ -928,7 +4209,7 @@ fn drawregion(x1: c_int, y1: c_int, x2: c_int, y2: c_int) {
                 continue;
             }
             *term.dirty.offset(y as isize) = 0;
-            x::drawline(*term.line.offset(y as isize), x1, y, x2);
+            x::drawline(Term::tline(&raw mut term, y), x1, y, x2, tlattr(y));
         }
     }
 }
@@ -1015,6 +4296,7 @@ fn ttynew(
             _ => {
                 libc::close(s);
                 cmdfd = m;
+                state().pid = pid;
                 libc::signal(SIGCHLD, sigchld as *mut c_void as sighandler_t);
             }
         }
@@ -1038,9 +4320,39 @@ fn sigchld(a: c_int) {
     unsafe { bindgen::sigchld(a) }
 }
 
-// DUMMY(long)
+/// Read output from the shell and process it, returning the number of bytes
+/// read.
 fn ttyread() -> usize {
-    unsafe { bindgen::ttyread() }
+    static mut BUF: [u8; libc::BUFSIZ as usize] = [0; libc::BUFSIZ as usize];
+    static mut BUFLEN: usize = 0;
+    unsafe {
+        let buf = &mut *std::ptr::addr_of_mut!(BUF);
+
+        // append read bytes to unprocessed bytes
+        let ret = libc::read(
+            cmdfd,
+            buf[BUFLEN..].as_mut_ptr().cast(),
+            buf.len() - BUFLEN,
+        );
+
+        match ret {
+            0 => std::process::exit(0),
+            -1 => {
+                die!("couldn't read from shell: {}", strerrno());
+            }
+            _ => {
+                if term.scr > 0 {
+                    kscrolldown(&Arg { i: term.scr });
+                }
+                BUFLEN += ret as usize;
+                let written = twrite(&buf[..BUFLEN], false);
+                BUFLEN -= written;
+                // keep any incomplete UTF-8 byte sequence for the next call
+                buf.copy_within(written..written + BUFLEN, 0);
+                ret as usize
+            }
+        }
+    }
 }
 
 fn tattrset(attr: c_int) -> c_int {
@@ -1057,6 +4369,6 @@ fn tattrset(attr: c_int) -> c_int {
 }
 
 #[inline]
-fn timediff(t1: timespec, t2: timespec) -> i64 {
+pub(crate) fn timediff(t1: timespec, t2: timespec) -> i64 {
     (t1.tv_sec - t2.tv_sec) * 1_000 + (t1.tv_nsec - t2.tv_nsec) / 1_000_000
 }
@@ -1,26 +1,162 @@
+use std::{
+    ffi::{c_char, c_uint, CString, OsString},
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    ptr::null_mut,
+};
+
 use x11::xlib;
 
-use rt::bindgen::{cursorshape, opt_title, xw};
+use rt::bindgen::{
+    self, allowaltscreen, cursorshape, opt_class, opt_cmd, opt_embed, opt_font,
+    opt_io, opt_line, opt_name, opt_title, xw,
+};
 
+use rt::die;
+use rt::settings::{self, settings};
 use rt::x::xsetcursor;
 use rt::{run, selinit, tnew, xinit, xsetenv};
 
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+fn usage(argv0: &str) -> String {
+    format!(
+        "usage: {argv0} [-aiv] [-c class] [-f font] [-g geometry] [-n name] \
+         [-o file]\n          [-T title] [-t title] [-w windowid] \
+         [[-e] command [args ...]]\n       \
+         {argv0} [-aiv] [-c class] [-f font] [-g geometry] [-n name] \
+         [-o file]\n          [-T title] [-t title] [-w windowid] -l line \
+         [stty_args ...]\n       \
+         {argv0} --help | --version"
+    )
+}
+
+/// Return `s` as a C string for the `opt_*` globals, which is kept for good.
+fn cstr(s: OsString) -> *mut c_char {
+    CString::new(s.into_vec()).unwrap().into_raw()
+}
+
+/// Set the `opt_*` globals and the geometry of the window from the command
+/// line `args`, like st, updating the number of columns and rows if it is
+/// given.
+fn options(args: &[OsString], cols: &mut c_uint, rows: &mut c_uint) {
+    let argv0 = args
+        .first()
+        .map_or("rt".into(), |a| a.to_string_lossy().into_owned());
+    let mut i = 1;
+    'args: while i < args.len() {
+        let arg = args[i].as_bytes();
+        match arg {
+            b"--help" => {
+                println!("{}", usage(&argv0));
+                std::process::exit(0);
+            }
+            b"--version" => {
+                println!("{argv0} {VERSION}");
+                std::process::exit(0);
+            }
+            b"--" => {
+                i += 1;
+                break;
+            }
+            // the options end at the first argument that isn't one
+            [b'-', _, ..] => {}
+            _ => break,
+        }
+        i += 1;
+
+        // several options can be given together, like -ai
+        let mut j = 1;
+        while j < arg.len() {
+            let c = arg[j];
+            j += 1;
+            // the argument of an option is the rest of this one, or else the
+            // next one
+            let mut optarg = || {
+                if j < arg.len() {
+                    let s = OsString::from_vec(arg[j..].to_vec());
+                    j = arg.len();
+                    return cstr(s);
+                }
+                i += 1;
+                match args.get(i - 1) {
+                    Some(s) => cstr(s.clone()),
+                    None => {
+                        die!("{}", usage(&argv0));
+                    }
+                }
+            };
+            unsafe {
+                match c {
+                    b'a' => allowaltscreen = 0,
+                    b'c' => opt_class = optarg(),
+                    // the rest is the command
+                    b'e' => break 'args,
+                    b'f' => opt_font = optarg(),
+                    b'g' => {
+                        xw.gm = bindgen::XParseGeometry(
+                            optarg(),
+                            &raw mut xw.l,
+                            &raw mut xw.t,
+                            cols,
+                            rows,
+                        );
+                    }
+                    b'i' => xw.isfixed = xlib::True,
+                    b'o' => opt_io = optarg(),
+                    b'l' => opt_line = optarg(),
+                    b'n' => opt_name = optarg(),
+                    b't' | b'T' => opt_title = optarg(),
+                    b'w' => opt_embed = optarg(),
+                    b'v' => {
+                        die!("{argv0} {VERSION}");
+                    }
+                    _ => {
+                        die!("{}", usage(&argv0));
+                    }
+                }
+            }
+        }
+    }
+
+    // what is left is the command, or the stty arguments with -l
+    if i < args.len() {
+        let cmd: Vec<*mut c_char> = args[i..]
+            .iter()
+            .map(|a| cstr(a.clone()))
+            .chain([null_mut()])
+            .collect();
+        unsafe {
+            opt_cmd = Box::leak(cmd.into_boxed_slice()).as_mut_ptr();
+        }
+    }
+}
+
 fn main() {
+    settings::load();
     unsafe {
         xw.l = 0;
         xw.t = 0;
         xw.isfixed = xlib::False;
         xsetcursor(cursorshape as i32);
 
+        let mut cols = settings().cols as c_uint;
+        let mut rows = settings().rows as c_uint;
+        let args: Vec<OsString> = std::env::args_os().collect();
+        options(&args, &mut cols, &mut rows);
+
         if opt_title.is_null() {
-            opt_title = c"rt".as_ptr() as *mut _;
+            opt_title = if !opt_line.is_null() || opt_cmd.is_null() {
+                c"rt".as_ptr() as *mut _
+            } else {
+                *opt_cmd
+            };
         }
 
         libc::setlocale(libc::LC_CTYPE, c"".as_ptr());
         xlib::XSetLocaleModifiers(c"".as_ptr());
 
-        let cols = 80;
-        let rows = 24;
+        let cols = cols.max(1) as i32;
+        let rows = rows.max(1) as i32;
 
         tnew(cols, rows);
         xinit(cols, rows);
@@ -29,3 +165,83 @@ fn main() {
         run();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+
+    use super::*;
+
+    /// Return the C string `s` set by `options`, if it was.
+    fn opt(s: *mut c_char) -> Option<String> {
+        (!s.is_null())
+            .then(|| unsafe { CStr::from_ptr(s) }.to_string_lossy().into())
+    }
+
+    /// Return the command left by `options`.
+    fn cmd() -> Vec<String> {
+        let mut v = Vec::new();
+        unsafe {
+            let mut p = opt_cmd;
+            while !p.is_null() && !(*p).is_null() {
+                v.push(opt(*p).unwrap());
+                p = p.add(1);
+            }
+        }
+        v
+    }
+
+    /// Parse the command line `args` after resetting what it sets, returning
+    /// the columns and rows.
+    fn parse(args: &[&str]) -> (c_uint, c_uint) {
+        unsafe {
+            allowaltscreen = 1;
+            (opt_class, opt_font, opt_io, opt_line) = Default::default();
+            (opt_name, opt_title, opt_embed) = Default::default();
+            opt_cmd = null_mut();
+            xw.isfixed = xlib::False;
+            xw.gm = 0;
+        }
+        let args: Vec<OsString> =
+            ["rt"].iter().chain(args).map(OsString::from).collect();
+        let (mut cols, mut rows) = (80, 24);
+        options(&args, &mut cols, &mut rows);
+        (cols, rows)
+    }
+
+    // the options set globals, so they are all checked in one test
+    #[test]
+    fn parsed() {
+        assert_eq!(parse(&[]), (80, 24));
+        assert!(cmd().is_empty());
+
+        parse(&["-a", "-c", "cls", "-fmono", "-T", "title", "-i"]);
+        unsafe { assert_eq!((allowaltscreen, xw.isfixed), (0, xlib::True)) };
+        assert_eq!(opt(unsafe { opt_class }).as_deref(), Some("cls"));
+        assert_eq!(opt(unsafe { opt_font }).as_deref(), Some("mono"));
+        assert_eq!(opt(unsafe { opt_title }).as_deref(), Some("title"));
+
+        // options given together, the last one with its argument
+        parse(&["-ainname", "vi", "x"]);
+        unsafe { assert_eq!((allowaltscreen, xw.isfixed), (0, xlib::True)) };
+        assert_eq!(opt(unsafe { opt_name }).as_deref(), Some("name"));
+        assert_eq!(cmd(), ["vi", "x"]);
+
+        assert_eq!(parse(&["-g", "100x30+10-20"]), (100, 30));
+        unsafe { assert_eq!((xw.l, xw.t), (10, -20)) };
+
+        // the command starts at -e, --, or the first argument that isn't an
+        // option
+        parse(&["-e", "ls", "-l"]);
+        assert_eq!(cmd(), ["ls", "-l"]);
+        parse(&["-n", "x", "--", "-c", "y"]);
+        assert_eq!(cmd(), ["-c", "y"]);
+        assert_eq!(opt(unsafe { opt_class }), None);
+        parse(&["-", "-c"]);
+        assert_eq!(cmd(), ["-", "-c"]);
+
+        parse(&["-l", "/dev/ttyS0", "9600"]);
+        assert_eq!(opt(unsafe { opt_line }).as_deref(), Some("/dev/ttyS0"));
+        assert_eq!(cmd(), ["9600"]);
+    }
+}
@@ -0,0 +1,618 @@
+//! A parser for the control sequences in the output of programs, which reports
+//! what it finds to a [`Handler`].
+//!
+//! The parser follows st in what it accepts: control characters are executed
+//! as soon as they arrive, even in the middle of an escape sequence, and
+//! control strings (DCS, OSC, PM, APC) end at BEL, ST or any C1 control, while
+//! CAN and SUB cancel them.
+
+use std::ffi::c_int;
+
+use crate::{between, bindgen::Rune, utf8decode, utf8encode, UTF_SIZ};
+
+/// Maximum number of bytes of a CSI sequence kept for [`Csi::raw`].
+const ESC_BUF_SIZ: usize = 128 * UTF_SIZ;
+/// Maximum number of parameters of a CSI or DCS sequence. Any further
+/// parameters are dropped.
+pub const ESC_ARG_SIZ: usize = 16;
+/// Initial capacity of the buffer for control strings.
+const STR_BUF_SIZ: usize = ESC_BUF_SIZ;
+/// Maximum number of parameters of an OSC string. Any further parameters are
+/// dropped.
+pub const STR_ARG_SIZ: usize = ESC_ARG_SIZ;
+/// Maximum number of intermediate characters of an escape sequence. Longer
+/// sequences are ignored.
+const ESC_INTER_SIZ: usize = 4;
+
+const BEL: Rune = 0x07;
+const CAN: Rune = 0x18;
+const SUB: Rune = 0x1a;
+const ESC: Rune = 0x1b;
+const DEL: Rune = 0x7f;
+const DCS: Rune = 0x90;
+const CSI: Rune = 0x9b;
+const ST: Rune = 0x9c;
+const OSC: Rune = 0x9d;
+const PM: Rune = 0x9e;
+const APC: Rune = 0x9f;
+
+/// A CSI sequence:
+///
+/// ESC '[' [<private>] [<param> [; <param>]...] [<intermediate>...] <final>
+pub struct Csi<'a> {
+    /// the private marker, one of `<`, `=`, `>` or `?`
+    pub private: Option<u8>,
+    /// the numeric parameters, with missing ones set to 0. There is always at
+    /// least one.
+    pub params: &'a [c_int],
+    /// for each parameter, whether it was separated from the previous one by a
+    /// colon instead of a semicolon, which makes it a sub-parameter
+    pub subparams: &'a [bool],
+    pub intermediates: &'a [u8],
+    pub action: u8,
+    /// the raw sequence after the `[`, possibly truncated
+    pub raw: &'a [u8],
+}
+
+/// A DCS string:
+///
+/// ESC 'P' [<param> [; <param>]...] [<intermediate>...] <final> <data> ST
+pub struct Dcs<'a> {
+    /// the numeric parameters, with missing ones set to 0. There is always at
+    /// least one.
+    pub params: &'a [c_int],
+    pub intermediates: &'a [u8],
+    /// the final character, or 0 if the string did not have one
+    pub action: u8,
+    pub data: &'a [u8],
+}
+
+/// The receiver of the actions found by a [`Parser`].
+pub trait Handler {
+    /// Display the graphic character `c`.
+    fn print(&mut self, c: Rune);
+
+    /// Perform the function of the C0 or C1 control character `byte`. ESC and
+    /// the C1 characters that start a sequence are handled by the parser, but
+    /// CAN and SUB are passed on after they cancel one.
+    fn execute(&mut self, byte: u8);
+
+    /// Handle a CSI sequence.
+    fn csi_dispatch(&mut self, csi: &Csi);
+
+    /// Handle the escape sequence ESC <intermediates> <byte>, such as `ESC 7`
+    /// or `ESC ( 0`.
+    fn esc_dispatch(&mut self, intermediates: &[u8], byte: u8);
+
+    /// Handle an OSC string, split into its `;` separated parameters. The old
+    /// title set sequence, ESC k <title> ST, is reported as OSC 2.
+    fn osc_dispatch(&mut self, params: &[&[u8]]);
+
+    /// Handle a DCS string.
+    fn dcs_dispatch(&mut self, _dcs: &Dcs) {}
+
+    /// Handle an APC string.
+    fn apc_dispatch(&mut self, _data: &[u8]) {}
+
+    /// Handle a PM string.
+    fn pm_dispatch(&mut self, _data: &[u8]) {}
+
+    /// Return whether the input is decoded as UTF-8. Otherwise each byte is a
+    /// character, and the 8-bit C1 control characters are recognized.
+    fn utf8(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq)]
+enum State {
+    #[default]
+    Ground,
+    /// after an ESC, possibly followed by intermediates
+    Escape,
+    /// in a CSI sequence
+    Csi,
+    /// in a malformed escape sequence, which is ignored up to its final
+    /// character
+    EscapeIgnore,
+    /// in a malformed CSI sequence, which is ignored up to its final character
+    CsiIgnore,
+    /// in a control string of the given type, `P`, `]`, `^`, `_` or `k`
+    Str(u8),
+    /// after an ESC in a control string, which ends the string if followed by
+    /// `\`
+    StrEsc(u8),
+}
+
+/// A state machine splitting the characters written to a terminal into the
+/// actions of a [`Handler`].
+#[derive(Default)]
+pub struct Parser {
+    state: State,
+    private: Option<u8>,
+    params: Vec<c_int>,
+    subparams: Vec<bool>,
+    /// whether a separator was dropped because there were ESC_ARG_SIZ
+    /// parameters already, so that the digits after it are ignored
+    params_full: bool,
+    intermediates: Vec<u8>,
+    /// the raw CSI sequence
+    raw: Vec<u8>,
+    /// the contents of a control string
+    buf: Vec<u8>,
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Self {
+            state: State::Ground,
+            private: None,
+            params: Vec::new(),
+            subparams: Vec::new(),
+            params_full: false,
+            intermediates: Vec::new(),
+            raw: Vec::new(),
+            buf: Vec::new(),
+        }
+    }
+
+    /// Process the bytes in `buf`, returning the number of bytes consumed. An
+    /// incomplete UTF-8 sequence at the end of `buf` is left for the next call.
+    pub fn feed<H: Handler>(&mut self, handler: &mut H, buf: &[u8]) -> usize {
+        let mut n = 0;
+        while n < buf.len() {
+            let mut u = 0;
+            if handler.utf8() {
+                let charsize = utf8decode(&buf[n..], &mut u);
+                if charsize == 0 {
+                    break;
+                }
+                n += charsize;
+            } else {
+                u = buf[n] as Rune;
+                n += 1;
+            }
+            self.advance(handler, u);
+        }
+        n
+    }
+
+    /// Process the single character `u`.
+    pub fn advance<H: Handler>(&mut self, handler: &mut H, u: Rune) {
+        // control strings take all following characters until they are ended
+        // by an ESC, a SUB, a ST or any other C1 control character
+        if let State::Str(kind) = self.state {
+            match u {
+                BEL => {
+                    // backwards compatibility to xterm
+                    self.state = State::Ground;
+                    self.str_dispatch(handler, kind);
+                }
+                ESC => self.state = State::StrEsc(kind),
+                CAN | SUB => {
+                    self.state = State::Ground;
+                    handler.execute(u as u8);
+                }
+                0x80..=0x9f => {
+                    self.state = State::Ground;
+                    if handler.utf8() {
+                        return;
+                    }
+                    if u == ST {
+                        self.str_dispatch(handler, kind);
+                    } else {
+                        self.control(handler, u);
+                    }
+                }
+                _ => {
+                    // NOTE(st): If the user never sends some code to stop the
+                    // str or esc command, the buffer keeps growing. This is
+                    // better than silently failing with unknown characters.
+                    if handler.utf8() {
+                        let mut c = [0; UTF_SIZ];
+                        let len = utf8encode(u, &mut c);
+                        self.buf.extend_from_slice(&c[..len]);
+                    } else {
+                        self.buf.push(u as u8);
+                    }
+                }
+            }
+            return;
+        }
+        if let State::StrEsc(kind) = self.state {
+            if u == '\\' as Rune {
+                self.state = State::Ground;
+                self.str_dispatch(handler, kind);
+                return;
+            }
+            // the string is dropped, and u continues a new escape sequence
+            self.state = State::Escape;
+            self.intermediates.clear();
+        }
+
+        // Actions of control codes must be performed as soon they arrive
+        // because they can be embedded inside a control sequence, and they
+        // must not cause conflicts with sequences.
+        if between(u, 0, 0x1f) || u == DEL || between(u, 0x80, 0x9f) {
+            // in UTF-8 mode ignore handling C1 control characters
+            if u >= 0x80 && handler.utf8() {
+                return;
+            }
+            return self.control(handler, u);
+        }
+
+        match self.state {
+            State::Ground => handler.print(u),
+            State::Escape => self.escape(handler, u),
+            State::EscapeIgnore => {
+                if between(u, 0x30, 0x7e) {
+                    self.state = State::Ground;
+                }
+            }
+            State::Csi => self.csi(handler, u),
+            State::CsiIgnore => {
+                if between(u, 0x40, 0x7e) {
+                    self.state = State::Ground;
+                }
+            }
+            State::Str(_) | State::StrEsc(_) => unreachable!(),
+        }
+    }
+
+    /// Handle the control character `u`.
+    fn control<H: Handler>(&mut self, handler: &mut H, u: Rune) {
+        match u {
+            ESC => {
+                self.state = State::Escape;
+                self.intermediates.clear();
+            }
+            CSI => self.start_csi(),
+            DCS => self.start_str(b'P'),
+            OSC => self.start_str(b']'),
+            PM => self.start_str(b'^'),
+            APC => self.start_str(b'_'),
+            CAN | SUB => {
+                // only CAN, SUB and C1 characters interrupt a sequence
+                self.state = State::Ground;
+                handler.execute(u as u8);
+            }
+            _ => {
+                if between(u, 0x80, 0x9f) {
+                    self.state = State::Ground;
+                }
+                handler.execute(u as u8);
+            }
+        }
+    }
+
+    /// Handle the character `u` following an ESC.
+    fn escape<H: Handler>(&mut self, handler: &mut H, u: Rune) {
+        if between(u, 0x20, 0x2f) {
+            if self.intermediates.len() < ESC_INTER_SIZ {
+                self.intermediates.push(u as u8);
+            } else {
+                self.state = State::EscapeIgnore;
+            }
+            return;
+        }
+        self.state = State::Ground;
+        if u > 0x7e {
+            return;
+        }
+        let c = u as u8;
+        if !self.intermediates.is_empty() {
+            return handler.esc_dispatch(&self.intermediates, c);
+        }
+        match c {
+            b'[' => self.start_csi(),
+            // DCS -- Device Control String, OSC -- Operating System Command,
+            // PM -- Privacy Message, APC -- Application Program Command, old
+            // title set compatibility
+            b'P' | b']' | b'^' | b'_' | b'k' => self.start_str(c),
+            _ => handler.esc_dispatch(&[], c),
+        }
+    }
+
+    fn start_csi(&mut self) {
+        self.state = State::Csi;
+        self.private = None;
+        self.params.clear();
+        self.params.push(0);
+        self.subparams.clear();
+        self.subparams.push(false);
+        self.params_full = false;
+        self.intermediates.clear();
+        self.raw.clear();
+    }
+
+    /// Handle the character `u` in a CSI sequence.
+    fn csi<H: Handler>(&mut self, handler: &mut H, u: Rune) {
+        if self.raw.len() < ESC_BUF_SIZ {
+            self.raw.push(u as u8);
+        }
+        let params_done = !self.intermediates.is_empty();
+        match u {
+            0x30..=0x39 if !params_done => {
+                if !self.params_full {
+                    add_digit(&mut self.params, u as u8);
+                }
+            }
+            0x3a | 0x3b if !params_done => {
+                if self.params.len() < ESC_ARG_SIZ {
+                    self.params.push(0);
+                    self.subparams.push(u == ':' as Rune);
+                } else {
+                    self.params_full = true;
+                }
+            }
+            0x3c..=0x3f if self.raw.len() == 1 => self.private = Some(u as u8),
+            0x20..=0x2f if self.intermediates.len() < ESC_INTER_SIZ => {
+                self.intermediates.push(u as u8);
+            }
+            0x40..=0x7e => {
+                self.state = State::Ground;
+                handler.csi_dispatch(&Csi {
+                    private: self.private,
+                    params: &self.params,
+                    subparams: &self.subparams,
+                    intermediates: &self.intermediates,
+                    action: u as u8,
+                    raw: &self.raw,
+                });
+            }
+            _ => self.state = State::CsiIgnore,
+        }
+    }
+
+    /// Start a new control string of type `kind`.
+    fn start_str(&mut self, kind: u8) {
+        self.state = State::Str(kind);
+        self.buf = Vec::with_capacity(STR_BUF_SIZ);
+    }
+
+    /// Pass the finished control string of type `kind` to `handler`.
+    fn str_dispatch<H: Handler>(&mut self, handler: &mut H, kind: u8) {
+        let buf = std::mem::take(&mut self.buf);
+        match kind {
+            b']' => handler.osc_dispatch(&strparse(&buf)),
+            b'k' => {
+                let mut params = vec![&b"2"[..]];
+                params.extend(strparse(&buf).first());
+                handler.osc_dispatch(&params);
+            }
+            b'P' => {
+                let mut params = vec![0];
+                let mut full = false;
+                let mut i = 0;
+                while i < buf.len() && matches!(buf[i], b'0'..=b';') {
+                    if buf[i].is_ascii_digit() {
+                        if !full {
+                            add_digit(&mut params, buf[i]);
+                        }
+                    } else if params.len() < ESC_ARG_SIZ {
+                        params.push(0);
+                    } else {
+                        full = true;
+                    }
+                    i += 1;
+                }
+                let start = i;
+                while i < buf.len() && between(buf[i], 0x20, 0x2f) {
+                    i += 1;
+                }
+                let intermediates = &buf[start..i];
+                let action = match buf.get(i) {
+                    Some(&c) if between(c, 0x40, 0x7e) => {
+                        i += 1;
+                        c
+                    }
+                    _ => 0,
+                };
+                handler.dcs_dispatch(&Dcs {
+                    params: &params,
+                    intermediates,
+                    action,
+                    data: &buf[i..],
+                });
+            }
+            b'^' => handler.pm_dispatch(&buf),
+            b'_' => handler.apc_dispatch(&buf),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Add the decimal digit `c` to the last of `params`.
+fn add_digit(params: &mut [c_int], c: u8) {
+    if let Some(p) = params.last_mut() {
+        *p = p.saturating_mul(10).saturating_add((c - b'0') as c_int);
+    }
+}
+
+/// Split the control string in `buf` into its `;` separated parameters.
+fn strparse(buf: &[u8]) -> Vec<&[u8]> {
+    let end = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+    if end == 0 {
+        return Vec::new();
+    }
+    buf[..end].split(|&c| c == b';').take(STR_ARG_SIZ).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A handler recording the actions in a readable form.
+    #[derive(Default)]
+    struct Log {
+        actions: Vec<String>,
+        ascii: bool,
+    }
+
+    impl Handler for Log {
+        fn print(&mut self, c: Rune) {
+            self.actions
+                .push(format!("print {}", char::from_u32(c).unwrap()));
+        }
+
+        fn execute(&mut self, byte: u8) {
+            self.actions.push(format!("execute {byte:#x}"));
+        }
+
+        fn csi_dispatch(&mut self, csi: &Csi) {
+            let private = csi
+                .private
+                .map_or(String::new(), |c| (c as char).to_string());
+            let params: Vec<_> = csi
+                .params
+                .iter()
+                .zip(csi.subparams)
+                .map(|(p, &sub)| format!("{}{p}", if sub { ":" } else { "" }))
+                .collect();
+            self.actions.push(format!(
+                "csi {private}{} {}{}",
+                params.join(","),
+                String::from_utf8_lossy(csi.intermediates),
+                csi.action as char
+            ));
+        }
+
+        fn esc_dispatch(&mut self, intermediates: &[u8], byte: u8) {
+            self.actions.push(format!(
+                "esc {}{}",
+                String::from_utf8_lossy(intermediates),
+                byte as char
+            ));
+        }
+
+        fn osc_dispatch(&mut self, params: &[&[u8]]) {
+            let params: Vec<_> =
+                params.iter().map(|p| String::from_utf8_lossy(p)).collect();
+            self.actions.push(format!("osc {}", params.join("|")));
+        }
+
+        fn dcs_dispatch(&mut self, dcs: &Dcs) {
+            self.actions.push(format!(
+                "dcs {:?} {}{} {}",
+                dcs.params,
+                String::from_utf8_lossy(dcs.intermediates),
+                dcs.action as char,
+                String::from_utf8_lossy(dcs.data)
+            ));
+        }
+
+        fn apc_dispatch(&mut self, data: &[u8]) {
+            self.actions
+                .push(format!("apc {}", String::from_utf8_lossy(data)));
+        }
+
+        fn pm_dispatch(&mut self, data: &[u8]) {
+            self.actions
+                .push(format!("pm {}", String::from_utf8_lossy(data)));
+        }
+
+        fn utf8(&self) -> bool {
+            !self.ascii
+        }
+    }
+
+    fn parse(input: &[u8]) -> Vec<String> {
+        let mut log = Log::default();
+        let n = Parser::new().feed(&mut log, input);
+        assert_eq!(n, input.len());
+        log.actions
+    }
+
+    #[test]
+    fn print_and_execute() {
+        assert_eq!(
+            parse("a\u{e9}\r\n".as_bytes()),
+            ["print a", "print \u{e9}", "execute 0xd", "execute 0xa"]
+        );
+    }
+
+    #[test]
+    fn incomplete_utf8() {
+        let mut log = Log::default();
+        let mut p = Parser::new();
+        assert_eq!(p.feed(&mut log, b"a\xc3"), 1);
+        assert_eq!(p.feed(&mut log, b"\xc3\xa9"), 2);
+        assert_eq!(log.actions, ["print a", "print \u{e9}"]);
+    }
+
+    #[test]
+    fn csi() {
+        assert_eq!(parse(b"\x1b[H"), ["csi 0 H"]);
+        assert_eq!(parse(b"\x1b[1;23r"), ["csi 1,23 r"]);
+        assert_eq!(parse(b"\x1b[?1049h"), ["csi ?1049 h"]);
+        assert_eq!(parse(b"\x1b[38:2::1:2:3m"), ["csi 38,:2,:0,:1,:2,:3 m"]);
+        assert_eq!(parse(b"\x1b[2 q"), ["csi 2  q"]);
+        assert_eq!(parse(b"\x1b[;5H"), ["csi 0,5 H"]);
+    }
+
+    #[test]
+    fn csi_limits() {
+        // the parameters after the first ESC_ARG_SIZ are dropped, along with
+        // their digits
+        let mut seq = b"\x1b[".to_vec();
+        for i in 1..=ESC_ARG_SIZ + 2 {
+            seq.extend_from_slice(format!("{i};").as_bytes());
+        }
+        seq.push(b'm');
+        let expected: Vec<_> =
+            (1..=ESC_ARG_SIZ).map(|i| i.to_string()).collect();
+        assert_eq!(parse(&seq), [format!("csi {} m", expected.join(","))]);
+
+        // huge parameters saturate
+        assert_eq!(
+            parse(b"\x1b[99999999999999A"),
+            [format!("csi {} A", c_int::MAX)]
+        );
+
+        // sequences with too many intermediates are ignored
+        assert_eq!(parse(b"\x1b[!!!!!pa"), ["print a"]);
+        assert_eq!(parse(b"\x1b!!!!!@a"), ["print a"]);
+    }
+
+    #[test]
+    fn csi_malformed() {
+        // a private marker after the parameters makes the sequence malformed
+        assert_eq!(parse(b"\x1b[1?hx"), ["print x"]);
+        // control characters are executed in the middle of a sequence
+        assert_eq!(parse(b"\x1b[1\n2H"), ["execute 0xa", "csi 12 H"]);
+        // CAN cancels it
+        assert_eq!(parse(b"\x1b[1\x18H"), ["execute 0x18", "print H"]);
+    }
+
+    #[test]
+    fn esc() {
+        assert_eq!(parse(b"\x1b7"), ["esc 7"]);
+        assert_eq!(parse(b"\x1b(0"), ["esc (0"]);
+        assert_eq!(parse(b"\x1b#8"), ["esc #8"]);
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(parse(b"\x1b]0;title\x07"), ["osc 0|title"]);
+        assert_eq!(parse(b"\x1b]8;;uri\x1b\\"), ["osc 8||uri"]);
+        assert_eq!(parse(b"\x1bktitle\x1b\\"), ["osc 2|title"]);
+        assert_eq!(parse(b"\x1b_Ga=T\x1b\\"), ["apc Ga=T"]);
+        assert_eq!(parse(b"\x1b^private\x1b\\"), ["pm private"]);
+        assert_eq!(parse(b"\x1bP1;2$qm\x1b\\"), ["dcs [1, 2] $q m"]);
+        assert_eq!(parse(b"\x1bPq#0\x1b\\"), ["dcs [0] q #0"]);
+        // an ESC that is not followed by \ drops the string
+        assert_eq!(parse(b"\x1b]0;x\x1b7"), ["esc 7"]);
+        // SUB cancels it
+        assert_eq!(parse(b"\x1b]0;x\x1ay"), ["execute 0x1a", "print y"]);
+    }
+
+    #[test]
+    fn c1() {
+        let mut log = Log { ascii: true, ..Log::default() };
+        Parser::new().feed(&mut log, b"\x9b2J\x9d0;t\x9c\x85");
+        assert_eq!(log.actions, ["csi 2 J", "osc 0|t", "execute 0x85"]);
+
+        // C1 characters are ignored in UTF-8
+        assert_eq!(parse("\u{9b}2J".as_bytes()), ["print 2", "print J"]);
+    }
+}
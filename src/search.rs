@@ -0,0 +1,322 @@
+//! Incremental search of the screen and the history, for literal text or a
+//! regular expression. The matches are highlighted in the view, and the
+//! question line at the bottom shows what is searched for.
+//!
+//! While searching, typed text is added to the pattern and these keys are
+//! taken:
+//!
+//! - Up, Ctrl+P: go to the previous match, further back in the history
+//! - Down, Ctrl+N: go to the next match
+//! - Return: select the current match, copy it and stop searching
+//! - Escape, Ctrl+G: stop searching
+//! - BackSpace: delete the last character of the pattern, Ctrl+U all of it
+//! - Ctrl+R: switch between literal text and a regular expression
+//! - Ctrl+T: switch case sensitivity
+
+use std::{ffi::c_int, fmt::Write as _, mem::MaybeUninit, ptr::null_mut};
+
+use libc::{clock_gettime, timespec, CLOCK_MONOTONIC};
+use regex::{Regex, RegexBuilder};
+use x11::{keysym::*, xlib::ControlMask};
+
+use crate::{
+    bindgen::{self, sel, term, Arg, KeySym, XKeyEvent},
+    config, getsel, is_set, kscrolldown, kscrollup, selclear, selnormalize,
+    state, tfulldirt, timediff, tlinetext, x, Pos, MODE_ALTSCREEN, SEL_READY,
+    SEL_REGULAR,
+};
+
+/// A search in progress.
+pub(crate) struct Search {
+    /// the text searched for
+    pattern: String,
+    /// whether `pattern` is a regular expression rather than literal text
+    regex: bool,
+    /// whether upper and lower case letters are told apart
+    case: bool,
+    /// the compiled `pattern`, or `None` if it is empty or invalid
+    re: Option<Regex>,
+    /// the first and the last cell of each match, from the oldest
+    matches: Vec<(Pos, Pos)>,
+    /// the index of the current match in `matches`
+    current: Option<usize>,
+    /// the bottom row of the view when the search started, which the first
+    /// match is looked for above
+    origin: c_int,
+    /// whether the lines changed since the matches were found
+    stale: bool,
+    /// when the matches were last found again for changed lines
+    found: timespec,
+}
+
+impl Search {
+    fn new() -> Self {
+        Self {
+            pattern: String::new(),
+            regex: false,
+            case: false,
+            re: None,
+            matches: Vec::new(),
+            current: None,
+            origin: unsafe { term.row - 1 - term.scr },
+            stale: false,
+            found: timespec { tv_sec: 0, tv_nsec: 0 },
+        }
+    }
+
+    /// Compile the pattern again after it or the flags changed, and look for
+    /// the match nearest to where the search started.
+    fn compile(&mut self) {
+        let pattern = if self.regex {
+            self.pattern.clone()
+        } else {
+            regex::escape(&self.pattern)
+        };
+        self.re = if self.pattern.is_empty() {
+            None
+        } else {
+            RegexBuilder::new(&pattern)
+                .case_insensitive(!self.case)
+                .build()
+                .ok()
+        };
+        self.current = None;
+        self.find();
+        self.reveal();
+    }
+
+    /// Find the matches on the screen and, unless the alternate screen is
+    /// shown, in the history. Lines wrapped by the terminal are searched as
+    /// one, so that matches may continue on the next row. Returns whether the
+    /// matches changed.
+    fn find(&mut self) -> bool {
+        let old = std::mem::take(&mut self.matches);
+        let current = self.current.map(|i| old[i]);
+        if let Some(re) = &self.re {
+            unsafe {
+                let first =
+                    if is_set(MODE_ALTSCREEN) { 0 } else { -term.histn };
+                let mut y = first;
+                while y < term.row {
+                    let (line, next) = tlinetext(y);
+                    self.matches.extend(
+                        re.find_iter(&line.text).filter(|m| !m.is_empty()).map(
+                            |m| (line.cell(m.start()), line.cell(m.end() - 1)),
+                        ),
+                    );
+                    y = next;
+                }
+            }
+        }
+
+        self.current = current
+            .and_then(|m| self.matches.iter().position(|&n| n == m))
+            .or_else(|| {
+                // the last match above where the search started, or else the
+                // first one below
+                let i = self
+                    .matches
+                    .partition_point(|&(start, _)| start.0 <= self.origin);
+                match i {
+                    0 if self.matches.is_empty() => None,
+                    0 => Some(0),
+                    i => Some(i - 1),
+                }
+            });
+        self.matches != old
+    }
+
+    /// Go to the match before the current one if `back` is set, or else the
+    /// one after it, going round at either end.
+    fn step(&mut self, back: bool) {
+        let Some(i) = self.current else {
+            return;
+        };
+        let n = self.matches.len();
+        self.current = Some(if back { (i + n - 1) % n } else { (i + 1) % n });
+        tfulldirt();
+        self.reveal();
+    }
+
+    /// Scroll the view to show the current match above the question line.
+    fn reveal(&self) {
+        let Some(i) = self.current else {
+            return;
+        };
+        let ((y, _), _) = self.matches[i];
+        unsafe {
+            let y = y + term.scr;
+            if y < 0 {
+                kscrollup(&Arg { i: -y });
+            } else if y > term.row - 2 {
+                kscrolldown(&Arg { i: y - (term.row - 2) });
+            }
+        }
+    }
+
+    /// Return the text of the question line.
+    fn status(&self) -> String {
+        let mut s = String::from("search");
+        if self.regex {
+            s.push_str(" regex");
+        }
+        if self.case {
+            s.push_str(" case");
+        }
+        let _ = write!(s, ": {}", self.pattern);
+        if self.pattern.is_empty() {
+            return s;
+        }
+        let _ = match (&self.re, self.current) {
+            (None, _) => write!(s, "  (invalid)"),
+            (_, None) => write!(s, "  (no matches)"),
+            (_, Some(i)) => write!(s, "  ({}/{})", i + 1, self.matches.len()),
+        };
+        s
+    }
+}
+
+/// Start searching.
+pub(crate) fn search(_: &Arg) {
+    unsafe { state() }.search = Some(Search::new());
+}
+
+/// Stop searching, removing the highlights.
+fn stop() {
+    unsafe { state() }.search = None;
+    tfulldirt();
+}
+
+/// Select the current match, copy it into the primary selection and stop
+/// searching.
+fn accept(s: &Search) {
+    if let Some(i) = s.current {
+        let ((y1, x1), (y2, x2)) = s.matches[i];
+        unsafe {
+            selclear();
+            sel.mode = SEL_READY;
+            sel.type_ = SEL_REGULAR;
+            sel.snap = 0;
+            sel.alt = is_set(MODE_ALTSCREEN) as c_int;
+            (sel.ob.x, sel.ob.y) = (x1, y1 + term.scr);
+            (sel.oe.x, sel.oe.y) = (x2, y2 + term.scr);
+            selnormalize();
+            if let Some(text) = getsel() {
+                x::xsetsel(&text);
+            }
+        }
+    }
+    stop();
+}
+
+/// Take the key press `e` for the search, if one is in progress, returning
+/// whether it was.
+#[allow(non_upper_case_globals)]
+pub(crate) fn ksearch(e: &mut XKeyEvent) -> bool {
+    // the search is taken out while it scrolls the view and reads the lines
+    let Some(mut s) = unsafe { state() }.search.take() else {
+        return false;
+    };
+    let mut ksym: KeySym = 0;
+    let mut buf = [0; 64];
+    let len = unsafe {
+        bindgen::XLookupString(
+            e,
+            buf.as_mut_ptr(),
+            buf.len() as c_int,
+            &mut ksym,
+            null_mut(),
+        )
+    };
+    let ctrl = e.state & ControlMask != 0;
+    match ksym as u32 {
+        XK_Escape => {
+            stop();
+            return true;
+        }
+        XK_g | XK_G if ctrl => {
+            stop();
+            return true;
+        }
+        XK_Return | XK_KP_Enter => {
+            accept(&s);
+            return true;
+        }
+        XK_Up | XK_KP_Up => s.step(true),
+        XK_p | XK_P if ctrl => s.step(true),
+        XK_Down | XK_KP_Down => s.step(false),
+        XK_n | XK_N if ctrl => s.step(false),
+        XK_r | XK_R if ctrl => {
+            s.regex = !s.regex;
+            s.compile();
+        }
+        XK_t | XK_T if ctrl => {
+            s.case = !s.case;
+            s.compile();
+        }
+        XK_u | XK_U if ctrl => {
+            s.pattern.clear();
+            s.compile();
+        }
+        XK_BackSpace => {
+            s.pattern.pop();
+            s.compile();
+        }
+        _ if !ctrl && len > 0 => {
+            let text = String::from_utf8_lossy(unsafe {
+                std::slice::from_raw_parts(buf.as_ptr().cast(), len as usize)
+            });
+            let text: String =
+                text.chars().filter(|c| !c.is_control()).collect();
+            if !text.is_empty() {
+                s.pattern.push_str(&text);
+                s.compile();
+            }
+        }
+        _ => {}
+    }
+    unsafe { state() }.search = Some(s);
+    true
+}
+
+/// Find the matches again before drawing if the lines changed, but not more
+/// often than every `SEARCHINTERVAL`, and return the question line to show, if
+/// searching.
+pub(crate) fn refresh() -> Option<String> {
+    let mut s = unsafe { state() }.search.take()?;
+    s.stale |=
+        unsafe { (0..term.row).any(|y| *term.dirty.add(y as usize) != 0) };
+    let mut now = MaybeUninit::uninit();
+    let now = unsafe {
+        clock_gettime(CLOCK_MONOTONIC, now.as_mut_ptr());
+        now.assume_init()
+    };
+    if s.stale && timediff(now, s.found) >= config::SEARCHINTERVAL {
+        s.stale = false;
+        s.found = now;
+        if s.find() {
+            tfulldirt();
+        }
+    }
+    let status = s.status();
+    unsafe { state() }.search = Some(s);
+    Some(status)
+}
+
+/// Return the time in milliseconds from `now` until the matches are found
+/// again, if the lines changed since they were last found.
+pub(crate) fn pending(now: timespec) -> Option<i64> {
+    let s = unsafe { state() }.search.as_ref()?;
+    s.stale
+        .then(|| (config::SEARCHINTERVAL - timediff(now, s.found)).max(1))
+}
+
+/// Return whether the cell at `x` on row `y` of the view is part of a match,
+/// and if so whether it is the current match.
+pub(crate) fn highlight(x: c_int, y: c_int) -> Option<bool> {
+    let s = unsafe { state() }.search.as_ref()?;
+    let pos = (y - unsafe { term.scr }, x);
+    let i = s.matches.partition_point(|&(start, _)| start <= pos);
+    let (_, end) = *s.matches.get(i.checked_sub(1)?)?;
+    (pos <= end).then_some(s.current == Some(i - 1))
+}
@@ -0,0 +1,670 @@
+//! The settings file, `$XDG_CONFIG_HOME/rt/rt.toml` or `~/.config/rt/rt.toml`,
+//! which overrides the settings of st/config.h at startup so that they can be
+//! changed without building rt again.
+//!
+//! The settings have the names they have in config.h, and the ones not in the
+//! file keep their values from there:
+//!
+//! ```toml
+//! font = "Liberation Mono:pixelsize=14:antialias=true:autohint=true"
+//! borderpx = 4
+//! worddelimiters = " `'\"()[]{}"
+//! minlatency = 2
+//! maxlatency = 33
+//! cols = 100
+//! rows = 30
+//!
+//! [colorname]
+//! 0 = "#282828"
+//! 258 = "#ebdbb2"
+//!
+//! [[shortcuts]]
+//! mod = "Control|Shift"
+//! keysym = "plus"
+//! func = "zoom"
+//! arg = 1
+//!
+//! [[mshortcuts]]
+//! mod = "Shift"
+//! button = 4
+//! func = "kscrollup"
+//! arg = 3
+//!
+//! [[key]]
+//! keysym = "Delete"
+//! mask = "Any"
+//! string = "\u001b[3~"
+//! appkey = -1
+//! ```
+//!
+//! Modifiers are names among Shift, Lock, Control, Mod1 to Mod5 and Any joined
+//! with `|`, or masks as numbers. Keysyms are the names Xlib knows them by,
+//! without `XK_`, and functions are the names of the functions in config.h and
+//! in src/config.rs. The shortcuts, mouse shortcuts and keys in the file are
+//! checked before the ones built in. A bad entry is reported on the standard
+//! error and left out, and a setting with a bad value, like a `tabspaces` of
+//! 0, keeps its value from config.h.
+
+use std::{
+    ffi::{c_char, c_int, c_uint, CString},
+    fmt::Display,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    ptr::addr_of,
+};
+
+use libc::wchar_t;
+use toml::{Table, Value};
+use x11::xlib::{
+    ControlMask, LockMask, Mod1Mask, Mod2Mask, Mod3Mask, Mod4Mask, Mod5Mask,
+    ShiftMask,
+};
+
+use crate::{
+    bindgen::{
+        self, allowaltscreen, allowwindowops, bellvolume, blinktimeout,
+        borderpx, chscale, colorname, cursorshape, cursorthickness, cwscale,
+        defaultattr, defaultbg, defaultcs, defaultfg, defaultrcs,
+        doubleclicktimeout, font, forcemousemod, histsize, ignoremod,
+        maxlatency, minlatency, mousebg, mousefg, shell, tabspaces, termname,
+        tripleclicktimeout, worddelimiters, Arg, KeySym,
+    },
+    config::Shortcut,
+    copymode::copymode,
+    export::{exportclip, exportfile, Format},
+    kscrolldown, kscrollprompt, kscrollup, len, newterm,
+    search::search,
+    selcmdoutput,
+};
+
+/// Largest number of lines of history, which are all allocated up front.
+const HISTSIZE_MAX: c_uint = 100_000;
+
+/// A mouse shortcut calling `func` with `arg` when `button` is pressed, or
+/// released if `release` is set, with the modifiers in `mod_`.
+pub(crate) struct MouseShortcut {
+    pub(crate) mod_: c_uint,
+    pub(crate) button: c_uint,
+    pub(crate) func: Func,
+    pub(crate) arg: Arg,
+    pub(crate) release: bool,
+}
+
+/// A key sending `string` to the program when `keysym` is pressed with the
+/// modifiers in `mask`, like the `Key` entries of config.h.
+pub(crate) struct Key {
+    pub(crate) keysym: KeySym,
+    pub(crate) mask: c_uint,
+    pub(crate) string: Vec<u8>,
+    /// whether application keypad mode must be on, if positive, or off, if
+    /// negative, and with 2 whether num lock must be off too
+    pub(crate) appkey: i8,
+    /// whether application cursor mode must be on or off in the same way
+    pub(crate) appcursor: i8,
+}
+
+/// The settings from the file that have no place in the globals of config.h.
+pub struct Settings {
+    pub(crate) shortcuts: Vec<Shortcut>,
+    pub(crate) mshortcuts: Vec<MouseShortcut>,
+    pub(crate) keys: Vec<Key>,
+    /// the number of columns and rows of a new window
+    pub cols: c_int,
+    pub rows: c_int,
+}
+
+static mut SETTINGS: Settings = Settings {
+    shortcuts: Vec::new(),
+    mshortcuts: Vec::new(),
+    keys: Vec::new(),
+    cols: 80,
+    rows: 24,
+};
+
+pub fn settings() -> &'static Settings {
+    unsafe { &*addr_of!(SETTINGS) }
+}
+
+/// How the `arg` of a shortcut is given for its function.
+#[derive(Clone, Copy)]
+enum ArgType {
+    /// it isn't used
+    None,
+    Int,
+    Float,
+    /// a string sent to the program
+    Str,
+    /// an export format: "text", "ansi" or "html"
+    Format,
+}
+
+/// The functions shortcuts call.
+type Func = fn(&Arg);
+
+/// Return a `Func` calling the function `f` of st.c or x.c.
+macro_rules! c {
+    ($f:ident) => {
+        |a| unsafe { bindgen::$f(a) }
+    };
+}
+
+/// The functions shortcuts can call, by name.
+const FUNCS: &[(&str, Func, ArgType)] = &[
+    ("clipcopy", c!(clipcopy), ArgType::None),
+    ("clippaste", c!(clippaste), ArgType::None),
+    ("selpaste", c!(selpaste), ArgType::None),
+    ("numlock", c!(numlock), ArgType::None),
+    ("zoom", c!(zoom), ArgType::Float),
+    ("zoomabs", c!(zoomabs), ArgType::Float),
+    ("zoomreset", c!(zoomreset), ArgType::None),
+    ("ttysend", c!(ttysend), ArgType::Str),
+    ("sendbreak", c!(sendbreak), ArgType::None),
+    ("toggleprinter", c!(toggleprinter), ArgType::None),
+    ("printscreen", c!(printscreen), ArgType::None),
+    ("printsel", c!(printsel), ArgType::None),
+    ("kscrollup", kscrollup, ArgType::Int),
+    ("kscrolldown", kscrolldown, ArgType::Int),
+    ("kscrollprompt", kscrollprompt, ArgType::Int),
+    ("selcmdoutput", selcmdoutput, ArgType::None),
+    ("newterm", newterm, ArgType::None),
+    ("exportclip", exportclip, ArgType::Format),
+    ("exportfile", exportfile, ArgType::Format),
+    ("search", search, ArgType::None),
+    ("copymode", copymode, ArgType::None),
+];
+
+/// Return the path of the settings file, if there is a home directory.
+pub fn path() -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("rt").join("rt.toml"))
+}
+
+/// Read the settings file, if there is one, and apply its settings. This has
+/// to be done before the terminal and the window are made.
+pub fn load() {
+    let Some(path) = path() else {
+        return;
+    };
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+        Err(e) => {
+            eprintln!("couldn't read {}: {e}", path.display());
+            return;
+        }
+    };
+    let table: Table = match text.parse() {
+        Ok(table) => table,
+        Err(e) => {
+            eprintln!("{}: {e}", path.display());
+            return;
+        }
+    };
+    apply(&path, &table);
+}
+
+/// Apply the settings in `table`, from the file at `path`.
+fn apply(path: &Path, table: &Table) {
+    let latencies = unsafe { (minlatency, maxlatency) };
+    for (key, v) in table {
+        if let Err(e) = set(path, key, v) {
+            warn(path, key, e);
+        }
+    }
+    // the latencies can only be checked together once both are set
+    unsafe {
+        if minlatency > maxlatency {
+            warn(path, "minlatency", "more than maxlatency");
+            (minlatency, maxlatency) = latencies;
+        }
+    }
+}
+
+fn warn(path: &Path, key: impl Display, e: impl Display) {
+    eprintln!("{}: {key}: {e}", path.display());
+}
+
+/// Apply the setting `key` with the value `v`, from the file at `path`.
+fn set(path: &Path, key: &str, v: &Value) -> Result<(), String> {
+    let s = unsafe { &mut *std::ptr::addr_of_mut!(SETTINGS) };
+    unsafe {
+        match key {
+            "font" => font = cstring(v)?,
+            "borderpx" => borderpx = bounded(v, 0..=i16::MAX.into())?,
+            "cwscale" => cwscale = positive(v)? as f32,
+            "chscale" => chscale = positive(v)? as f32,
+            "shell" => shell = cstring(v)?,
+            "termname" => termname = cstring(v)?,
+            "worddelimiters" => worddelimiters = wstring(v)?,
+            "doubleclicktimeout" => doubleclicktimeout = int(v)?,
+            "tripleclicktimeout" => tripleclicktimeout = int(v)?,
+            "allowaltscreen" => allowaltscreen = boolean(v)? as c_int,
+            "allowwindowops" => allowwindowops = boolean(v)? as c_int,
+            "minlatency" => minlatency = positive(v)?,
+            "maxlatency" => maxlatency = positive(v)?,
+            "blinktimeout" => blinktimeout = int(v)?,
+            "cursorthickness" => cursorthickness = int(v)?,
+            "bellvolume" => bellvolume = int(v)?,
+            "tabspaces" => tabspaces = bounded(v, 1..=i16::MAX as c_uint)?,
+            "histsize" => histsize = bounded(v, 0..=HISTSIZE_MAX)?,
+            "colorname" => colors(path, v)?,
+            "defaultfg" => defaultfg = color(v)?,
+            "defaultbg" => defaultbg = color(v)?,
+            "defaultcs" => defaultcs = color(v)?,
+            "defaultrcs" => defaultrcs = color(v)?,
+            "cursorshape" => cursorshape = int(v)?,
+            "cols" => s.cols = size(v)?,
+            "rows" => s.rows = size(v)?,
+            "mousefg" => mousefg = color(v)?,
+            "mousebg" => mousebg = color(v)?,
+            "defaultattr" => defaultattr = color(v)?,
+            "forcemousemod" => forcemousemod = modmask(v)?,
+            "ignoremod" => ignoremod = modmask(v)?,
+            "shortcuts" => s.shortcuts = entries(path, key, v, shortcut)?,
+            "mshortcuts" => s.mshortcuts = entries(path, key, v, mshortcut)?,
+            "key" => s.keys = entries(path, key, v, keyentry)?,
+            _ => return Err("unknown setting".into()),
+        }
+    }
+    Ok(())
+}
+
+/// Return the entries of the array of tables `v`, made with `f`, reporting
+/// and leaving out the bad ones.
+fn entries<T>(
+    path: &Path,
+    key: &str,
+    v: &Value,
+    f: fn(&Table) -> Result<T, String>,
+) -> Result<Vec<T>, String> {
+    let Value::Array(a) = v else {
+        return Err(format!("expected an array of tables, like [[{key}]]"));
+    };
+    let mut out = Vec::new();
+    for (i, v) in a.iter().enumerate() {
+        let res = match v {
+            Value::Table(t) => f(t),
+            _ => Err("expected a table".into()),
+        };
+        match res {
+            Ok(e) => out.push(e),
+            Err(e) => warn(path, format_args!("{key}[{i}]"), e),
+        }
+    }
+    Ok(out)
+}
+
+fn shortcut(t: &Table) -> Result<Shortcut, String> {
+    fields(t, &["mod", "keysym", "func", "arg"])?;
+    let (func, arg) = func(t)?;
+    Ok(Shortcut {
+        mod_: optional(t, "mod", modmask)?.unwrap_or(0),
+        keysym: field(t, "keysym", keysym)?,
+        func,
+        arg,
+    })
+}
+
+fn mshortcut(t: &Table) -> Result<MouseShortcut, String> {
+    fields(t, &["mod", "button", "func", "arg", "release"])?;
+    let (func, arg) = func(t)?;
+    Ok(MouseShortcut {
+        mod_: optional(t, "mod", modmask)?.unwrap_or(0),
+        button: field(t, "button", int)?,
+        func,
+        arg,
+        release: optional(t, "release", boolean)?.unwrap_or(false),
+    })
+}
+
+fn keyentry(t: &Table) -> Result<Key, String> {
+    fields(t, &["keysym", "mask", "string", "appkey", "appcursor"])?;
+    Ok(Key {
+        keysym: field(t, "keysym", keysym)?,
+        mask: optional(t, "mask", modmask)?.unwrap_or(0),
+        string: field(t, "string", string)?.as_bytes().to_vec(),
+        appkey: optional(t, "appkey", int)?.unwrap_or(0),
+        appcursor: optional(t, "appcursor", int)?.unwrap_or(0),
+    })
+}
+
+/// Return an error if the table `t` has other fields than `names`.
+fn fields(t: &Table, names: &[&str]) -> Result<(), String> {
+    match t.keys().find(|k| !names.contains(&k.as_str())) {
+        Some(k) => Err(format!("{k}: unknown field")),
+        None => Ok(()),
+    }
+}
+
+fn field<'a, T>(
+    t: &'a Table,
+    name: &str,
+    f: fn(&'a Value) -> Result<T, String>,
+) -> Result<T, String> {
+    optional(t, name, f)?.ok_or_else(|| format!("{name}: missing"))
+}
+
+fn optional<'a, T>(
+    t: &'a Table,
+    name: &str,
+    f: fn(&'a Value) -> Result<T, String>,
+) -> Result<Option<T>, String> {
+    t.get(name)
+        .map(f)
+        .transpose()
+        .map_err(|e| format!("{name}: {e}"))
+}
+
+/// Return the function named by the `func` field of the shortcut `t`, and the
+/// argument in its `arg` field.
+fn func(t: &Table) -> Result<(Func, Arg), String> {
+    let name = field(t, "func", string)?;
+    let Some(&(_, func, type_)) = FUNCS.iter().find(|f| f.0 == name) else {
+        return Err(format!("func: unknown function {name:?}"));
+    };
+    let Some(v) = t.get("arg") else {
+        return match type_ {
+            ArgType::None | ArgType::Int | ArgType::Float => {
+                Ok((func, Arg { i: 0 }))
+            }
+            _ => Err(format!("arg: missing for {name}")),
+        };
+    };
+    let arg = || -> Result<Arg, String> {
+        Ok(match type_ {
+            ArgType::None => Arg { i: 0 },
+            ArgType::Int => Arg { i: int(v)? },
+            ArgType::Float => Arg { f: float(v)? as f32 },
+            ArgType::Str => Arg { s: cstring(v)?.cast_const() },
+            ArgType::Format => {
+                let format = match string(v)? {
+                    "text" => Format::Text,
+                    "ansi" => Format::Ansi,
+                    "html" => Format::Html,
+                    s => return Err(format!("unknown format {s:?}")),
+                };
+                Arg { i: format as c_int }
+            }
+        })
+    };
+    Ok((func, arg().map_err(|e| format!("arg: {e}"))?))
+}
+
+fn int<T: TryFrom<i64>>(v: &Value) -> Result<T, String> {
+    let Value::Integer(n) = *v else {
+        return Err("expected an integer".into());
+    };
+    T::try_from(n).map_err(|_| format!("{n} is out of range"))
+}
+
+/// Return the integer `v`, which has to be in `range`.
+fn bounded<T>(v: &Value, range: RangeInclusive<T>) -> Result<T, String>
+where
+    T: TryFrom<i64> + PartialOrd + Display,
+{
+    match int(v)? {
+        n if range.contains(&n) => Ok(n),
+        n => Err(format!(
+            "{n} is not between {} and {}",
+            range.start(),
+            range.end()
+        )),
+    }
+}
+
+/// Return the number of columns or rows `v`.
+fn size(v: &Value) -> Result<c_int, String> {
+    match int(v)? {
+        n if n < 1 => Err(format!("{n} is too small")),
+        n => Ok(n),
+    }
+}
+
+fn float(v: &Value) -> Result<f64, String> {
+    match *v {
+        Value::Float(f) => Ok(f),
+        Value::Integer(n) => Ok(n as f64),
+        _ => Err("expected a number".into()),
+    }
+}
+
+/// Return the number `v`, which has to be more than 0.
+fn positive(v: &Value) -> Result<f64, String> {
+    match float(v)? {
+        f if f > 0.0 && f.is_finite() => Ok(f),
+        f => Err(format!("{f} is not a positive number")),
+    }
+}
+
+fn boolean(v: &Value) -> Result<bool, String> {
+    v.as_bool().ok_or_else(|| "expected true or false".into())
+}
+
+fn string(v: &Value) -> Result<&str, String> {
+    v.as_str().ok_or_else(|| "expected a string".into())
+}
+
+/// Return the string `v` as a C string, which is kept for good.
+fn cstring(v: &Value) -> Result<*mut c_char, String> {
+    let s = CString::new(string(v)?).map_err(|_| "contains a NUL")?;
+    Ok(s.into_raw())
+}
+
+/// Return the string `v` as a wide C string, which is kept for good.
+fn wstring(v: &Value) -> Result<*mut wchar_t, String> {
+    let s = string(v)?;
+    let w: Vec<wchar_t> = s.chars().map(|c| c as wchar_t).chain([0]).collect();
+    Ok(Box::leak(w.into_boxed_slice()).as_mut_ptr())
+}
+
+/// Return the index in `colorname` given by `v`.
+fn color(v: &Value) -> Result<c_uint, String> {
+    let n = int(v)?;
+    if n as usize >= len(&raw const colorname) {
+        return Err(format!("there is no color {n}"));
+    }
+    Ok(n)
+}
+
+/// Set the colors in the table `v`, which are given by their index.
+fn colors(path: &Path, v: &Value) -> Result<(), String> {
+    let Value::Table(t) = v else {
+        return Err("expected a table of colors by index".into());
+    };
+    for (k, v) in t {
+        let res = k
+            .parse()
+            .map_err(|_| "expected the index of a color".to_string())
+            .and_then(|i| color(&Value::Integer(i)))
+            .and_then(|i| Ok((i, cstring(v)?)));
+        match res {
+            Ok((i, name)) => unsafe {
+                colorname[i as usize] = name.cast_const()
+            },
+            Err(e) => warn(path, format_args!("colorname.{k}"), e),
+        }
+    }
+    Ok(())
+}
+
+/// Return the modifier mask named by `v`, like "Control|Shift", or given as a
+/// number.
+fn modmask(v: &Value) -> Result<c_uint, String> {
+    if v.is_integer() {
+        return int(v);
+    }
+    let s = string(v)?;
+    let mut mask = 0;
+    for name in s.split('|').map(str::trim).filter(|s| !s.is_empty()) {
+        mask |= match name {
+            "Shift" => ShiftMask,
+            "Lock" => LockMask,
+            "Control" => ControlMask,
+            "Mod1" => Mod1Mask,
+            "Mod2" => Mod2Mask,
+            "Mod3" => Mod3Mask,
+            "Mod4" => Mod4Mask,
+            "Mod5" => Mod5Mask,
+            // XK_ANY_MOD
+            "Any" => c_uint::MAX,
+            _ => return Err(format!("unknown modifier {name:?}")),
+        };
+    }
+    Ok(mask)
+}
+
+fn keysym(v: &Value) -> Result<KeySym, String> {
+    let s = string(v)?;
+    let name = CString::new(s).map_err(|_| "contains a NUL")?;
+    match unsafe { bindgen::XStringToKeysym(name.as_ptr()) } {
+        0 => Err(format!("unknown keysym {s:?}")),
+        k => Ok(k),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(s: &str) -> Table {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(int::<c_int>(&Value::Integer(-3)), Ok(-3));
+        assert!(int::<c_uint>(&Value::Integer(-3)).is_err());
+        assert!(int::<c_int>(&Value::Float(1.0)).is_err());
+        assert_eq!(size(&Value::Integer(80)), Ok(80));
+        assert!(size(&Value::Integer(0)).is_err());
+        assert_eq!(float(&Value::Integer(2)), Ok(2.0));
+        assert_eq!(float(&Value::Float(0.5)), Ok(0.5));
+        assert!(float(&Value::String("1".into())).is_err());
+        assert_eq!(boolean(&Value::Boolean(true)), Ok(true));
+        assert!(boolean(&Value::Integer(1)).is_err());
+    }
+
+    #[test]
+    fn ranges() {
+        let path = Path::new("rt.toml");
+        let set = |key, v| set(path, key, &v);
+        assert!(set("tabspaces", Value::Integer(0)).is_err());
+        assert!(set("tabspaces", Value::Integer(1 << 31)).is_err());
+        assert!(set("cwscale", Value::Float(0.0)).is_err());
+        assert!(set("chscale", Value::Integer(-1)).is_err());
+        assert!(set("chscale", Value::Float(f64::NAN)).is_err());
+        assert!(set("histsize", Value::Integer(1 << 31)).is_err());
+        assert!(
+            set("histsize", Value::Integer(HISTSIZE_MAX as i64 + 1)).is_err()
+        );
+        assert!(set("borderpx", Value::Integer(-1)).is_err());
+        assert!(set("minlatency", Value::Integer(0)).is_err());
+        assert!(set("maxlatency", Value::Float(-33.0)).is_err());
+        assert_eq!(
+            bounded::<c_uint>(&Value::Integer(0), 1..=8),
+            Err("0 is not between 1 and 8".into())
+        );
+
+        // a minimum latency above the maximum leaves both as they were
+        let latencies = unsafe { (minlatency, maxlatency) };
+        apply(path, &table("minlatency = 50\nmaxlatency = 40"));
+        assert_eq!(unsafe { (minlatency, maxlatency) }, latencies);
+    }
+
+    #[test]
+    fn modifiers() {
+        let m = |s: &str| modmask(&Value::String(s.into()));
+        assert_eq!(m("Control|Shift"), Ok(ControlMask | ShiftMask));
+        assert_eq!(m(" Mod1 | Mod4 "), Ok(Mod1Mask | Mod4Mask));
+        assert_eq!(m(""), Ok(0));
+        assert_eq!(m("Any"), Ok(c_uint::MAX));
+        assert!(m("Ctrl").is_err());
+        assert_eq!(modmask(&Value::Integer(5)), Ok(5));
+    }
+
+    #[test]
+    fn keysyms() {
+        let k = |s: &str| keysym(&Value::String(s.into()));
+        assert_eq!(k("Delete"), Ok(x11::keysym::XK_Delete as KeySym));
+        assert_eq!(k("plus"), Ok(x11::keysym::XK_plus as KeySym));
+        assert!(k("NoSuchKey").is_err());
+        assert!(k("a\0b").is_err());
+    }
+
+    #[test]
+    fn shortcuts() {
+        let s = shortcut(&table(
+            r#"mod = "Control|Shift"
+               keysym = "plus"
+               func = "zoom"
+               arg = 1"#,
+        ))
+        .unwrap();
+        assert_eq!(s.mod_, ControlMask | ShiftMask);
+        assert_eq!(s.keysym, x11::keysym::XK_plus as KeySym);
+        assert_eq!(unsafe { s.arg.f }, 1.0);
+
+        let s = shortcut(&table("keysym = \"Home\"\nfunc = \"kscrollup\""));
+        assert_eq!(s.map(|s| (s.mod_, unsafe { s.arg.i })), Ok((0, 0)));
+        let f = table("keysym = \"F1\"\nfunc = \"exportfile\"\narg = \"html\"");
+        let s = shortcut(&f).unwrap();
+        assert_eq!(unsafe { s.arg.i }, Format::Html as c_int);
+
+        let err = |s: &str| shortcut(&table(s)).err().unwrap();
+        assert_eq!(
+            err("keysym = \"F1\"\nfunc = \"nope\""),
+            "func: unknown function \"nope\""
+        );
+        assert_eq!(
+            err("keysym = \"F1\"\nfunc = \"ttysend\""),
+            "arg: missing for ttysend"
+        );
+        assert_eq!(
+            err("keysym = \"F1\"\nfunc = \"exportclip\"\narg = \"pdf\""),
+            "arg: unknown format \"pdf\""
+        );
+        assert_eq!(err("func = \"zoomreset\""), "keysym: missing");
+        assert_eq!(
+            err("keysym = \"F1\"\nfunc = \"zoomreset\"\nkey = 1"),
+            "key: unknown field"
+        );
+    }
+
+    #[test]
+    fn mouse_shortcuts() {
+        let m = mshortcut(&table(
+            "mod = \"Shift\"\nbutton = 4\nfunc = \"kscrollup\"\narg = 3",
+        ))
+        .unwrap();
+        assert_eq!((m.mod_, m.button, m.release), (ShiftMask, 4, false));
+        assert_eq!(unsafe { m.arg.i }, 3);
+        let m = mshortcut(&table(
+            "button = 2\nfunc = \"selpaste\"\nrelease = true",
+        ))
+        .unwrap();
+        assert!(m.release);
+        assert!(mshortcut(&table("button = -1\nfunc = \"selpaste\"")).is_err());
+    }
+
+    #[test]
+    fn keys() {
+        let k = keyentry(&table(
+            r#"keysym = "Delete"
+               mask = "Any"
+               string = "\u001b[3~"
+               appkey = -1"#,
+        ))
+        .unwrap();
+        assert_eq!(k.mask, c_uint::MAX);
+        assert_eq!(k.string, b"\x1b[3~");
+        assert_eq!((k.appkey, k.appcursor), (-1, 0));
+        assert!(keyentry(&table("keysym = \"Delete\"")).is_err());
+        assert!(keyentry(&table(
+            "keysym = \"Delete\"\nstring = \"x\"\nappkey = 300"
+        ))
+        .is_err());
+    }
+}
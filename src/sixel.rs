@@ -0,0 +1,279 @@
+//! Decoder for DEC sixel graphics, sent in DCS strings:
+//!
+//! ESC 'P' <P1> ; <P2> ; <P3> 'q' <data> ST
+//!
+//! The data is drawn in bands of six rows of pixels. Each character from '?'
+//! to '~' gives the six pixels of one column of the band, in the current
+//! color:
+//!
+//! - `" Pan ; Pad ; Ph ; Pv` sets the raster attributes, the last two being
+//!   the size of the image, which is ignored: the image is only as large as
+//!   what is drawn
+//! - `# Pc` selects color register `Pc`, and `# Pc ; Pu ; Px ; Py ; Pz`
+//!   defines it in HLS (`Pu` = 1) or RGB (`Pu` = 2)
+//! - `! Pn <c>` repeats the sixel `c` `Pn` times
+//! - `$` returns to the start of the band and `-` moves to the next one
+
+use std::ffi::c_int;
+
+use crate::graphics::Image;
+
+/// Number of color registers.
+pub(crate) const REGISTERS: usize = 1024;
+/// Largest width and height of an image.
+pub(crate) const SIXEL_MAX: usize = 4096;
+
+/// The default palette of the VT340, in percent.
+const VT340: [[u32; 3]; 16] = [
+    [0, 0, 0],
+    [20, 20, 80],
+    [80, 13, 13],
+    [20, 80, 20],
+    [80, 20, 80],
+    [20, 80, 80],
+    [80, 80, 20],
+    [53, 53, 53],
+    [26, 26, 26],
+    [33, 33, 60],
+    [60, 26, 26],
+    [33, 60, 33],
+    [60, 33, 60],
+    [33, 60, 60],
+    [60, 60, 33],
+    [80, 80, 80],
+];
+
+/// Decode the sixel `data` of a DCS string with the parameters `params`. The
+/// pixels that are not drawn are transparent if the second parameter is 1,
+/// and have the color `bg`, as 0xrrggbb, otherwise. The aspect ratio in the
+/// first parameter is ignored, pixels are always square. The pixels past
+/// `max`, the largest width and height of the image, are dropped.
+///
+/// Return `None` if the image is empty.
+pub fn decode(
+    params: &[c_int],
+    data: &[u8],
+    bg: u32,
+    max: (usize, usize),
+) -> Option<Image> {
+    let mut palette = [0xff000000; REGISTERS];
+    for (c, &[r, g, b]) in palette.iter_mut().zip(VT340.iter()) {
+        *c = rgb(r, g, b);
+    }
+    let transparent = params.get(1) == Some(&1);
+
+    // rows of pixels, where unset pixels are 0
+    let mut rows: Vec<Vec<u32>> = Vec::new();
+    let mut color = palette[0];
+    let (mut x, mut y) = (0usize, 0);
+
+    let mut i = 0;
+    while i < data.len() {
+        let c = data[i];
+        i += 1;
+        match c {
+            b'"' => {
+                numbers(data, &mut i);
+            }
+            b'#' => {
+                let p = numbers(data, &mut i);
+                let reg = p[0] as usize % REGISTERS;
+                match p[..] {
+                    [_, 1, h, l, s, ..] => palette[reg] = hls(h, l, s),
+                    [_, 2, r, g, b, ..] => {
+                        palette[reg] = rgb(r.min(100), g.min(100), b.min(100))
+                    }
+                    _ => {}
+                }
+                color = palette[reg];
+            }
+            b'!' => {
+                let n = numbers(data, &mut i)[0].max(1) as usize;
+                if let Some(&c @ b'?'..=b'~') = data.get(i) {
+                    i += 1;
+                    // only the columns up to the largest width are drawn
+                    for x in x..x.saturating_add(n).min(max.0) {
+                        sixel(&mut rows, x, y, c, color, max);
+                    }
+                    x = x.saturating_add(n);
+                }
+            }
+            b'$' => x = 0,
+            b'-' => {
+                x = 0;
+                y += 6;
+            }
+            b'?'..=b'~' => {
+                sixel(&mut rows, x, y, c, color, max);
+                x += 1;
+            }
+            // anything else, like the line breaks some encoders add, is
+            // ignored
+            _ => {}
+        }
+    }
+
+    let width = rows.iter().map(Vec::len).max().unwrap_or(0);
+    let height = rows.len();
+    if width == 0 || height == 0 {
+        return None;
+    }
+    let fill = if transparent { 0 } else { 0xff000000 | bg };
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let row = rows.get(y).map_or(&[][..], Vec::as_slice);
+        pixels.extend((0..width).map(|x| match row.get(x) {
+            Some(&p) if p != 0 => p,
+            _ => fill,
+        }));
+    }
+    Some(Image { width, height, pixels })
+}
+
+/// Parse the `;` separated numbers at `data[*i..]`, advancing `i` past them.
+/// There is always at least one, and missing ones are 0.
+fn numbers(data: &[u8], i: &mut usize) -> Vec<u32> {
+    let mut p = vec![0u32];
+    while let Some(&c) = data.get(*i) {
+        match c {
+            b'0'..=b'9' => {
+                let n = p.last_mut().unwrap();
+                *n = n.saturating_mul(10).saturating_add((c - b'0') as u32);
+            }
+            b';' => p.push(0),
+            _ => break,
+        }
+        *i += 1;
+    }
+    p
+}
+
+/// Draw the sixel character `c` at column `x` of the band starting at row `y`,
+/// dropping the pixels past `max`.
+fn sixel(
+    rows: &mut Vec<Vec<u32>>,
+    x: usize,
+    y: usize,
+    c: u8,
+    color: u32,
+    max: (usize, usize),
+) {
+    let bits = c - b'?';
+    if bits == 0 || x >= max.0 {
+        return;
+    }
+    for b in 0..6 {
+        let y = y + b;
+        if bits & 1 << b == 0 || y >= max.1 {
+            continue;
+        }
+        if rows.len() <= y {
+            rows.resize_with(y + 1, Vec::new);
+        }
+        let row = &mut rows[y];
+        if row.len() <= x {
+            row.resize(x + 1, 0);
+        }
+        row[x] = color;
+    }
+}
+
+/// Return the opaque color with the red, green and blue percentages `r`, `g`
+/// and `b`.
+fn rgb(r: u32, g: u32, b: u32) -> u32 {
+    let c = |p: u32| (p * 255 + 50) / 100;
+    0xff000000 | c(r) << 16 | c(g) << 8 | c(b)
+}
+
+/// Return the opaque color with hue `h`, lightness `l` and saturation `s`,
+/// where the hue is in degrees starting at blue, as on the VT340, and the
+/// others in percent.
+fn hls(h: u32, l: u32, s: u32) -> u32 {
+    let h = ((h % 360 + 240) % 360) as f64;
+    let l = l.min(100) as f64 / 100.0;
+    let s = s.min(100) as f64 / 100.0;
+
+    let c = (1.0 - (2.0 * l - 1.0).abs()) * s;
+    let x = c * (1.0 - ((h / 60.0) % 2.0 - 1.0).abs());
+    let m = l - c / 2.0;
+    let (r, g, b) = match h as u32 / 60 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let p = |v: f64| ((v + m) * 100.0).round() as u32;
+    rgb(p(r), p(g), p(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: u32 = 0xffff0000;
+    const BG: u32 = 0xff123456;
+    const MAX: (usize, usize) = (SIXEL_MAX, SIXEL_MAX);
+
+    #[test]
+    fn empty() {
+        assert!(decode(&[0], b"", 0x123456, MAX).is_none());
+        assert!(decode(&[0], b"???$-", 0x123456, MAX).is_none());
+    }
+
+    #[test]
+    fn pixels() {
+        // two columns: the top pixel, and the top and third pixels
+        let image = decode(&[0], b"#1;2;100;0;0@D", 0x123456, MAX).unwrap();
+        assert_eq!((image.width, image.height), (2, 3));
+        assert_eq!(image.pixels, [RED, RED, BG, BG, BG, RED]);
+    }
+
+    #[test]
+    fn transparent_and_raster() {
+        // the image is not as large as the raster attributes say
+        let data = b"\"1;1;4096;4096#1;2;100;0;0@?@";
+        let image = decode(&[0, 1], data, 0x123456, MAX).unwrap();
+        assert_eq!((image.width, image.height), (3, 1));
+        assert_eq!(image.pixels, [RED, 0, RED]);
+    }
+
+    #[test]
+    fn cropped() {
+        let image =
+            decode(&[0], b"#1;2;100;0;0!9~-~", 0x123456, (4, 3)).unwrap();
+        assert_eq!((image.width, image.height), (4, 3));
+        assert_eq!(image.pixels, [RED; 12]);
+    }
+
+    #[test]
+    fn bands() {
+        let image = decode(&[0], b"#1;2;100;0;0@$-@", 0x123456, MAX).unwrap();
+        assert_eq!((image.width, image.height), (1, 7));
+        assert_eq!(image.pixels[0], RED);
+        assert_eq!(image.pixels[6], RED);
+    }
+
+    #[test]
+    fn repeat() {
+        let image = decode(&[0], b"#1;2;100;0;0!3@", 0x123456, MAX).unwrap();
+        assert_eq!(image.pixels, [RED; 3]);
+
+        // repeats past SIXEL_MAX are cut off
+        let image = decode(&[0], b"!4294967295@!9@", 0x123456, MAX).unwrap();
+        assert_eq!((image.width, image.height), (SIXEL_MAX, 1));
+    }
+
+    #[test]
+    fn colors() {
+        assert_eq!(rgb(100, 50, 0), 0xffff8000);
+        // the hue starts at blue
+        assert_eq!(hls(0, 50, 100), 0xff0000ff);
+        assert_eq!(hls(120, 50, 100), 0xffff0000);
+        assert_eq!(hls(240, 50, 100), 0xff00ff00);
+        // the default palette is the VT340's, and registers wrap around
+        let image = decode(&[0], b"#1025@", 0, MAX).unwrap();
+        assert_eq!(image.pixels, [rgb(20, 20, 80)]);
+    }
+}
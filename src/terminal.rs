@@ -0,0 +1,484 @@
+//! A terminal that is not attached to an X window or a tty.
+
+use std::{
+    ffi::c_int,
+    path::Path,
+    ptr::null_mut,
+    sync::{Mutex, PoisonError},
+};
+
+use crate::{
+    bindgen::{
+        sel, term, win, xw, Display, Glyph_, Rune, Selection, Term, TermWindow,
+    },
+    export::{export, Format},
+    graphics::Image,
+    selinit, state, tnew, treset, tresize, twrite, TermState, ATTR_WDUMMY,
+};
+
+/// A terminal emulator that can be used without an X display, for example to
+/// drive TUI programs in tests.
+///
+/// Bytes passed to [`Terminal::write`] go through the same code as output from
+/// the shell in `rt`, and replies to the program, such as cursor position
+/// reports, are collected for [`Terminal::take_output`]. Window operations like
+/// setting the title or the selection are ignored.
+///
+/// Character widths come from wcwidth(3), so wide characters are only
+/// recognized if the process has set a UTF-8 `LC_CTYPE` locale.
+pub struct Terminal {
+    term: Term,
+    sel: Selection,
+    win: TermWindow,
+    state: TermState,
+    /// the bytes of an incomplete UTF-8 sequence at the end of the last write
+    pending: Vec<u8>,
+}
+
+impl Terminal {
+    /// Create a terminal of `cols` x `rows` characters.
+    pub fn new(cols: c_int, rows: c_int) -> Self {
+        let mut t = Self {
+            term: Term::default(),
+            sel: Selection::default(),
+            win: TermWindow::default(),
+            state: TermState::new(),
+            pending: Vec::new(),
+        };
+        t.state.output = Some(Vec::new());
+        t.with(|| {
+            tnew(cols, rows);
+            selinit();
+        });
+        t
+    }
+
+    /// Run `f` with this terminal swapped into the globals the emulator works
+    /// on, hiding any X display from it.
+    ///
+    /// Only one terminal can use the globals at a time, so this waits for
+    /// other threads doing the same. The globals are restored even if `f`
+    /// panics.
+    pub(crate) fn with<T>(&mut self, f: impl FnOnce() -> T) -> T {
+        let _lock = LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        let swapped = Swapped::new(self);
+        let ret = f();
+        drop(swapped);
+        ret
+    }
+
+    fn swap(&mut self) {
+        unsafe {
+            std::ptr::swap(&mut self.term, &raw mut term);
+            std::ptr::swap(&mut self.sel, &raw mut sel);
+            std::ptr::swap(&mut self.win, &raw mut win);
+        }
+        std::mem::swap(&mut self.state, unsafe { state() });
+    }
+
+    /// Process `bytes` as output from a program running in the terminal.
+    pub fn write(&mut self, bytes: &[u8]) {
+        let mut buf = std::mem::take(&mut self.pending);
+        buf.extend_from_slice(bytes);
+        let n = self.with(|| twrite(&buf, false));
+        // keep any incomplete UTF-8 byte sequence for the next call
+        buf.drain(..n);
+        self.pending = buf;
+    }
+
+    /// Resize the terminal to `cols` x `rows` characters.
+    pub fn resize(&mut self, cols: c_int, rows: c_int) {
+        self.with(|| tresize(cols, rows));
+    }
+
+    /// Reset the terminal to its initial state, keeping its size and history.
+    pub fn reset(&mut self) {
+        self.with(treset);
+    }
+
+    /// Return the number of columns and rows of the terminal.
+    pub fn size(&self) -> (c_int, c_int) {
+        (self.term.col, self.term.row)
+    }
+
+    /// Return the column and row of the cursor.
+    pub fn cursor(&self) -> (c_int, c_int) {
+        (self.term.c.x, self.term.c.y)
+    }
+
+    /// Return the `MODE_*` flags of the terminal, such as `MODE_ALTSCREEN`.
+    pub fn mode(&self) -> c_int {
+        self.term.mode
+    }
+
+    /// Return the window mode flags from [`crate::win`], such as
+    /// `MODE_APPCURSOR` or `MODE_HIDE`.
+    pub fn win_mode(&self) -> c_int {
+        self.win.mode
+    }
+
+    /// Return the glyph at column `x` of row `y` of the screen.
+    pub fn cell(&self, x: c_int, y: c_int) -> Option<Glyph_> {
+        let (cols, rows) = self.size();
+        if !(0..cols).contains(&x) || !(0..rows).contains(&y) {
+            return None;
+        }
+        unsafe {
+            Some(*(*self.term.line.offset(y as isize)).offset(x as isize))
+        }
+    }
+
+    /// Return the combining marks drawn over the character at column `x` of row
+    /// `y` of the screen.
+    pub fn cell_marks(&self, x: c_int, y: c_int) -> Option<&[Rune]> {
+        self.cell(x, y).map(|g| self.state.marks(&g))
+    }
+
+    /// Return the URI of the hyperlink on the character at column `x` of row
+    /// `y` of the screen, set with OSC 8.
+    pub fn cell_link(&self, x: c_int, y: c_int) -> Option<&str> {
+        let link = self.state.link(self.cell(x, y)?.link)?;
+        std::str::from_utf8(link).ok()
+    }
+
+    /// Return the `SEM_*` marks of row `y` of the screen, set with OSC 133.
+    pub fn line_sem(&self, y: c_int) -> Option<u8> {
+        self.state.sem.get(usize::try_from(y).ok()?).copied()
+    }
+
+    /// Return the exit status of the last command, reported with OSC 133 D.
+    pub fn exit_status(&self) -> Option<c_int> {
+        self.state.status
+    }
+
+    /// Return the working directory last reported by the program with OSC 7.
+    pub fn cwd(&self) -> Option<&Path> {
+        self.state.cwd.as_deref()
+    }
+
+    /// Return the `LATTR_*` size attribute of row `y` of the screen, set by the
+    /// DECDHL, DECDWL and DECSWL escape sequences.
+    pub fn line_attr(&self, y: c_int) -> Option<u8> {
+        self.state.lattr.get(usize::try_from(y).ok()?).copied()
+    }
+
+    /// Return the image shown in the cell at column `x` of row `y` of the
+    /// screen, if any.
+    pub fn image_at(&self, x: c_int, y: c_int) -> Option<&Image> {
+        self.cell(x, y)?;
+        let line = unsafe { *self.term.line.offset(y as isize) };
+        let strips = self.state.images.get(&(line as usize))?;
+        // later images are drawn over earlier ones
+        strips.iter().rev().find(|s| s.covers(x)).map(|s| &*s.image)
+    }
+
+    /// Return the text of row `y` of the screen, without trailing spaces.
+    /// Combining marks follow the characters they are drawn over.
+    pub fn line_text(&self, y: c_int) -> Option<String> {
+        let (cols, _) = self.size();
+        let mut s = String::new();
+        for x in 0..cols {
+            let g = self.cell(x, y)?;
+            if g.mode & ATTR_WDUMMY as u16 != 0 {
+                continue;
+            }
+            s.push(char::from_u32(g.u).unwrap_or(char::REPLACEMENT_CHARACTER));
+            let marks = self.state.marks(&g);
+            s.extend(marks.iter().filter_map(|&u| char::from_u32(u)));
+        }
+        s.truncate(s.trim_end_matches(' ').len());
+        Some(s)
+    }
+
+    /// Return the text of the whole screen, one line per row.
+    pub fn text(&self) -> String {
+        let (_, rows) = self.size();
+        let lines: Vec<_> =
+            (0..rows).filter_map(|y| self.line_text(y)).collect();
+        lines.join("\n")
+    }
+
+    /// Serialize the history, unless the alternate screen is shown, and the
+    /// screen in `format`.
+    pub fn export(&mut self, format: Format) -> Vec<u8> {
+        self.with(|| export(format))
+    }
+
+    /// Return the replies the terminal has sent to the program since the last
+    /// call.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(self.state.output.get_or_insert_with(Vec::new))
+    }
+}
+
+/// Held while a [`Terminal`] is swapped into the globals.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Swaps a [`Terminal`] into the globals and back out when dropped.
+struct Swapped<'a> {
+    t: &'a mut Terminal,
+    dpy: *mut Display,
+}
+
+impl<'a> Swapped<'a> {
+    fn new(t: &'a mut Terminal) -> Self {
+        let dpy = unsafe { std::ptr::replace(&raw mut xw.dpy, null_mut()) };
+        t.swap();
+        Self { t, dpy }
+    }
+}
+
+impl Drop for Swapped<'_> {
+    fn drop(&mut self) {
+        self.t.swap();
+        unsafe {
+            xw.dpy = self.dpy;
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let t = &self.term;
+        unsafe {
+            for y in 0..t.row as isize {
+                libc::free((*t.line.offset(y)).cast());
+                libc::free((*t.alt.offset(y)).cast());
+            }
+            for i in 0..t.histmax as isize {
+                libc::free((*t.hist.offset(i)).cast());
+            }
+            libc::free(t.line.cast());
+            libc::free(t.alt.cast());
+            libc::free(t.hist.cast());
+            libc::free(t.dirty.cast());
+            libc::free(t.tabs.cast());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        win::{MODE_APPCURSOR, MODE_HIDE},
+        ATTR_BOLD, ATTR_REVERSE, LATTR_DWIDTH, LATTR_SINGLE, MODE_ALTSCREEN,
+        MODE_INSERT, SEM_OUTPUT, SEM_PROMPT,
+    };
+
+    #[test]
+    fn text() {
+        let mut t = Terminal::new(10, 3);
+        t.write(b"hello\r\nworld");
+        assert_eq!(t.text(), "hello\nworld\n");
+        assert_eq!(t.cursor(), (5, 1));
+
+        // an incomplete UTF-8 sequence is kept for the next write
+        t.write(b"\r\n\xc3");
+        t.write(b"\xa9");
+        assert_eq!(t.line_text(2).as_deref(), Some("\u{e9}"));
+        assert!(t.cell(10, 0).is_none());
+    }
+
+    #[test]
+    fn wrap_and_scroll() {
+        let mut t = Terminal::new(4, 2);
+        t.write(b"abcdef");
+        assert_eq!(t.text(), "abcd\nef");
+        t.write(b"\r\nxy");
+        assert_eq!(t.text(), "ef\nxy");
+        assert_eq!(t.cursor(), (2, 1));
+    }
+
+    #[test]
+    fn cursor_movement() {
+        let mut t = Terminal::new(10, 5);
+        t.write(b"\x1b[3;4H");
+        assert_eq!(t.cursor(), (3, 2));
+        t.write(b"\x1b[2A\x1b[5C");
+        assert_eq!(t.cursor(), (8, 0));
+        // the cursor stays on the screen
+        t.write(b"\x1b[99B\x1b[99D");
+        assert_eq!(t.cursor(), (0, 4));
+    }
+
+    #[test]
+    fn attributes() {
+        let mut t = Terminal::new(10, 2);
+        t.write(b"\x1b[1;7mA\x1b[0mB");
+        let a = t.cell(0, 0).unwrap();
+        let b = t.cell(1, 0).unwrap();
+        assert_eq!(a.u, 'A' as Rune);
+        assert_ne!(a.mode & (ATTR_BOLD | ATTR_REVERSE) as u16, 0);
+        assert_eq!(b.mode & (ATTR_BOLD | ATTR_REVERSE) as u16, 0);
+    }
+
+    #[test]
+    fn modes() {
+        let mut t = Terminal::new(10, 2);
+        t.write(b"\x1b[4h\x1b[?1h\x1b[?25l");
+        assert_ne!(t.mode() & MODE_INSERT, 0);
+        assert_ne!(t.win_mode() & MODE_APPCURSOR, 0);
+        assert_ne!(t.win_mode() & MODE_HIDE, 0);
+
+        t.write(b"main\x1b[?1049h");
+        assert_ne!(t.mode() & MODE_ALTSCREEN, 0);
+        assert_eq!(t.text(), "\n");
+        t.write(b"\x1b[?1049l");
+        assert_eq!(t.mode() & MODE_ALTSCREEN, 0);
+        assert_eq!(t.line_text(0).as_deref(), Some("main"));
+
+        t.write(b"\x1b[4l");
+        assert_eq!(t.mode() & MODE_INSERT, 0);
+    }
+
+    #[test]
+    fn replies() {
+        let mut t = Terminal::new(10, 5);
+        // device attributes
+        t.write(b"\x1b[c");
+        assert_eq!(t.take_output(), b"\x1b[?62;4c");
+        // cursor position report
+        t.write(b"\x1b[2;3H\x1b[6n");
+        assert_eq!(t.take_output(), b"\x1b[2;3R");
+        // device status report
+        t.write(b"\x1b[5n");
+        assert_eq!(t.take_output(), b"\x1b[0n");
+        assert!(t.take_output().is_empty());
+
+        // mode reports
+        t.write(b"\x1b[?25$p\x1b[4$p\x1b[?3$p\x1b[?9999$p");
+        assert_eq!(
+            t.take_output(),
+            b"\x1b[?25;1$y\x1b[4;2$y\x1b[?3;4$y\x1b[?9999;0$y"
+        );
+    }
+
+    #[test]
+    fn resize() {
+        let mut t = Terminal::new(10, 3);
+        t.write(b"abc\r\ndef");
+        t.resize(5, 2);
+        assert_eq!(t.size(), (5, 2));
+        assert_eq!(t.cursor().1, 1);
+        assert_eq!(t.line_text(1).as_deref(), Some("def"));
+    }
+
+    #[test]
+    fn resize_altscreen() {
+        let mut t = Terminal::new(10, 3);
+        t.write(b"0123456789ab\x1b[?1049hxyz");
+        t.resize(5, 4);
+        assert_eq!(t.text(), "\n  xyz\n\n");
+        // the saved cursor moves with the reflowed line it was on
+        t.write(b"\x1b[?1049lc");
+        assert_eq!(t.text(), "01234\n56789\nabc\n");
+        assert_eq!(t.cursor(), (3, 2));
+    }
+
+    #[test]
+    fn resize_double_size() {
+        let mut t = Terminal::new(10, 3);
+        t.write(b"0123456789ab\r\n\x1b#6abcd\r\nxyz");
+        t.resize(4, 4);
+        // the double width line is cut off instead of wrapped
+        assert_eq!(t.text(), "4567\n89ab\nabcd\nxyz");
+        assert_eq!(t.line_attr(2), Some(LATTR_DWIDTH));
+        assert_eq!(t.line_attr(3), Some(LATTR_SINGLE));
+        assert_eq!(t.cursor(), (3, 3));
+
+        // the line in the history is double width too when it comes back
+        t.resize(4, 1);
+        t.resize(10, 4);
+        assert_eq!(t.text(), "0123456789\nab\nabcd\nxyz");
+        assert_eq!(t.line_attr(2), Some(LATTR_DWIDTH));
+    }
+
+    #[test]
+    fn resize_marks() {
+        let mut t = Terminal::new(10, 4);
+        t.write(b"\x1b]133;A\x07$ abcdefgh\r\n\x1b]133;C\x07out");
+        t.resize(5, 4);
+        assert_eq!(t.text(), "$ abc\ndefgh\nout\n");
+        assert_eq!(t.line_sem(0), Some(SEM_PROMPT));
+        assert_eq!(t.line_sem(1), Some(0));
+        assert_eq!(t.line_sem(2), Some(SEM_OUTPUT));
+
+        // and back out of the history
+        t.resize(5, 1);
+        t.resize(10, 4);
+        assert_eq!(t.line_sem(0), Some(SEM_PROMPT));
+        assert_eq!(t.line_sem(1), Some(SEM_OUTPUT));
+    }
+
+    #[test]
+    fn resize_images() {
+        let mut t = Terminal::new(10, 5);
+        // a 1x1 kitty image after wrapped text
+        t.write(b"0123456789ab\x1b_Ga=T,f=24,s=1,v=1;/wAA\x1b\\");
+        assert!(t.image_at(2, 1).is_some());
+        t.resize(5, 5);
+        assert_eq!(t.text(), "01234\n56789\nab\n\n");
+        assert!(t.image_at(2, 1).is_none());
+        assert!(t.image_at(2, 2).is_some());
+
+        t.resize(2, 8);
+        assert!(t.image_at(0, 6).is_some());
+
+        // images past the end of the text are cut off at the edge
+        let mut t = Terminal::new(10, 3);
+        t.write(b"ab\x1b[9G\x1b_Ga=T,f=24,s=1,v=1;/wAA\x1b\\\r\n");
+        assert!(t.image_at(8, 0).is_some());
+        t.resize(5, 3);
+        assert!((0..5).all(|x| t.image_at(x, 0).is_none()));
+    }
+
+    #[test]
+    fn marks_collected() {
+        unsafe {
+            libc::setlocale(libc::LC_CTYPE, c"C.UTF-8".as_ptr());
+        }
+        // more sequences of combining marks than the terminal keeps, most of
+        // which scroll out of the history
+        let mark = |i: u32| char::from_u32(0x300 + i).unwrap();
+        let mut glyphs = Vec::new();
+        for i in 0..112 {
+            for j in 0..112 {
+                for k in 0..6 {
+                    glyphs.push(format!("a{}{}{}", mark(i), mark(j), mark(k)));
+                }
+            }
+        }
+        let mut t = Terminal::new(10, 2);
+        t.write(glyphs.concat().as_bytes());
+
+        let n = glyphs.len();
+        let start = n - 10 - n % 10;
+        assert_eq!(t.line_text(0).unwrap(), glyphs[start..start + 10].concat());
+        assert_eq!(t.line_text(1).unwrap(), glyphs[start + 10..].concat());
+    }
+
+    #[test]
+    fn links_collected() {
+        // more hyperlinks than the terminal keeps, most of which scroll out of
+        // the history
+        let mut t = Terminal::new(10, 2);
+        let links: String =
+            (0..70000).map(|i| format!("\x1b]8;;u{i}\x07x")).collect();
+        t.write(links.as_bytes());
+        t.write(b"\x1b]8;;\x07y");
+        assert_eq!(t.cell_link(0, 0), Some("u69990"));
+        assert_eq!(t.cell_link(9, 0), Some("u69999"));
+        assert_eq!(t.cell_link(0, 1), None);
+    }
+
+    #[test]
+    fn reset() {
+        let mut t = Terminal::new(10, 3);
+        t.write(b"\x1b[4h\x1b[1mabc");
+        t.reset();
+        assert_eq!(t.mode() & MODE_INSERT, 0);
+        assert_eq!(t.cursor(), (0, 0));
+        assert_eq!(t.text(), "\n\n");
+    }
+}
@@ -0,0 +1,233 @@
+//! Detection of URLs in the text on the terminal, and of file paths followed
+//! by a line number if `PATHS` is set, so that they can be opened with a click
+//! like the hyperlinks set with OSC 8.
+
+use std::{
+    ffi::{c_int, OsString},
+    path::PathBuf,
+    sync::OnceLock,
+};
+
+use regex::Regex;
+
+use crate::{bindgen::term, config, state, tlinestart, tlinetext, Pos};
+
+/// What a piece of text on the terminal refers to.
+pub(crate) enum Target {
+    Url(String),
+    /// a file, and the line in it
+    Path(PathBuf, Option<u32>),
+}
+
+impl Target {
+    /// Return the command from config.rs opening the target, with its
+    /// arguments, or `None` if there is none.
+    pub(crate) fn command(&self) -> Option<Vec<OsString>> {
+        let (opener, args) = match self {
+            Target::Url(url) => {
+                // a URL without a scheme is taken to be on the web
+                let url = if url.starts_with("www.") {
+                    format!("http://{url}")
+                } else {
+                    url.clone()
+                };
+                (config::LINKOPENER, vec![url.into()])
+            }
+            Target::Path(path, line) => {
+                let mut args: Vec<OsString> =
+                    line.map(|n| format!("+{n}").into()).into_iter().collect();
+                // a path starting with - is not taken for an option
+                args.extend(["--".into(), path.into()]);
+                (config::PATHOPENER, args)
+            }
+        };
+        if opener.is_empty() {
+            return None;
+        }
+        Some(opener.iter().map(OsString::from).chain(args).collect())
+    }
+}
+
+fn urlregex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| {
+        Regex::new(
+            r#"(?:(?:https?|ftp|file|sftp|ssh|git)://|mailto:|www\.)[^\s<>"'`]+"#,
+        )
+        .unwrap()
+    })
+}
+
+fn pathregex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| {
+        Regex::new(
+            r#"(?:^|[\s"'(\[<])((?:~?/)?(?:[\w.+-]+/)*[\w+-][\w.+-]*\.\w+):(\d+)(?::\d+)?"#,
+        )
+        .unwrap()
+    })
+}
+
+/// Return the length of `url` without the punctuation at its end that is more
+/// likely to belong to the text around it, like a full stop or the closing
+/// parenthesis of a URL in parentheses.
+fn trim(url: &str) -> usize {
+    let mut s = url;
+    loop {
+        let Some(c) = s.chars().last() else {
+            return 0;
+        };
+        let open = match c {
+            ')' => '(',
+            ']' => '[',
+            '.' | ',' | ':' | ';' | '!' | '?' => {
+                s = &s[..s.len() - 1];
+                continue;
+            }
+            _ => return s.len(),
+        };
+        if s.matches(c).count() <= s.matches(open).count() {
+            return s.len();
+        }
+        s = &s[..s.len() - 1];
+    }
+}
+
+/// Resolve `path` as written on the terminal, relative to the working
+/// directory reported with OSC 7 if it is relative, returning it if it exists.
+fn resolve(path: &str) -> Option<PathBuf> {
+    let path = match path.strip_prefix("~/") {
+        Some(rest) => PathBuf::from(std::env::var_os("HOME")?).join(rest),
+        None => PathBuf::from(path),
+    };
+    let path = match &unsafe { state() }.cwd {
+        Some(cwd) if path.is_relative() => cwd.join(path),
+        _ => path,
+    };
+    path.exists().then_some(path)
+}
+
+/// Return the URL or path the cell at `pos` is part of, with its first and last
+/// cell. Lines wrapped by the terminal are looked at as one.
+pub(crate) fn urlat(pos: Pos) -> Option<(Target, Pos, Pos)> {
+    let (line, _) = tlinetext(tlinestart(pos.0));
+    let span = |start: usize, end: usize| {
+        let (first, last) = (line.cell(start), line.cell(end - 1));
+        (first <= pos && pos <= last).then_some((first, last))
+    };
+
+    for m in urlregex().find_iter(&line.text) {
+        let len = trim(m.as_str());
+        if let Some((first, last)) = span(m.start(), m.start() + len) {
+            let url = m.as_str()[..len].to_owned();
+            return Some((Target::Url(url), first, last));
+        }
+    }
+    if !config::PATHS {
+        return None;
+    }
+    for c in pathregex().captures_iter(&line.text) {
+        let (path, n) = (c.get(1)?, c.get(2)?);
+        let Some((first, last)) = span(path.start(), c.get(0)?.end()) else {
+            continue;
+        };
+        let target =
+            Target::Path(resolve(path.as_str())?, n.as_str().parse().ok());
+        return Some((target, first, last));
+    }
+    None
+}
+
+/// Return whether the cell at `x` on row `y` of the view is part of the URL
+/// under the pointer.
+pub(crate) fn hovered(x: c_int, y: c_int) -> bool {
+    let Some((first, last)) = unsafe { state() }.urlhover else {
+        return false;
+    };
+    let pos = (y - unsafe { term.scr }, x);
+    first <= pos && pos <= last
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terminal::Terminal;
+
+    #[test]
+    fn trimmed() {
+        fn t(s: &str) -> &str {
+            &s[..trim(s)]
+        }
+        assert_eq!(t("http://a.org/x"), "http://a.org/x");
+        assert_eq!(t("http://a.org/x."), "http://a.org/x");
+        assert_eq!(t("http://a.org/x?!,"), "http://a.org/x");
+        // parentheses are kept if they are balanced
+        assert_eq!(t("http://a.org/x)"), "http://a.org/x");
+        assert_eq!(t("http://a.org/x_(y)"), "http://a.org/x_(y)");
+        assert_eq!(t("http://a.org/x_(y))."), "http://a.org/x_(y)");
+        assert_eq!(t("http://a.org/[1]]"), "http://a.org/[1]");
+        assert_eq!(t(".)"), "");
+    }
+
+    #[test]
+    fn urls() {
+        let find = |s| urlregex().find(s).map(|m| m.as_str());
+        assert_eq!(
+            find("see https://a.org/x?y=1 now"),
+            Some("https://a.org/x?y=1")
+        );
+        assert_eq!(find("<www.a.org>"), Some("www.a.org"));
+        assert_eq!(find("\"mailto:me@a.org\""), Some("mailto:me@a.org"));
+        assert_eq!(find("git://a.org/r.git`"), Some("git://a.org/r.git"));
+        assert_eq!(find("http:/a.org a.org"), None);
+    }
+
+    #[test]
+    fn paths() {
+        let find = |s| {
+            pathregex()
+                .captures(s)
+                .map(|c| (c[1].to_owned(), c[2].to_owned()))
+        };
+        let p = |a: &str, b: &str| Some((a.to_owned(), b.to_owned()));
+        assert_eq!(find("src/urls.rs:12: error"), p("src/urls.rs", "12"));
+        assert_eq!(find("at (~/a/b.c:3:14)"), p("~/a/b.c", "3"));
+        assert_eq!(find("/tmp/x.rs:1"), p("/tmp/x.rs", "1"));
+        // a name needs an extension and a line
+        assert_eq!(find("Makefile:3"), None);
+        assert_eq!(find("src/urls.rs"), None);
+        assert_eq!(find("a:b/c.rs:3"), None);
+    }
+
+    #[test]
+    fn at() {
+        let mut t = Terminal::new(20, 3);
+        t.write(b"go to (www.a.org/abcdef).\r\nhttp://b.org");
+        t.with(|| {
+            let url = |pos| match urlat(pos) {
+                Some((Target::Url(url), first, last)) => {
+                    Some((url, first, last))
+                }
+                _ => None,
+            };
+            // the URL wraps onto the second line
+            let a = Some(("www.a.org/abcdef".to_owned(), (0, 7), (1, 2)));
+            assert_eq!(url((0, 7)), a);
+            assert_eq!(url((1, 2)), a);
+            assert_eq!(url((0, 6)), None);
+            assert_eq!(url((1, 3)), None);
+            assert_eq!(url((2, 0)).map(|u| u.0), Some("http://b.org".into()));
+        });
+    }
+
+    #[test]
+    fn commands() {
+        let url = Target::Url("www.a.org".into()).command().unwrap();
+        assert_eq!(url.last().unwrap(), "http://www.a.org");
+        let path = Target::Path("-x.rs".into(), Some(3)).command().unwrap();
+        let n = path.len();
+        assert_eq!(path[n - 3..], ["+3", "--", "-x.rs"]);
+        let path = Target::Path("x.rs".into(), None).command().unwrap();
+        assert_eq!(path[path.len() - 2..], ["--", "x.rs"]);
+    }
+}
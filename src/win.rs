@@ -19,5 +19,11 @@ pub const MODE_MOUSEX10: c_int = 1 << 14;
 pub const MODE_MOUSEMANY: c_int = 1 << 15;
 pub const MODE_BRCKTPASTE: c_int = 1 << 16;
 pub const MODE_NUMLOCK: c_int = 1 << 17;
+/// the cursor blinks, set with DECSCUSR or att610
+pub const MODE_CURSORBLINK: c_int = 1 << 18;
+/// the cursor is in the hidden phase of blinking
+pub const MODE_CURSOROFF: c_int = 1 << 19;
+/// the colors are inverted for the visual bell
+pub const MODE_VBELL: c_int = 1 << 20;
 pub const MODE_MOUSE: c_int =
     MODE_MOUSEBTN | MODE_MOUSEMOTION | MODE_MOUSEX10 | MODE_MOUSEMANY;
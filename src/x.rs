@@ -1,40 +1,75 @@
 use std::{
-    ffi::{c_char, c_double, c_int, c_long, c_ushort, c_void, CStr},
+    ffi::{
+        c_char, c_double, c_int, c_long, c_short, c_uint, c_ushort, c_void,
+        CStr, OsStr, OsString,
+    },
     mem::MaybeUninit,
-    ptr::null_mut,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    process::{Command, Stdio},
+    ptr::{null, null_mut},
+    rc::{Rc, Weak},
+    sync::atomic::{AtomicBool, Ordering},
 };
 
-use libc::strlen;
-use x11::xlib::{
-    False, InputHint, NorthEastGravity, NorthWestGravity, PBaseSize, PMaxSize,
-    PMinSize, PResizeInc, PSize, PWinGravity, SouthEastGravity,
-    SouthWestGravity, Success, USPosition, XIMPreeditNothing, XIMStatusNothing,
-    XNegative, XUTF8StringStyle, XValue, YNegative, YValue,
+use libc::{clock_gettime, strlen, CLOCK_MONOTONIC};
+use x11::{
+    keysym::{XK_Hyper_R, XK_Shift_L},
+    xlib::{
+        AnyPropertyType, Button1Mask, CurrentTime, False, InputHint,
+        NorthEastGravity, NorthWestGravity, PBaseSize, PMaxSize, PMinSize,
+        PResizeInc, PSize, PWinGravity, PointerMotionMask, SouthEastGravity,
+        SouthWestGravity, Success, True, USPosition, XIMPreeditNothing,
+        XIMStatusNothing, XNegative, XUTF8StringStyle, XUrgencyHint, XValue,
+        YNegative, YValue, XA_PRIMARY,
+    },
 };
 
 use crate::{
     between,
     bindgen::{
-        self, ascii_printable, borderpx, chscale, colorname, cursorthickness,
-        cwscale, dc, defaultbg, defaultcs, defaultfg, defaultfontsize,
-        defaultrcs, opt_class, opt_name, opt_title, termname, usedfontsize,
-        win, xw, Color, FcChar8, FcConfigSubstitute, FcFontMatch, FcNameParse,
-        FcPattern, FcPatternAddDouble, FcPatternAddInteger, FcPatternDel,
+        self, ascii_printable, bellvolume, borderpx, chscale, colorname,
+        cursorthickness, cwscale, dc, defaultattr, defaultbg, defaultcs,
+        defaultfg, defaultfontsize, defaultrcs, forcemousemod, ignoremod,
+        opt_class, opt_name, opt_title, term, termname, usedfontsize, win,
+        xsel, xw, Atom, Color, FcChar8, FcConfigSubstitute, FcFontMatch,
+        FcMatrix, FcNameParse, FcPattern, FcPatternAddDouble,
+        FcPatternAddInteger, FcPatternAddMatrix, FcPatternDel,
         FcPatternDestroy, FcPatternDuplicate, FcPatternGetDouble,
-        FcPatternGetInteger, Font_, Glyph_, XAllocSizeHints, XClassHint,
-        XCopyArea, XCreateIC, XICCallback, XIMCallback, XNDestroyCallback,
-        XNPreeditAttributes, XPointer, XRenderColor, XSetForeground,
-        XSetICValues, XSetIMValues, XVaCreateNestedList, XWMHints,
-        XftColorAllocName, XftColorAllocValue, XftColorFree,
-        XftDefaultSubstitute, XftFontOpenPattern, XftGlyphFontSpec,
-        XftTextExtentsUtf8, XftXlfdParse, _FcMatchKind_FcMatchPattern,
-        _FcResult_FcResultMatch, FC_PIXEL_SIZE, FC_SIZE, FC_SLANT,
-        FC_SLANT_ITALIC, FC_SLANT_ROMAN, FC_WEIGHT, FC_WEIGHT_BOLD, XIC, XIM,
+        FcPatternGetInteger, Font_, GlyphFontSpec, Glyph_, KeySym, PictOpOver,
+        PictStandardARGB32, Picture, Pixmap, Term, XAllocSizeHints,
+        XButtonEvent, XClassHint, XConvertSelection, XCopyArea, XCreateGC,
+        XCreateIC, XCreateImage, XCreatePixmap, XEvent, XFree, XFreeGC,
+        XFreePixmap, XGetWMHints, XGetWindowProperty, XICCallback, XIMCallback,
+        XInternAtom, XKeyEvent, XMotionEvent, XNDestroyCallback,
+        XNPreeditAttributes, XPointer, XPutImage, XRectangle, XRenderColor,
+        XRenderComposite, XRenderCreatePicture, XRenderFindStandardFormat,
+        XRenderFreePicture, XSelectionEvent, XSetForeground, XSetICValues,
+        XSetIMValues, XSetSelectionOwner, XSetWMHints, XVaCreateNestedList,
+        XWMHints, XftCharIndex, XftColorAllocName, XftColorAllocValue,
+        XftColorFree, XftDefaultSubstitute, XftDrawChange,
+        XftDrawGlyphFontSpec, XftDrawPicture, XftDrawRect, XftDrawSetClip,
+        XftDrawSetClipRectangles, XftFont, XftFontClose, XftFontOpenPattern,
+        XftGlyphFontSpec, XftTextExtentsUtf8, XftXlfdParse, XkbBell, ZPixmap,
+        _FcMatchKind_FcMatchPattern, _FcResult_FcResultMatch, FC_PIXEL_SIZE,
+        FC_SIZE, FC_SLANT, FC_SLANT_ITALIC, FC_SLANT_ROMAN, FC_WEIGHT,
+        FC_WEIGHT_BOLD, XIC, XIM,
     },
-    die, len, selected,
-    win::{MODE_FOCUSED, MODE_HIDE, MODE_REVERSE, MODE_VISIBLE},
-    xmalloc, ATTR_BOLD, ATTR_ITALIC, ATTR_REVERSE, ATTR_STRUCK, ATTR_UNDERLINE,
-    ATTR_WDUMMY, ATTR_WIDE,
+    config, die, gmarks,
+    graphics::{self, Image, Strip},
+    len, redraw, search, selected,
+    settings::settings,
+    state, tanswer, tclipreply, tfulldirt, timediff, tlattr, tresize, ttywrite,
+    urls,
+    win::{
+        MODE_APPCURSOR, MODE_APPKEYPAD, MODE_BLINK, MODE_CURSORBLINK,
+        MODE_CURSOROFF, MODE_FOCUSED, MODE_HIDE, MODE_KBDLOCK, MODE_MOUSE,
+        MODE_NUMLOCK, MODE_REVERSE, MODE_VBELL, MODE_VISIBLE,
+    },
+    xmalloc, xrealloc, ATTR_BLINK, ATTR_BOLD, ATTR_BOLD_FAINT, ATTR_FAINT,
+    ATTR_INVISIBLE, ATTR_ITALIC, ATTR_REVERSE, ATTR_STRUCK, ATTR_UNDERLINE,
+    ATTR_USTYLE, ATTR_WDUMMY, ATTR_WIDE, LATTR_DBOTTOM, LATTR_DTOP,
+    LATTR_DWIDTH, LATTR_SINGLE, USTYLE_CURLY, USTYLE_DASHED, USTYLE_DOTTED,
+    USTYLE_DOUBLE,
 };
 
 #[inline]
@@ -51,6 +86,9 @@ pub fn xsetcursor(cursor: c_int) -> c_int {
     unsafe {
         win.cursor = cursor;
     }
+    // the odd shapes blink, and so does 0, the blinking block of xterm
+    xsetmode(matches!(cursor, 0 | 1 | 3 | 5), MODE_CURSORBLINK);
+    xsetmode(false, MODE_CURSOROFF);
 
     0
 }
@@ -333,6 +371,10 @@ fn divceil(n: c_int, d: c_int) -> c_int {
 /// Load colors.
 pub(crate) fn xloadcols() {
     unsafe {
+        if xw.dpy.is_null() {
+            return;
+        }
+
         // TODO LazyLock
         static mut LOADED: bool = false;
 
@@ -511,6 +553,10 @@ pub(crate) extern "C" fn ximinstantiate(
 
 pub(crate) fn xsettitle(mut p: *mut c_char) {
     unsafe {
+        if xw.dpy.is_null() {
+            return;
+        }
+
         p = if p.is_null() { opt_title } else { p };
 
         if *p == b'\0' as c_char {
@@ -541,6 +587,554 @@ pub(crate) fn xsettitle(mut p: *mut c_char) {
     }
 }
 
+// DUMMY
+pub(crate) fn xseticontitle(p: *mut c_char) {
+    unsafe {
+        if !xw.dpy.is_null() {
+            bindgen::xseticontitle(p)
+        }
+    }
+}
+
+/// Set or clear the window mode `flags`, redrawing if reverse video changed.
+pub(crate) fn xsetmode(set: bool, flags: c_int) {
+    unsafe {
+        let mode = win.mode;
+        if set {
+            win.mode |= flags;
+        } else {
+            win.mode &= !flags;
+        }
+        if (win.mode & MODE_REVERSE) != (mode & MODE_REVERSE) {
+            redraw();
+        }
+    }
+}
+
+/// Enable or disable pointer motion events from the window. They stay enabled
+/// if hyperlinks are highlighted under the pointer.
+pub(crate) fn xsetpointermotion(set: bool) {
+    unsafe {
+        if xw.dpy.is_null() {
+            return;
+        }
+
+        if set || config::LINKHOVER {
+            xw.attrs.event_mask |= PointerMotionMask;
+        } else {
+            xw.attrs.event_mask &= !PointerMotionMask;
+        }
+        bindgen::XChangeWindowAttributes(
+            xw.dpy,
+            xw.win,
+            bindgen::CWEventMask as u64,
+            &raw mut xw.attrs,
+        );
+    }
+}
+
+/// Ring the bell, in the ways set in config.rs and `bellvolume`.
+pub(crate) fn xbell() {
+    unsafe {
+        if xw.dpy.is_null() {
+            return;
+        }
+        if config::BELLURGENT && !is_set(MODE_FOCUSED) {
+            xseturgency(true);
+        }
+        if bellvolume != 0 {
+            XkbBell(xw.dpy, xw.win, bellvolume, 0);
+        }
+        if config::VISUALBELL > 0 {
+            // the run loop turns it off again
+            if !is_set(MODE_VBELL) {
+                win.mode |= MODE_VBELL;
+                tfulldirt();
+            }
+            clock_gettime(CLOCK_MONOTONIC, &mut state().belltime);
+        }
+        if let Some((cmd, args)) = config::BELLCMD.split_first() {
+            static RUNNING: AtomicBool = AtomicBool::new(false);
+            if RUNNING.swap(true, Ordering::Relaxed) {
+                return;
+            }
+            spawncmd(
+                Command::new(cmd).args(args).arg({ xw.win }.to_string()),
+                || RUNNING.store(false, Ordering::Relaxed),
+            );
+        }
+    }
+}
+
+/// Show a desktop notification with `title` and `body` with `NOTIFIER`, unless
+/// the window is focused and `NOTIFYUNFOCUSED` is set, or the last one was
+/// shown less than `NOTIFYINTERVAL` ago.
+pub(crate) fn xnotify(title: &[u8], body: &[u8]) {
+    unsafe {
+        if config::NOTIFIER.is_empty() {
+            return;
+        }
+        if xw.dpy.is_null() || (config::NOTIFYUNFOCUSED && is_set(MODE_FOCUSED))
+        {
+            return;
+        }
+        let mut now = MaybeUninit::uninit();
+        clock_gettime(CLOCK_MONOTONIC, now.as_mut_ptr());
+        let now = now.assume_init();
+        let st = state();
+        if st.notifytime.tv_sec != 0
+            && timediff(now, st.notifytime) < config::NOTIFYINTERVAL
+        {
+            return;
+        }
+        st.notifytime = now;
+
+        // without a title, the body is shown as the title
+        let (title, body) =
+            if title.is_empty() { (body, &b""[..]) } else { (title, body) };
+        let cmd: Vec<OsString> = config::NOTIFIER
+            .iter()
+            .map(OsString::from)
+            .chain([title, body].map(|s| OsStr::from_bytes(s).to_owned()))
+            .collect();
+        spawn(&cmd);
+    }
+}
+
+/// Set or clear the urgency hint of the window.
+fn xseturgency(add: bool) {
+    unsafe {
+        let h = XGetWMHints(xw.dpy, xw.win);
+        if h.is_null() {
+            return;
+        }
+        if add {
+            (*h).flags |= XUrgencyHint;
+        } else {
+            (*h).flags &= !XUrgencyHint;
+        }
+        XSetWMHints(xw.dpy, xw.win, h);
+        XFree(h as *mut c_void);
+    }
+}
+
+/// Return whether the colors of the window are inverted, by reverse video or
+/// the visual bell.
+fn reversed() -> bool {
+    is_set(MODE_REVERSE) != is_set(MODE_VBELL)
+}
+
+// DUMMY
+pub(crate) fn xclipcopy() {
+    unsafe {
+        if !xw.dpy.is_null() {
+            bindgen::xclipcopy()
+        }
+    }
+}
+
+/// The X selections programs can set and read with OSC 52.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Clip {
+    Clipboard,
+    Primary,
+}
+
+impl Clip {
+    fn atom(self) -> Atom {
+        match self {
+            Clip::Clipboard => unsafe {
+                XInternAtom(xw.dpy, c"CLIPBOARD".as_ptr(), False as c_int)
+            },
+            Clip::Primary => XA_PRIMARY,
+        }
+    }
+}
+
+/// The largest selection read for an OSC 52 query, in bytes.
+const CLIP_MAX: c_long = 1 << 24;
+
+/// Own the selection `clip` with the text in `s`.
+pub(crate) fn xsetclip(clip: Clip, s: &[u8]) {
+    unsafe {
+        if xw.dpy.is_null() {
+            return;
+        }
+        match clip {
+            Clip::Primary => xsetsel(s),
+            Clip::Clipboard => {
+                // freed by the C side when the clipboard is set again
+                let p: *mut c_char = xmalloc(s.len() + 1).cast();
+                std::ptr::copy_nonoverlapping(s.as_ptr(), p.cast(), s.len());
+                *p.add(s.len()) = 0;
+                libc::free(xsel.clipboard.cast());
+                xsel.clipboard = p;
+                XSetSelectionOwner(xw.dpy, clip.atom(), xw.win, CurrentTime);
+            }
+        }
+    }
+}
+
+/// Ask for the contents of the selection `clip`, which arrive with a
+/// `SelectionNotify` event for `clipnotify`.
+pub(crate) fn xgetclip(clip: Clip) {
+    unsafe {
+        if xw.dpy.is_null() {
+            return;
+        }
+        state().clipread = Some(clip);
+        XConvertSelection(
+            xw.dpy,
+            clip.atom(),
+            xsel.xtarget,
+            XInternAtom(xw.dpy, c"RT_OSC52".as_ptr(), False as c_int),
+            xw.win,
+            CurrentTime,
+        );
+    }
+}
+
+/// Reply to the OSC 52 query waiting for the selection converted in `e`, if
+/// any, returning whether there was one. Selections transferred in chunks are
+/// reported as empty.
+pub(crate) fn clipnotify(e: &XSelectionEvent) -> bool {
+    unsafe {
+        let Some(clip) = state().clipread else {
+            return false;
+        };
+        let prop = XInternAtom(xw.dpy, c"RT_OSC52".as_ptr(), False as c_int);
+        let none = bindgen::None as Atom;
+        if e.selection != clip.atom()
+            || (e.property != prop && e.property != none)
+        {
+            return false;
+        }
+        state().clipread = None;
+
+        let mut data = Vec::new();
+        if e.property == prop {
+            let (mut type_, mut format, mut nitems, mut rem) = (0, 0, 0, 0);
+            let mut p = null_mut();
+            let res = XGetWindowProperty(
+                xw.dpy,
+                xw.win,
+                prop,
+                0,
+                CLIP_MAX / 4,
+                True as c_int,
+                AnyPropertyType as Atom,
+                &mut type_,
+                &mut format,
+                &mut nitems,
+                &mut rem,
+                &mut p,
+            );
+            if res == Success as c_int && !p.is_null() {
+                let incr =
+                    XInternAtom(xw.dpy, c"INCR".as_ptr(), False as c_int);
+                if type_ != incr {
+                    let len = nitems as usize * format as usize / 8;
+                    data.extend_from_slice(std::slice::from_raw_parts(p, len));
+                }
+                XFree(p.cast());
+            }
+        }
+        tclipreply(clip, &data);
+        true
+    }
+}
+
+/// Draw `text` over the bottom row of the window, in reverse video.
+pub(crate) fn drawprompt(text: &str) {
+    unsafe {
+        let blank = Glyph_ {
+            u: ' ' as u32,
+            mode: ATTR_REVERSE as u16,
+            fg: defaultfg,
+            bg: defaultbg,
+            comb: 0,
+            link: 0,
+            ucolor: 0,
+        };
+        let mut line = vec![blank; term.col as usize];
+        for (g, c) in line.iter_mut().zip(text.chars()) {
+            g.u = c as u32;
+        }
+        drawline(line.as_mut_ptr(), 0, term.row - 1, term.col, LATTR_SINGLE);
+    }
+}
+
+/// Draw the cursor of copy mode on the cell at `x, y` of the view, as a block
+/// in the cursor color whatever the shape of the terminal's cursor.
+pub(crate) fn drawcopycursor(mut x: c_int, y: c_int) {
+    unsafe {
+        let line = Term::tline(&raw mut term, y);
+        if x > 0 && (*line.offset(x as isize)).mode & ATTR_WDUMMY as u16 != 0 {
+            x -= 1;
+        }
+        let mut g = *line.offset(x as isize);
+        g.mode &= !(ATTR_REVERSE | ATTR_INVISIBLE | ATTR_BLINK) as u16;
+        g.fg = defaultbg;
+        g.bg = defaultcs;
+        match tlattr(y) {
+            LATTR_SINGLE => drawglyph(g, x, y),
+            lattr => drawglyphscaled(g, x, y, lattr),
+        }
+    }
+}
+
+/// Take the key press `e` as the answer to the question of the prompt, if one
+/// is shown, returning whether it was. Any key but `y` says no.
+pub(crate) fn kprompt(e: &mut XKeyEvent) -> bool {
+    if unsafe { state() }.prompt.is_none() {
+        return false;
+    }
+    let mut ksym = 0;
+    let mut buf = [0; 64];
+    let len = unsafe {
+        bindgen::XLookupString(
+            e,
+            buf.as_mut_ptr(),
+            buf.len() as c_int,
+            &mut ksym,
+            null_mut(),
+        )
+    };
+    // wait for a key that is not a modifier
+    if (XK_Shift_L as KeySym..=XK_Hyper_R as KeySym).contains(&ksym) {
+        return true;
+    }
+    tanswer(len > 0 && matches!(buf[0] as u8, b'y' | b'Y'));
+    true
+}
+
+/// Own the primary selection with the text in `s`.
+pub(crate) fn xsetsel(s: &[u8]) {
+    unsafe {
+        if xw.dpy.is_null() {
+            return;
+        }
+
+        // xsetsel takes ownership of the string and frees it later
+        let p: *mut c_char = xmalloc(s.len() + 1).cast();
+        std::ptr::copy_nonoverlapping(s.as_ptr(), p.cast(), s.len());
+        *p.add(s.len()) = 0;
+        bindgen::xsetsel(p);
+    }
+}
+
+// DUMMY
+pub(crate) fn xsetcolorname(x: c_int, name: Option<&CStr>) -> c_int {
+    unsafe {
+        // without a display there are no colors loaded to change
+        if xw.dpy.is_null() {
+            return 1;
+        }
+        bindgen::xsetcolorname(x, name.map_or(null(), CStr::as_ptr))
+    }
+}
+
+// DUMMY
+pub(crate) fn xgetcolor(x: c_int, r: &mut u8, g: &mut u8, b: &mut u8) -> c_int {
+    unsafe {
+        if xw.dpy.is_null() {
+            return 1;
+        }
+        bindgen::xgetcolor(x, r, g, b)
+    }
+}
+
+/// Run the shortcut from config.rs matching the key press `e`, returning
+/// whether there was one. The shortcuts in config.h are left to kpress.
+pub(crate) fn kshortcut(e: &mut XKeyEvent) -> bool {
+    unsafe {
+        if is_set(MODE_KBDLOCK) {
+            return false;
+        }
+
+        let mut ksym = 0;
+        let mut buf = [0; 64];
+        bindgen::XLookupString(
+            e,
+            buf.as_mut_ptr(),
+            buf.len() as c_int,
+            &mut ksym,
+            null_mut(),
+        );
+        for bp in settings().shortcuts.iter().chain(config::SHORTCUTS) {
+            if ksym == bp.keysym && modmatch(bp.mod_, e.state) {
+                (bp.func)(&bp.arg);
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Send the string of the key from the settings file pressed with `e` to the
+/// program, if there is one. Return whether there was.
+pub(crate) fn kmap(e: &mut XKeyEvent) -> bool {
+    if settings().keys.is_empty() || is_set(MODE_KBDLOCK) {
+        return false;
+    }
+    let mut ksym = 0;
+    let mut buf = [0; 64];
+    unsafe {
+        bindgen::XLookupString(
+            e,
+            buf.as_mut_ptr(),
+            buf.len() as c_int,
+            &mut ksym,
+            null_mut(),
+        );
+    }
+    // a positive appkey or appcursor needs the mode, a negative one needs it
+    // off
+    let fits = |n: i8, mode| if is_set(mode) { n >= 0 } else { n <= 0 };
+    let key = settings().keys.iter().find(|k| {
+        k.keysym == ksym
+            && modmatch(k.mask, e.state)
+            && fits(k.appkey, MODE_APPKEYPAD)
+            && !(is_set(MODE_NUMLOCK) && k.appkey == 2)
+            && fits(k.appcursor, MODE_APPCURSOR)
+    });
+    match key {
+        Some(k) => {
+            ttywrite(&k.string, true);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Call the mouse shortcut from the settings file for the button pressed, or
+/// released if `release` is set, with `e`, if there is one. Return whether
+/// there was.
+pub(crate) fn mshortcut(e: &XButtonEvent, release: bool) -> bool {
+    let force = unsafe { forcemousemod };
+    if is_set(MODE_MOUSE) && e.state & force == 0 {
+        return false;
+    }
+    // the mask of a button is set in the state of its release
+    let state = match e.button {
+        1..=5 => e.state & !(Button1Mask << (e.button - 1)),
+        _ => e.state,
+    };
+    for ms in &settings().mshortcuts {
+        if ms.release == release
+            && ms.button == e.button
+            && (modmatch(ms.mod_, state) || modmatch(ms.mod_, state & !force))
+        {
+            (ms.func)(&ms.arg);
+            return true;
+        }
+    }
+    false
+}
+
+/// Return whether the modifier `state` of an event matches `mask`, ignoring
+/// the modifiers in `ignoremod`.
+fn modmatch(mask: c_uint, state: c_uint) -> bool {
+    mask == c_uint::MAX || mask == (state & !unsafe { ignoremod })
+}
+
+/// Return the cell of the view under the pointer at `px, py`, as a column and
+/// a row.
+fn cellat(px: c_int, py: c_int) -> (c_int, c_int) {
+    unsafe {
+        let x = (px - borderpx).clamp(0, win.tw - 1) / win.cw;
+        let y = (py - borderpx).clamp(0, win.th - 1) / win.ch;
+        let (x, y) = (x.min(term.col - 1), y.min(term.row - 1));
+        // double size lines only have half as many glyphs
+        let x = if tlattr(y) == LATTR_SINGLE { x } else { x / 2 };
+        (x, y)
+    }
+}
+
+/// Return the `Glyph_::link` value of the hyperlink in the cell at `x, y` of
+/// the view, or 0.
+fn linkat(x: c_int, y: c_int) -> u32 {
+    unsafe { (*Term::tline(&raw mut term, y).offset(x as isize)).link }
+}
+
+/// Underline the hyperlink the pointer moved to with `e`, if any. URLs in the
+/// text are only underlined while `LINKMOD` is held, since it takes that to
+/// open them.
+pub(crate) fn linkhover(e: &XMotionEvent) {
+    let (x, y) = cellat(e.x, e.y);
+    let link = linkat(x, y);
+    let url =
+        if config::LINKHOVER && link == 0 && modmatch(config::LINKMOD, e.state)
+        {
+            urls::urlat((y - unsafe { term.scr }, x)).map(|(_, a, b)| (a, b))
+        } else {
+            None
+        };
+    let st = unsafe { state() };
+    if config::LINKHOVER && link != st.hover {
+        st.hover = link;
+        tfulldirt();
+    }
+    if url != st.urlhover {
+        st.urlhover = url;
+        tfulldirt();
+    }
+}
+
+/// Open the hyperlink or the URL in the text clicked on with `e`, if it was
+/// clicked with `LINKBUTTON` and `LINKMOD`. Return whether it was.
+pub(crate) fn linkpress(e: &XButtonEvent) -> bool {
+    if e.button != config::LINKBUTTON || !modmatch(config::LINKMOD, e.state) {
+        return false;
+    }
+    let (x, y) = cellat(e.x, e.y);
+    let uri = unsafe { state() }.link(linkat(x, y)).map(<[u8]>::to_vec);
+    let cmd = match uri {
+        Some(uri) => config::LINKOPENER
+            .iter()
+            .map(OsString::from)
+            .chain([OsString::from_vec(uri)])
+            .collect(),
+        None => match urls::urlat((y - unsafe { term.scr }, x)) {
+            Some((target, _, _)) => target.command().unwrap_or_default(),
+            None => return false,
+        },
+    };
+    spawn(&cmd);
+    true
+}
+
+/// Run `cmd`, a program followed by its arguments, without waiting for it to
+/// finish.
+fn spawn(cmd: &[OsString]) {
+    let [prog, args @ ..] = cmd else {
+        return;
+    };
+    spawncmd(Command::new(prog).args(args), || {});
+}
+
+/// Run `cmd` with its input from /dev/null without waiting for it to finish,
+/// and call `done` once it has, or if it couldn't be run.
+pub(crate) fn spawncmd(
+    cmd: &mut Command,
+    done: impl FnOnce() + Send + 'static,
+) {
+    match cmd.stdin(Stdio::null()).spawn() {
+        // reap it without blocking the terminal
+        Ok(mut child) => {
+            std::thread::spawn(move || {
+                let _ = child.wait();
+                done();
+            });
+        }
+        Err(e) => {
+            let prog = cmd.get_program().to_string_lossy();
+            eprintln!("couldn't run {prog}: {e}");
+            done();
+        }
+    }
+}
+
 /// Set the `WINDOWID` environment variable to `xw.win`.
 pub fn xsetenv() {
     unsafe {
@@ -557,6 +1151,78 @@ pub fn xsetenv() {
     }
 }
 
+/// Handle a `ConfigureNotify` event by resizing the terminal to the new window
+/// size.
+pub(crate) extern "C" fn resize(e: *mut XEvent) {
+    unsafe {
+        let e = (*e).xconfigure;
+        if e.width == win.w && e.height == win.h {
+            return;
+        }
+
+        cresize(e.width, e.height);
+    }
+}
+
+/// Resize the terminal to fit a window of `width` x `height` pixels. A zero
+/// dimension keeps the current window size.
+pub(crate) fn cresize(width: c_int, height: c_int) {
+    unsafe {
+        if width != 0 {
+            win.w = width;
+        }
+        if height != 0 {
+            win.h = height;
+        }
+
+        let col = ((win.w - 2 * borderpx) / win.cw).max(1);
+        let row = ((win.h - 2 * borderpx) / win.ch).max(1);
+
+        tresize(col, row);
+        xresize(col, row);
+        bindgen::ttyresize(win.tw, win.th);
+    }
+}
+
+fn xresize(col: c_int, row: c_int) {
+    unsafe {
+        win.tw = col * win.cw;
+        win.th = row * win.ch;
+
+        XFreePixmap(xw.dpy, xw.buf);
+        xw.buf = XCreatePixmap(
+            xw.dpy,
+            xw.win,
+            win.w as u32,
+            win.h as u32,
+            bindgen::XDefaultDepth(xw.dpy, xw.scr) as u32,
+        );
+        XftDrawChange(xw.draw, xw.buf);
+        xclear(0, 0, win.w, win.h);
+
+        // resize to new width
+        xw.specbuf = xrealloc(
+            xw.specbuf.cast(),
+            col as usize * size_of::<GlyphFontSpec>(),
+        )
+        .cast();
+    }
+}
+
+fn xclear(x1: c_int, y1: c_int, x2: c_int, y2: c_int) {
+    unsafe {
+        bindgen::XftDrawRect(
+            xw.draw,
+            dc.col
+                .add(if reversed() { defaultfg } else { defaultbg } as usize),
+            x1,
+            y1,
+            (x2 - x1) as u32,
+            (y2 - y1) as u32,
+        );
+    }
+}
+
 pub fn startdraw() -> bool {
     is_set(MODE_VISIBLE)
 }
@@ -575,9 +1241,13 @@ pub(crate) fn drawcursor(
         if selected(ox, oy) != 0 {
             og.mode ^= ATTR_REVERSE as u16;
         }
-        drawglyph(og, ox, oy);
+        match tlattr(oy) {
+            LATTR_SINGLE => drawglyph(og, ox, oy),
+            lattr => drawglyphscaled(og, ox, oy, lattr),
+        }
 
-        if is_set(MODE_HIDE) {
+        if is_set(MODE_HIDE) || (is_set(MODE_CURSOROFF) && is_set(MODE_FOCUSED))
+        {
             return;
         }
 
@@ -585,10 +1255,11 @@ pub(crate) fn drawcursor(
         g.mode &= (ATTR_BOLD
             | ATTR_ITALIC
             | ATTR_UNDERLINE
+            | ATTR_USTYLE
             | ATTR_STRUCK
             | ATTR_WIDE) as u16;
 
-        if is_set(MODE_REVERSE) {
+        if reversed() {
             g.mode |= ATTR_REVERSE as u16;
             g.bg = defaultfg;
             if selected(cx, cy) != 0 {
@@ -609,7 +1280,16 @@ pub(crate) fn drawcursor(
             drawcol = *dc.col.offset(g.bg as isize);
         }
 
-        // draw the new one
+        // draw the new one, over two cells on double size lines
+        let lattr = tlattr(cy);
+        let draw = |g| match lattr {
+            LATTR_SINGLE => drawglyph(g, cx, cy),
+            _ => drawglyphscaled(g, cx, cy, lattr),
+        };
+        let (rx, rw) = match lattr {
+            LATTR_SINGLE => (borderpx + cx * win.cw, win.cw),
+            _ => (borderpx + 2 * cx * win.cw, 2 * win.cw),
+        };
         if is_set(MODE_FOCUSED) {
             match win.cursor {
                 // st extension
@@ -617,24 +1297,24 @@ pub(crate) fn drawcursor(
                     // snowman U+2603
                     g.u = 0x2603;
                     // fallthrough to 0|1|2 case
-                    drawglyph(g, cx, cy);
+                    draw(g);
                 }
                 // blinking block, blinking block (default), or steady block
-                0..=2 => drawglyph(g, cx, cy),
+                0..=2 => draw(g),
                 // Blinking Underline or Steady Underline
                 3 | 4 => bindgen::XftDrawRect(
                     xw.draw,
                     &drawcol,
-                    borderpx + cx * win.cw,
+                    rx,
                     borderpx + (cy + 1) * win.ch - cursorthickness as i32,
-                    win.cw as u32,
+                    rw as u32,
                     cursorthickness,
                 ),
                 // Blinking bar or Steady bar
                 5 | 6 => bindgen::XftDrawRect(
                     xw.draw,
                     &drawcol,
-                    borderpx + cx * win.cw,
+                    rx,
                     borderpx + cy * win.ch,
                     cursorthickness,
                     win.ch as u32,
@@ -646,15 +1326,15 @@ pub(crate) fn drawcursor(
             bindgen::XftDrawRect(
                 xw.draw,
                 &drawcol,
-                borderpx + cx * win.cw,
+                rx,
                 borderpx + cy * win.ch,
-                win.cw as u32 - 1,
+                rw as u32 - 1,
                 1,
             );
             bindgen::XftDrawRect(
                 xw.draw,
                 &drawcol,
-                borderpx + cx * win.cw,
+                rx,
                 borderpx + cy * win.ch,
                 1,
                 win.ch as u32 - 1,
@@ -662,7 +1342,7 @@ pub(crate) fn drawcursor(
             bindgen::XftDrawRect(
                 xw.draw,
                 &drawcol,
-                borderpx + (cx + 1) * win.cw - 1,
+                rx + rw - 1,
                 borderpx + cy * win.ch,
                 1,
                 win.ch as u32 - 1,
@@ -670,9 +1350,9 @@ pub(crate) fn drawcursor(
             bindgen::XftDrawRect(
                 xw.draw,
                 &drawcol,
-                borderpx + cx * win.cw,
+                rx,
                 borderpx + (cy + 1) * win.ch - 1,
-                win.cw as u32,
+                rw as u32,
                 1,
             );
         }
@@ -682,7 +1362,26 @@ pub(crate) fn drawcursor(
 fn drawglyph(g: Glyph_, x: c_int, y: c_int) {
     let mut spec = MaybeUninit::uninit();
     let numspecs = makeglyphfontspecs(spec.as_mut_ptr(), &g, 1, x, y);
-    drawglyphfontspecs(spec.as_mut_ptr(), g, numspecs, x, y);
+    let mut marks = Vec::new();
+    makemarkspecs(&g, x, y, &mut marks);
+    drawglyphfontspecs(spec.as_mut_ptr(), g, numspecs, x, y, &marks);
+}
+
+/// Append the specs for the combining marks of `g`, which are drawn over its
+/// character at column `x` of row `y`, to `specs`.
+fn makemarkspecs(
+    g: &Glyph_,
+    x: c_int,
+    y: c_int,
+    specs: &mut Vec<XftGlyphFontSpec>,
+) {
+    for u in gmarks(g) {
+        let mark = Glyph_ { u, comb: 0, ..*g };
+        let mut spec = MaybeUninit::uninit();
+        if makeglyphfontspecs(spec.as_mut_ptr(), &mark, 1, x, y) == 1 {
+            specs.push(unsafe { spec.assume_init() });
+        }
+    }
 }
 
 // DUMMY(long)
@@ -696,18 +1395,265 @@ fn makeglyphfontspecs(
     unsafe { bindgen::xmakeglyphfontspecs(specs, glyphs, len, x, y) }
 }
 
-// DUMMY(long)
+/// Draw the `len` glyphs in `specs`, which share the attributes of `base`,
+/// from column `x` of row `y`, and then the combining marks in `marks` over
+/// them.
 fn drawglyphfontspecs(
     specs: *const XftGlyphFontSpec,
-    base: Glyph_,
+    mut base: Glyph_,
     len: c_int,
     x: c_int,
     y: c_int,
+    marks: &[XftGlyphFontSpec],
+) {
+    unsafe {
+        let charlen =
+            len * if base.mode & ATTR_WIDE as u16 != 0 { 2 } else { 1 };
+        let winx = borderpx + x * win.cw;
+        let winy = borderpx + y * win.ch;
+        let width = charlen * win.cw;
+        let mode = base.mode as c_int;
+        let mut truefg = Color::default();
+        let mut truebg = Color::default();
+        let mut revfg = Color::default();
+        let mut revbg = Color::default();
+        let mut trueuc = Color::default();
+
+        // fallback on color display for attributes not supported by the font
+        if mode & ATTR_ITALIC != 0 && mode & ATTR_BOLD != 0 {
+            if dc.ibfont.badslant != 0 || dc.ibfont.badweight != 0 {
+                base.fg = defaultattr;
+            }
+        } else if (mode & ATTR_ITALIC != 0 && dc.ifont.badslant != 0)
+            || (mode & ATTR_BOLD != 0 && dc.bfont.badweight != 0)
+        {
+            base.fg = defaultattr;
+        }
+
+        let mut fg = color(base.fg, &raw mut truefg);
+        let mut bg = color(base.bg, &raw mut truebg);
+
+        // change basic system colors [0-7] to bright system colors [8-15]
+        if mode & ATTR_BOLD_FAINT == ATTR_BOLD && between(base.fg, 0, 7) {
+            fg = dc.col.add(base.fg as usize + 8);
+        }
+
+        if reversed() {
+            if fg == dc.col.add(defaultfg as usize) {
+                fg = dc.col.add(defaultbg as usize);
+            } else {
+                let c = (*fg).color;
+                let c = XRenderColor {
+                    red: !c.red,
+                    green: !c.green,
+                    blue: !c.blue,
+                    alpha: c.alpha,
+                };
+                XftColorAllocValue(xw.dpy, xw.vis, xw.cmap, &c, &mut revfg);
+                fg = &raw mut revfg;
+            }
+
+            if bg == dc.col.add(defaultbg as usize) {
+                bg = dc.col.add(defaultfg as usize);
+            } else {
+                let c = (*bg).color;
+                let c = XRenderColor {
+                    red: !c.red,
+                    green: !c.green,
+                    blue: !c.blue,
+                    alpha: c.alpha,
+                };
+                XftColorAllocValue(xw.dpy, xw.vis, xw.cmap, &c, &mut revbg);
+                bg = &raw mut revbg;
+            }
+        }
+
+        if mode & ATTR_BOLD_FAINT == ATTR_FAINT {
+            let c = (*fg).color;
+            let c = XRenderColor {
+                red: c.red / 2,
+                green: c.green / 2,
+                blue: c.blue / 2,
+                alpha: c.alpha,
+            };
+            XftColorAllocValue(xw.dpy, xw.vis, xw.cmap, &c, &mut revfg);
+            fg = &raw mut revfg;
+        }
+
+        if mode & ATTR_REVERSE != 0 {
+            std::mem::swap(&mut fg, &mut bg);
+        }
+
+        if mode & ATTR_BLINK != 0 && is_set(MODE_BLINK) {
+            fg = bg;
+        }
+
+        if mode & ATTR_INVISIBLE != 0 {
+            fg = bg;
+        }
+
+        // the underline is in the color of the text unless set with SGR 58
+        let ucol = if base.ucolor == 0 || fg == bg {
+            fg
+        } else {
+            color(base.ucolor - 1, &raw mut trueuc)
+        };
+
+        // intelligent cleaning up of the borders
+        let top = if y == 0 { 0 } else { winy };
+        let last = winy + win.ch >= borderpx + win.th;
+        if x == 0 {
+            xclear(
+                0,
+                top,
+                borderpx,
+                winy + win.ch + if last { win.h } else { 0 },
+            );
+        }
+        if winx + width >= borderpx + win.tw {
+            xclear(
+                winx + width,
+                top,
+                win.w,
+                if last { win.h } else { winy + win.ch },
+            );
+        }
+        if y == 0 {
+            xclear(winx, 0, winx + width, borderpx);
+        }
+        if last {
+            xclear(winx, winy + win.ch, winx + width, win.h);
+        }
+
+        // clean up the region we want to draw to
+        XftDrawRect(xw.draw, bg, winx, winy, width as u32, win.ch as u32);
+
+        // set the clip region because Xft is sometimes dirty
+        let r = XRectangle {
+            x: 0,
+            y: 0,
+            width: width as c_ushort,
+            height: win.ch as c_ushort,
+        };
+        XftDrawSetClipRectangles(xw.draw, winx, winy, &r, 1);
+
+        // render the glyphs, and the combining marks on top of them
+        XftDrawGlyphFontSpec(xw.draw, fg, specs, len);
+        if !marks.is_empty() {
+            XftDrawGlyphFontSpec(
+                xw.draw,
+                fg,
+                marks.as_ptr(),
+                marks.len() as c_int,
+            );
+        }
+
+        // render underline and strikethrough
+        let ascent = dc.font.ascent as f32 * chscale;
+        if mode & ATTR_UNDERLINE != 0 {
+            drawunderline(
+                ucol,
+                mode & ATTR_USTYLE,
+                winx,
+                (winy as f32 + ascent + 1.0) as c_int,
+                width,
+            );
+        }
+        if mode & ATTR_STRUCK != 0 {
+            XftDrawRect(
+                xw.draw,
+                fg,
+                winx,
+                (winy as f32 + 2.0 * ascent / 3.0) as c_int,
+                width as u32,
+                1,
+            );
+        }
+
+        // reset clip to none
+        XftDrawSetClip(xw.draw, null_mut());
+    }
+}
+
+/// Draw an underline of `style` with its top at `y`, from `x` for `width`
+/// pixels. The dots, dashes and waves are aligned to the cells, so that they
+/// line up when a line is drawn in several parts.
+fn drawunderline(
+    col: *const Color,
+    style: c_int,
+    x: c_int,
+    y: c_int,
+    width: c_int,
 ) {
-    unsafe { bindgen::xdrawglyphfontspecs(specs, base, len, x, y) }
+    unsafe {
+        let rect = |x, y, w, h| XftDrawRect(xw.draw, col, x, y, w, h);
+        // the room left in the cell below the underline
+        let room =
+            (borderpx + (y - borderpx) / win.ch * win.ch + win.ch - y).max(1);
+        match style {
+            USTYLE_DOUBLE => {
+                rect(x, y, width as u32, 1);
+                if room > 2 {
+                    rect(x, y + 2, width as u32, 1);
+                } else {
+                    rect(x, y - 2, width as u32, 1);
+                }
+            }
+            USTYLE_CURLY => {
+                let amp = ((room - 1) / 2).clamp(1, 2) as f32;
+                let wave = |px: c_int| {
+                    let phase = (px - borderpx).rem_euclid(win.cw) as f32
+                        / win.cw as f32;
+                    y + amp as c_int
+                        - (amp * (phase * std::f32::consts::TAU).sin()).round()
+                            as c_int
+                };
+                let mut prev = wave(x);
+                for px in x..x + width {
+                    let cur = wave(px);
+                    rect(px, prev.min(cur), 1, (prev - cur).unsigned_abs() + 1);
+                    prev = cur;
+                }
+            }
+            USTYLE_DOTTED => {
+                for px in (x..x + width).filter(|px| px % 2 == 0) {
+                    rect(px, y, 1, 1);
+                }
+            }
+            USTYLE_DASHED => {
+                let dash = (win.cw / 2).max(1);
+                for px in x..x + width {
+                    if (px - borderpx).rem_euclid(win.cw) < dash {
+                        rect(px, y, 1, 1);
+                    }
+                }
+            }
+            _ => rect(x, y, width as u32, 1),
+        }
+    }
+}
+
+/// Return the color for the glyph color `c`, which is allocated in `truecolor`
+/// if it is not one of the colors in `dc.col`.
+fn color(c: u32, truecolor: *mut Color) -> *mut Color {
+    unsafe {
+        if c & 1 << 24 == 0 {
+            return dc.col.add(c as usize);
+        }
+        let rgb = XRenderColor {
+            red: ((c & 0xff0000) >> 8) as c_ushort,
+            green: (c & 0xff00) as c_ushort,
+            blue: ((c & 0xff) << 8) as c_ushort,
+            alpha: 0xffff,
+        };
+        XftColorAllocValue(xw.dpy, xw.vis, xw.cmap, &rgb, truecolor);
+        truecolor
+    }
 }
 
 pub(crate) fn finishdraw() {
+    // images were drawn with their lines, below the cursor
+    freepictures();
     unsafe {
         XCopyArea(
             xw.dpy,
@@ -724,11 +1670,8 @@ pub(crate) fn finishdraw() {
         XSetForeground(
             xw.dpy,
             dc.gc,
-            (*dc.col.add(if is_set(MODE_REVERSE) {
-                defaultfg
-            } else {
-                defaultbg
-            } as usize))
+            (*dc.col
+                .add(if reversed() { defaultfg } else { defaultbg } as usize))
             .pixel,
         );
     }
@@ -752,7 +1695,20 @@ pub(crate) fn ximspot(x: i32, y: i32) {
     }
 }
 
-pub(crate) fn drawline(line: *mut Glyph_, x1: i32, y1: i32, x2: i32) {
+/// Draw the glyphs of `line` between `x1` and `x2` on row `y1`, which has the
+/// size attribute `lattr`. Double size lines are always drawn whole.
+pub(crate) fn drawline(
+    line: *mut Glyph_,
+    x1: i32,
+    y1: i32,
+    x2: i32,
+    lattr: u8,
+) {
+    if lattr != LATTR_SINGLE {
+        drawlinescaled(line, y1, lattr);
+        drawstrips(line, y1);
+        return;
+    }
     unsafe {
         let mut base = Glyph_::default();
         let mut specs = xw.specbuf;
@@ -763,19 +1719,31 @@ pub(crate) fn drawline(line: *mut Glyph_, x1: i32, y1: i32, x2: i32) {
             x1,
             y1,
         );
+        let mut marks = Vec::new();
         let mut i = 0;
         let mut ox = 0;
         let mut x = x1;
         while x < x2 && i < numspecs {
             let mut new = *line.offset(x as isize);
             if new.mode == ATTR_WDUMMY as u16 {
+                x += 1;
                 continue;
             }
+            if let Some(current) = search::highlight(x, y1) {
+                (new.fg, new.bg) = config::SEARCHCOLORS[current as usize];
+                new.mode &= !ATTR_REVERSE as u16;
+            }
             if selected(x, y1) != 0 {
                 new.mode ^= ATTR_REVERSE as u16;
             }
+            if (new.link != 0 && new.link == state().hover)
+                || urls::hovered(x, y1)
+            {
+                new.mode |= ATTR_UNDERLINE as u16;
+            }
             if i > 0 && attrcmp(base, new) {
-                drawglyphfontspecs(specs, base, i, ox, y1);
+                drawglyphfontspecs(specs, base, i, ox, y1, &marks);
+                marks.clear();
                 specs = specs.offset(i as isize);
                 numspecs -= i;
                 i = 0;
@@ -784,16 +1752,282 @@ pub(crate) fn drawline(line: *mut Glyph_, x1: i32, y1: i32, x2: i32) {
                 ox = x;
                 base = new;
             }
+            makemarkspecs(&new, x, y1, &mut marks);
             i += 1;
             x += 1;
         }
         if i > 0 {
-            drawglyphfontspecs(specs, base, i, ox, y1);
+            drawglyphfontspecs(specs, base, i, ox, y1, &marks);
+        }
+    }
+    drawstrips(line, y1);
+}
+
+/// Draw the images shown on `line` over row `y`.
+fn drawstrips(line: *mut Glyph_, y: c_int) {
+    for s in graphics::strips(line) {
+        drawstrip(&s, y);
+    }
+}
+
+/// Free the pictures of images that are not shown anymore.
+fn freepictures() {
+    unsafe {
+        let pictures = &mut *std::ptr::addr_of_mut!(PICTURES);
+        pictures.retain(|&(ref image, pixmap, picture)| {
+            if image.strong_count() > 0 {
+                return true;
+            }
+            XRenderFreePicture(xw.dpy, picture);
+            XFreePixmap(xw.dpy, pixmap);
+            false
+        });
+    }
+}
+
+/// Draw each run of cells of `s` that still shows its image on row `y`.
+fn drawstrip(s: &Strip, y: c_int) {
+    let Some(picture) = picture(&s.image) else {
+        return;
+    };
+    unsafe {
+        let mut start = 0;
+        while start < s.cells.len() {
+            if !s.cells[start] {
+                start += 1;
+                continue;
+            }
+            let end = s.cells[start..]
+                .iter()
+                .position(|&c| !c)
+                .map_or(s.cells.len(), |n| start + n);
+            let sx = start * s.cw;
+            let w = ((end - start) * s.cw).min(win.cw as usize * (end - start));
+            let h = s.ch.min(win.ch as usize);
+            XRenderComposite(
+                xw.dpy,
+                PictOpOver as c_int,
+                picture,
+                0,
+                XftDrawPicture(xw.draw),
+                sx as c_int,
+                s.top as c_int,
+                0,
+                0,
+                borderpx + (s.x + start as c_int) * win.cw,
+                borderpx + y * win.ch,
+                w as c_uint,
+                h as c_uint,
+            );
+            start = end;
+        }
+    }
+}
+
+/// The pictures made from images, with the pixmaps holding them, freed once
+/// the images are dropped.
+static mut PICTURES: Vec<(Weak<Image>, Pixmap, Picture)> = Vec::new();
+
+/// Return the picture of `image`, uploading it to the X server the first time
+/// it is drawn.
+fn picture(image: &Rc<Image>) -> Option<Picture> {
+    unsafe {
+        let pictures = &mut *std::ptr::addr_of_mut!(PICTURES);
+        let weak = Rc::downgrade(image);
+        if let Some(&(_, _, p)) =
+            pictures.iter().find(|(i, ..)| i.ptr_eq(&weak))
+        {
+            return Some(p);
+        }
+
+        let format =
+            XRenderFindStandardFormat(xw.dpy, PictStandardARGB32 as c_int);
+        if format.is_null() {
+            return None;
+        }
+        let (w, h) = (image.width as c_uint, image.height as c_uint);
+        let pixmap = XCreatePixmap(xw.dpy, xw.win, w, h, 32);
+
+        // XRender wants premultiplied alpha
+        let mut data: Vec<u32> = image
+            .pixels
+            .iter()
+            .map(|&p| {
+                let a = p >> 24;
+                let c = |s: u32| ((p >> s & 0xff) * a / 255) << s;
+                a << 24 | c(16) | c(8) | c(0)
+            })
+            .collect();
+        let ximage = XCreateImage(
+            xw.dpy,
+            xw.vis,
+            32,
+            ZPixmap as c_int,
+            0,
+            data.as_mut_ptr().cast(),
+            w,
+            h,
+            32,
+            0,
+        );
+        if ximage.is_null() {
+            XFreePixmap(xw.dpy, pixmap);
+            return None;
+        }
+        let gc = XCreateGC(xw.dpy, pixmap, 0, null_mut());
+        XPutImage(xw.dpy, pixmap, gc, ximage, 0, 0, 0, 0, w, h);
+        XFreeGC(xw.dpy, gc);
+        // only free the structure, the data belongs to `data`
+        XFree(ximage.cast());
+
+        let picture = XRenderCreatePicture(xw.dpy, pixmap, format, 0, null());
+        pictures.push((weak, pixmap, picture));
+        Some(picture)
+    }
+}
+
+/// Draw the double size `line` on row `y`, where each of the first half of its
+/// glyphs takes up two cells.
+fn drawlinescaled(line: *mut Glyph_, y: c_int, lattr: u8) {
+    unsafe {
+        let cols = (term.col / 2).max(1);
+        for x in 0..cols {
+            let mut g = *line.offset(x as isize);
+            if g.mode & ATTR_WDUMMY as u16 != 0 {
+                continue;
+            }
+            if let Some(current) = search::highlight(x, y) {
+                (g.fg, g.bg) = config::SEARCHCOLORS[current as usize];
+                g.mode &= !ATTR_REVERSE as u16;
+            }
+            if selected(x, y) != 0 {
+                g.mode ^= ATTR_REVERSE as u16;
+            }
+            if (g.link != 0 && g.link == state().hover) || urls::hovered(x, y) {
+                g.mode |= ATTR_UNDERLINE as u16;
+            }
+            drawglyphscaled(g, x, y, lattr);
+        }
+        // the last cell of an odd width is not covered by any glyph
+        if term.col % 2 != 0 {
+            let blank = Glyph_ {
+                u: ' ' as u32,
+                mode: 0,
+                fg: defaultfg,
+                bg: defaultbg,
+                comb: 0,
+                link: 0,
+                ucolor: 0,
+            };
+            drawglyph(blank, term.col - 1, y);
+        }
+    }
+}
+
+/// Draw `g` at column `x` of the double size line on row `y`, with a font
+/// scaled to its size. Glyphs missing from the scaled font are drawn
+/// unscaled.
+fn drawglyphscaled(mut g: Glyph_, x: c_int, y: c_int, lattr: u8) {
+    unsafe {
+        // a wide glyph spans four cells, and XftDrawGlyphFontSpec only knows
+        // about two, so the glyph is given twice at the same position
+        let len = if g.mode & ATTR_WIDE as u16 != 0 { 2 } else { 1 };
+        g.mode |= ATTR_WIDE as u16;
+        let mut specs = [XftGlyphFontSpec::default(); 2];
+        makeglyphfontspecs(specs.as_mut_ptr(), &g, 1, 2 * x, y);
+
+        let style = match (
+            g.mode & ATTR_BOLD as u16 != 0,
+            g.mode & ATTR_ITALIC as u16 != 0,
+        ) {
+            (false, false) => 0,
+            (true, false) => 1,
+            (false, true) => 2,
+            (true, true) => 3,
+        };
+        let tall = lattr != LATTR_DWIDTH;
+        let font = scaledfont(style, tall);
+        if !font.is_null() {
+            let glyph = XftCharIndex(xw.dpy, font, g.u);
+            if glyph != 0 {
+                specs[0].font = font;
+                specs[0].glyph = glyph;
+                // only the half of a double height glyph belonging to this row
+                // shows through the clipping in drawglyphfontspecs
+                let ascent = dc.font.ascent as c_short;
+                match lattr {
+                    LATTR_DTOP => specs[0].y += ascent,
+                    LATTR_DBOTTOM => specs[0].y += ascent - win.ch as c_short,
+                    _ => {}
+                }
+            }
+        }
+        specs[1] = specs[0];
+        let mut marks = Vec::new();
+        makemarkspecs(&g, 2 * x, y, &mut marks);
+        drawglyphfontspecs(specs.as_ptr(), g, len, 2 * x, y, &marks);
+    }
+}
+
+/// The fonts scaled for double size lines by [`scaledfont`], and the font size
+/// they were made for.
+static mut SCALEDFONTS: ([[*mut XftFont; 2]; 4], c_double) =
+    ([[null_mut(); 2]; 4], 0.0);
+
+/// Return the regular, bold, italic or bold italic font, selected by `style`,
+/// scaled to twice its width and, if `tall` is set, twice its height, or null
+/// if it can't be loaded.
+fn scaledfont(style: usize, tall: bool) -> *mut XftFont {
+    unsafe {
+        let (fonts, size) = &mut *std::ptr::addr_of_mut!(SCALEDFONTS);
+        // the fonts are loaded again when zooming
+        if *size != usedfontsize {
+            for f in fonts.iter_mut().flatten() {
+                if !f.is_null() {
+                    XftFontClose(xw.dpy, *f);
+                    *f = null_mut();
+                }
+            }
+            *size = usedfontsize;
+        }
+
+        let f = &mut fonts[style][tall as usize];
+        if !f.is_null() {
+            return *f;
+        }
+        let base = [
+            &raw mut dc.font,
+            &raw mut dc.bfont,
+            &raw mut dc.ifont,
+            &raw mut dc.ibfont,
+        ][style];
+        let pattern = FcPatternDuplicate((*base).pattern);
+        if pattern.is_null() {
+            return null_mut();
+        }
+        let matrix = FcMatrix {
+            xx: 2.0,
+            xy: 0.0,
+            yx: 0.0,
+            yy: if tall { 2.0 } else { 1.0 },
+        };
+        FcPatternAddMatrix(pattern, c"matrix".as_ptr(), &matrix);
+
+        let mut result = MaybeUninit::uninit();
+        let match_ = FcFontMatch(null_mut(), pattern, result.as_mut_ptr());
+        FcPatternDestroy(pattern);
+        if match_.is_null() {
+            return null_mut();
+        }
+        *f = XftFontOpenPattern(xw.dpy, match_);
+        if f.is_null() {
+            FcPatternDestroy(match_);
         }
+        *f
     }
 }
 
 #[inline]
 fn attrcmp(a: Glyph_, b: Glyph_) -> bool {
-    a.mode != b.mode || a.fg != b.fg || a.bg != b.bg
+    a.mode != b.mode || a.fg != b.fg || a.bg != b.bg || a.ucolor != b.ucolor
 }
@@ -42,6 +42,9 @@
 #define ISCONTROLC1(c)		(BETWEEN(c, 0x80, 0x9f))
 #define ISCONTROL(c)		(ISCONTROLC0(c) || ISCONTROLC1(c))
 #define ISDELIM(u)		(u && wcschr(worddelimiters, u))
+#define TLINE(y)		((y) < term.scr ? term.hist[(term.histi + (y) - \
+				term.scr + term.histmax) % term.histmax] : \
+				term.line[(y) - term.scr])
 
 enum term_mode {
 	MODE_WRAP        = 1 << 0,
@@ -108,8 +111,6 @@ typedef struct {
 	int narg;              /* nb of args */
 } STREscape;
 
-static void ttywriteraw(const char *, size_t);
-
 static void csidump(void);
 static void csihandle(void);
 static void csiparse(void);
@@ -135,6 +136,8 @@ static void tnewline(int);
 static void tputtab(int);
 static void tputc(Rune);
 static void tscrollup(int, int);
+static void thistpush(int);
+static void thistclear(void);
 static void tscrolldown(int, int);
 static void tsetattr(const int *, int);
 static void tsetchar(Rune, const Glyph *, int, int);
@@ -352,10 +355,10 @@ tlinelen(int y)
 {
 	int i = term.col;
 
-	if (term.line[y][i - 1].mode & ATTR_WRAP)
+	if (TLINE(y)[i - 1].mode & ATTR_WRAP)
 		return i;
 
-	while (i > 0 && term.line[y][i - 1].u == ' ')
+	while (i > 0 && TLINE(y)[i - 1].u == ' ')
 		--i;
 
 	return i;
@@ -464,7 +467,7 @@ selsnap(int *x, int *y, int direction)
 		 * Snap around if the word wraps around at the end or
 		 * beginning of a line.
 		 */
-		prevgp = &term.line[*y][*x];
+		prevgp = &TLINE(*y)[*x];
 		prevdelim = ISDELIM(prevgp->u);
 		for (;;) {
 			newx = *x + direction;
@@ -479,14 +482,14 @@ selsnap(int *x, int *y, int direction)
 					yt = *y, xt = *x;
 				else
 					yt = newy, xt = newx;
-				if (!(term.line[yt][xt].mode & ATTR_WRAP))
+				if (!(TLINE(yt)[xt].mode & ATTR_WRAP))
 					break;
 			}
 
 			if (newx >= tlinelen(newy))
 				break;
 
-			gp = &term.line[newy][newx];
+			gp = &TLINE(newy)[newx];
 			delim = ISDELIM(gp->u);
 			if (!(gp->mode & ATTR_WDUMMY) && (delim != prevdelim
 					|| (delim && gp->u != prevgp->u)))
@@ -507,14 +510,14 @@ selsnap(int *x, int *y, int direction)
 		*x = (direction < 0) ? 0 : term.col - 1;
 		if (direction < 0) {
 			for (; *y > 0; *y += direction) {
-				if (!(term.line[*y-1][term.col-1].mode
+				if (!(TLINE(*y-1)[term.col-1].mode
 						& ATTR_WRAP)) {
 					break;
 				}
 			}
 		} else if (direction > 0) {
 			for (; *y < term.row-1; *y += direction) {
-				if (!(term.line[*y][term.col-1].mode
+				if (!(TLINE(*y)[term.col-1].mode
 						& ATTR_WRAP)) {
 					break;
 				}
@@ -545,13 +548,13 @@ getsel(void)
 		}
 
 		if (sel.type == SEL_RECTANGULAR) {
-			gp = &term.line[y][sel.nb.x];
+			gp = &TLINE(y)[sel.nb.x];
 			lastx = sel.ne.x;
 		} else {
-			gp = &term.line[y][sel.nb.y == y ? sel.nb.x : 0];
+			gp = &TLINE(y)[sel.nb.y == y ? sel.nb.x : 0];
 			lastx = (sel.ne.y == y) ? sel.ne.x : term.col-1;
 		}
-		last = &term.line[y][MIN(lastx, linelen-1)];
+		last = &TLINE(y)[MIN(lastx, linelen-1)];
 		while (last >= gp && last->u == ' ')
 			--last;
 
@@ -773,6 +776,8 @@ ttyread(void)
 	case -1:
 		die("couldn't read from shell: %s\n", strerror(errno));
 	default:
+		if (term.scr > 0)
+			kscrolldown(&(Arg){ .i = term.scr });
 		buflen += ret;
 		written = twrite(buf, buflen, 0);
 		buflen -= written;
@@ -783,90 +788,10 @@ ttyread(void)
 	}
 }
 
-void
-ttywrite(const char *s, size_t n, int may_echo)
-{
-	const char *next;
-
-	if (may_echo && IS_SET(MODE_ECHO))
-		twrite(s, n, 1);
-
-	if (!IS_SET(MODE_CRLF)) {
-		ttywriteraw(s, n);
-		return;
-	}
-
-	/* This is similar to how the kernel handles ONLCR for ttys */
-	while (n > 0) {
-		if (*s == '\r') {
-			next = s + 1;
-			ttywriteraw("\r\n", 2);
-		} else {
-			next = memchr(s, '\r', n);
-			DEFAULT(next, s + n);
-			ttywriteraw(s, next - s);
-		}
-		n -= next - s;
-		s = next;
-	}
-}
-
-void
-ttywriteraw(const char *s, size_t n)
-{
-	fd_set wfd, rfd;
-	ssize_t r;
-	size_t lim = 256;
-
-	/*
-	 * Remember that we are using a pty, which might be a modem line.
-	 * Writing too much will clog the line. That's why we are doing this
-	 * dance.
-	 * FIXME: Migrate the world to Plan 9.
-	 */
-	while (n > 0) {
-		FD_ZERO(&wfd);
-		FD_ZERO(&rfd);
-		FD_SET(cmdfd, &wfd);
-		FD_SET(cmdfd, &rfd);
-
-		/* Check if we can write. */
-		if (pselect(cmdfd+1, &rfd, &wfd, NULL, NULL, NULL) < 0) {
-			if (errno == EINTR)
-				continue;
-			die("select failed: %s\n", strerror(errno));
-		}
-		if (FD_ISSET(cmdfd, &wfd)) {
-			/*
-			 * Only write the bytes written by ttywrite() or the
-			 * default of 256. This seems to be a reasonable value
-			 * for a serial line. Bigger values might clog the I/O.
-			 */
-			if ((r = write(cmdfd, s, (n < lim)? n : lim)) < 0)
-				goto write_error;
-			if (r < n) {
-				/*
-				 * We weren't able to write out everything.
-				 * This means the buffer is getting full
-				 * again. Empty it.
-				 */
-				if (n < lim)
-					lim = ttyread();
-				n -= r;
-				s += r;
-			} else {
-				/* All bytes have been written. */
-				break;
-			}
-		}
-		if (FD_ISSET(cmdfd, &rfd))
-			lim = ttyread();
-	}
-	return;
-
-write_error:
-	die("write error on tty: %s\n", strerror(errno));
-}
+/*
+ * ttywrite() is defined in Rust, so that a full tty is emptied with its
+ * ttyread() and parser.
+ */
 
 void
 ttyresize(int tw, int th)
@@ -982,6 +907,9 @@ void
 tnew(int col, int row)
 {
 	term = (Term){ .c = { .attr = { .fg = defaultfg, .bg = defaultbg } } };
+	term.histmax = histsize;
+	term.hist = xmalloc(term.histmax * sizeof(Line));
+	memset(term.hist, 0, term.histmax * sizeof(Line));
 	tresize(col, row);
 	treset();
 }
@@ -991,6 +919,7 @@ tswapscreen(void)
 {
 	Line *tmp = term.line;
 
+	term.scr = 0;
 	term.line = term.alt;
 	term.alt = tmp;
 	term.mode ^= MODE_ALTSCREEN;
@@ -1025,6 +954,11 @@ tscrollup(int orig, int n)
 
 	LIMIT(n, 0, term.bot-orig+1);
 
+	if (orig == 0 && term.histmax > 0 && !IS_SET(MODE_ALTSCREEN)) {
+		for (i = 0; i < n; i++)
+			thistpush(i);
+	}
+
 	tclearregion(0, orig, term.col-1, orig+n-1);
 	tsetdirt(orig+n, term.bot);
 
@@ -1037,6 +971,65 @@ tscrollup(int orig, int n)
 	selscroll(orig, -n);
 }
 
+void
+thistpush(int y)
+{
+	Line tmp;
+
+	/* swap the line into the ring, reusing the evicted line's buffer */
+	if (!term.hist[term.histi])
+		term.hist[term.histi] = xmalloc(term.col * sizeof(Glyph));
+	tmp = term.hist[term.histi];
+	term.hist[term.histi] = term.line[y];
+	term.line[y] = tmp;
+
+	term.histi = (term.histi + 1) % term.histmax;
+	if (term.histn < term.histmax)
+		term.histn++;
+}
+
+void
+thistclear(void)
+{
+	term.histn = 0;
+	term.scr = 0;
+	tfulldirt();
+}
+
+void
+kscrollup(const Arg *a)
+{
+	int n = a->i;
+
+	if (IS_SET(MODE_ALTSCREEN))
+		return;
+	if (n < 0)
+		n = MAX(term.row + n, 1);
+	n = MIN(n, term.histn - term.scr);
+	if (n <= 0)
+		return;
+
+	term.scr += n;
+	selscroll(0, n);
+	tfulldirt();
+}
+
+void
+kscrolldown(const Arg *a)
+{
+	int n = a->i;
+
+	if (n < 0)
+		n = MAX(term.row + n, 1);
+	n = MIN(n, term.scr);
+	if (n <= 0)
+		return;
+
+	term.scr -= n;
+	selscroll(0, -n);
+	tfulldirt();
+}
+
 void
 selscroll(int orig, int n)
 {
@@ -1655,6 +1648,9 @@ csihandle(void)
 		case 2: /* all */
 			tclearregion(0, 0, term.col-1, term.row-1);
 			break;
+		case 3: /* scrollback */
+			thistclear();
+			break;
 		default:
 			goto unknown;
 		}
@@ -2488,7 +2484,7 @@ twrite(const char *buf, int buflen, int show_ctrl)
 void
 tresize(int col, int row)
 {
-	int i;
+	int i, j;
 	int minrow = MIN(row, term.row);
 	int mincol = MIN(col, term.col);
 	int *bp;
@@ -2536,6 +2532,19 @@ tresize(int col, int row)
 		term.line[i] = xmalloc(col * sizeof(Glyph));
 		term.alt[i] = xmalloc(col * sizeof(Glyph));
 	}
+
+	/* resize history lines, blank-padding if needed */
+	for (i = 0; i < term.histmax; i++) {
+		if (!term.hist[i])
+			continue;
+		term.hist[i] = xrealloc(term.hist[i], col * sizeof(Glyph));
+		for (j = term.col; j < col; j++) {
+			term.hist[i][j] = term.c.attr;
+			term.hist[i][j].mode = 0;
+			term.hist[i][j].u = ' ';
+		}
+	}
+	LIMIT(term.scr, 0, term.histn);
 	if (col > term.col) {
 		bp = term.tabs + term.col;
 
@@ -2583,7 +2592,7 @@ drawregion(int x1, int y1, int x2, int y2)
 			continue;
 
 		term.dirty[y] = 0;
-		xdrawline(term.line[y], x1, y, x2);
+		xdrawline(TLINE(y), x1, y, x2);
 	}
 }
 
@@ -2604,8 +2613,9 @@ draw(void)
 		cx--;
 
 	drawregion(0, 0, term.col, term.row);
-	xdrawcursor(cx, term.c.y, term.line[term.c.y][cx],
-			term.ocx, term.ocy, term.line[term.ocy][term.ocx]);
+	if (term.scr == 0)
+		xdrawcursor(cx, term.c.y, term.line[term.c.y][cx],
+				term.ocx, term.ocy, term.line[term.ocy][term.ocx]);
 	term.ocx = cx;
 	term.ocy = term.c.y;
 	xfinishdraw();
@@ -65,6 +65,12 @@ typedef struct {
 	ushort mode;      /* attribute flags */
 	uint32_t fg;      /* foreground  */
 	uint32_t bg;      /* background  */
+	uint32_t comb;    /* combining marks, 1 + index into a table of the
+	                     Rust side, or 0 for none */
+	uint32_t link;    /* hyperlink, 1 + index into a table of the Rust
+	                     side, or 0 for none */
+	uint32_t ucolor;  /* underline color, 1 + a color like fg, or 0 for
+	                     the color of the text */
 } Glyph;
 
 typedef Glyph *Line;
@@ -81,6 +87,8 @@ void die(const char *, ...);
 void redraw(void);
 void draw(void);
 
+void kscrolldown(const Arg *);
+void kscrollup(const Arg *);
 void printscreen(const Arg *);
 void printsel(const Arg *);
 void sendbreak(const Arg *);
@@ -131,6 +139,7 @@ extern int allowaltscreen;
 extern int allowwindowops;
 extern char *termname;
 extern unsigned int tabspaces;
+extern unsigned int histsize;
 extern unsigned int defaultfg;
 extern unsigned int defaultbg;
 extern unsigned int defaultcs;
@@ -29,6 +29,11 @@ typedef struct {
 	int col;      /* nb col */
 	Line *line;   /* screen */
 	Line *alt;    /* alternate screen */
+	Line *hist;   /* scrollback history ring */
+	int histmax;  /* history ring capacity */
+	int histi;    /* ring index of the next history line */
+	int histn;    /* nb of lines in history */
+	int scr;      /* scroll offset into history */
 	int *dirty;   /* dirtyness of lines */
 	TCursor c;    /* cursor */
 	int ocx;      /* old cursor col */
@@ -30,14 +30,14 @@ typedef struct {
 #define XK_SWITCH_MOD (1<<13|1<<14)
 
 /* function definitions used in config.h */
-static void clipcopy(const Arg *);
-static void clippaste(const Arg *);
-static void numlock(const Arg *);
-static void selpaste(const Arg *);
-static void zoom(const Arg *);
-static void zoomabs(const Arg *);
-static void zoomreset(const Arg *);
-static void ttysend(const Arg *);
+void clipcopy(const Arg *);
+void clippaste(const Arg *);
+void numlock(const Arg *);
+void selpaste(const Arg *);
+void zoom(const Arg *);
+void zoomabs(const Arg *);
+void zoomreset(const Arg *);
+void ttysend(const Arg *);
 
 /* config.h for applying patches and the configuration. */
 #include "config.h"
@@ -163,6 +163,7 @@ static void setsel(char *, Time);
 static void mousesel(XEvent *, int);
 static void mousereport(XEvent *);
 static char *kmap(KeySym, uint);
+int xshortcut(KeySym, uint);
 static int match(uint, uint);
 
 void run(void);
@@ -1782,6 +1783,19 @@ match(uint mask, uint state)
 	return mask == XK_ANY_MOD || mask == (state & ~ignoremod);
 }
 
+/* Return whether one of the shortcuts above is bound to k with state. */
+int
+xshortcut(KeySym k, uint state)
+{
+	Shortcut *bp;
+
+	for (bp = shortcuts; bp < shortcuts + LEN(shortcuts); bp++) {
+		if (k == bp->keysym && match(bp->mod, state))
+			return 1;
+	}
+	return 0;
+}
+
 char*
 kmap(KeySym k, uint state)
 {